@@ -0,0 +1,110 @@
+//! Command line splitting shared by the chip8-rs, chip8-asm and chip8-disasm
+//! binaries. Options take their value either as the next argument or after
+//! an `=`, as in `--scale 4` or `--scale=4`
+
+/// Walks the arguments of a command line, not including the program name
+pub struct Args< I >
+{
+    args: I,
+
+    /// The last flag returned, without any `=value`
+    flag: String,
+
+    /// The value given to the last flag as `--flag=value`, until it is used
+    inline_value: Option< String >,
+}
+
+impl< I: Iterator< Item = String > > Args< I >
+{
+    pub fn new(args: I) -> Self
+    {
+        Args { args, flag: String::new(), inline_value: None }
+    }
+
+    /// The next option or positional argument. `--flag=value` is returned as
+    /// `--flag`, keeping the value for `value`. Fails if the previous flag was
+    /// given a value it did not use
+    pub fn next_arg(&mut self) -> Result< Option< String >, String >
+    {
+        if self.inline_value.take().is_some()
+        {
+            return Err(format!("{} does not take a value", self.flag));
+        }
+
+        let arg = match self.args.next()
+        {
+            Some(arg) => arg,
+            None => return Ok(None)
+        };
+        self.flag = match arg.find('=')
+        {
+            Some(eq) if arg.starts_with("--") =>
+            {
+                self.inline_value = Some(arg[eq + 1..].to_string());
+                arg[..eq].to_string()
+            },
+            _ => arg
+        };
+
+        Ok(Some(self.flag.clone()))
+    }
+
+    /// The value of the flag last returned by `next_arg`
+    pub fn value(&mut self) -> Result< String, String >
+    {
+        match self.inline_value.take()
+        {
+            Some(value) => Ok(value),
+            None => self.args.next().ok_or_else(|| format!("{} requires a value", self.flag))
+        }
+    }
+}
+
+/// Whether an argument is an option rather than a positional argument. A
+/// lone `-` is positional
+pub fn is_option(arg: &str) -> bool
+{
+    arg.starts_with('-') && arg.len() > 1
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn args(line: &str) -> Args< impl Iterator< Item = String > >
+    {
+        Args::new(line.split_whitespace().map(String::from).collect::< Vec< _ > >().into_iter())
+    }
+
+    #[test]
+    fn values()
+    {
+        let mut args = args("--scale 4 --hz=700 rom.ch8 --palette=a=b");
+        assert_eq!(args.next_arg(), Ok(Some("--scale".to_string())));
+        assert_eq!(args.value(), Ok("4".to_string()));
+        assert_eq!(args.next_arg(), Ok(Some("--hz".to_string())));
+        assert_eq!(args.value(), Ok("700".to_string()));
+        assert_eq!(args.next_arg(), Ok(Some("rom.ch8".to_string())));
+        assert_eq!(args.next_arg(), Ok(Some("--palette".to_string())));
+        assert_eq!(args.value(), Ok("a=b".to_string()));
+        assert_eq!(args.next_arg(), Ok(None));
+    }
+
+    #[test]
+    fn missing_and_unused_values()
+    {
+        let mut args = args("--mute=1 --scale");
+        assert_eq!(args.next_arg(), Ok(Some("--mute".to_string())));
+        assert_eq!(args.next_arg(), Err("--mute does not take a value".to_string()));
+        assert_eq!(args.next_arg(), Ok(Some("--scale".to_string())));
+        assert_eq!(args.value(), Err("--scale requires a value".to_string()));
+    }
+
+    #[test]
+    fn options()
+    {
+        assert!(is_option("-h") && is_option("--octo"));
+        assert!(!is_option("-") && !is_option("rom.ch8"));
+    }
+}
@@ -0,0 +1,764 @@
+//! Two-pass assembler for CHIP-8 programs written with Cowgod's mnemonics,
+//! the syntax `disasm` writes.
+//!
+//! ```text
+//! SPEED   EQU 2               ; constants
+//! start:                      ; labels
+//!         LD   V0, SPEED
+//!         LD   I, sprite
+//!         DRW  V0, V1, 4
+//!         JP   start
+//! sprite: DB   0b11110000, 0x90, 0x90, 0xF0
+//!         DW   0x1234
+//!         INCLUDE "font.asm"
+//! ```
+//!
+//! The first pass expands includes, records the address of every label and
+//! the size of every line. The second pass evaluates operands and encodes
+//! the program, so labels can be used before they are defined. Operands are
+//! expressions over numbers, labels and constants with `+ - * / & | << >>`
+//! and parentheses. Numbers are decimal, or hexadecimal with a `0x`, `#` or
+//! `$` prefix, or binary with a `0b` prefix.
+
+use crate::cpu::{ MEMORY_SIZE, PROGRAM_START };
+use crate::expr::{ parse_number, Grammar, Parser };
+use crate::instruction::{ Instruction, Platform };
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::rc::Rc;
+
+/// How deep includes and constant definitions may nest before they are
+/// assumed to be recursive
+const MAX_NESTING: usize = 32;
+
+/// An error in the source, with the file and line it was found on
+#[derive(Debug)]
+pub struct AsmError
+{
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+/// Assembles source text into a ROM to be loaded at 0x200. Includes are
+/// resolved relative to the current directory
+pub fn assemble(source: &str, platform: Platform) -> Result< Vec< u8 >, AsmError >
+{
+    let mut lines = Vec::new();
+    read_lines(Rc::from("<input>"), source, Path::new(""), &mut Vec::new(), &mut lines)?;
+    Assembler::new(platform).run(&lines)
+}
+
+/// Assembles a source file into a ROM to be loaded at 0x200. Includes are
+/// resolved relative to the including file
+pub fn assemble_file(path: &Path, platform: Platform) -> Result< Vec< u8 >, AsmError >
+{
+    let name = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|e| AsmError { file: name.clone(), line: 0, message: e.to_string() })?;
+
+    let mut lines = Vec::new();
+    let mut stack = vec![fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())];
+    read_lines(Rc::from(name.as_str()), &source, path.parent().unwrap_or(Path::new("")), &mut stack, &mut lines)?;
+    Assembler::new(platform).run(&lines)
+}
+
+/// A line of source after includes are expanded, with comments removed
+struct Line
+{
+    file: Rc< str >,
+    number: usize,
+    text: String,
+}
+
+impl Line
+{
+    fn error(&self, message: String) -> AsmError
+    {
+        AsmError { file: self.file.to_string(), line: self.number, message }
+    }
+}
+
+/// Splits source into lines, replacing each INCLUDE with the lines of the
+/// included file. `stack` holds the files being included to catch cycles
+fn read_lines(file: Rc< str >, source: &str, dir: &Path, stack: &mut Vec< PathBuf >, lines: &mut Vec< Line >) -> Result< (), AsmError >
+{
+    for (index, text) in source.lines().enumerate()
+    {
+        let line = Line { file: file.clone(), number: index + 1, text: strip_comment(text).trim().to_string() };
+
+        let (keyword, rest) = split_word(&line.text);
+        if !keyword.eq_ignore_ascii_case("include")
+        {
+            lines.push(line);
+            continue;
+        }
+
+        let name = parse_string(rest).ok_or_else(|| line.error("INCLUDE expects a quoted file name".to_string()))?;
+        let path = dir.join(&name);
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if stack.contains(&canonical) || stack.len() >= MAX_NESTING
+        {
+            return Err(line.error(format!("\"{}\" includes itself", name)));
+        }
+
+        let included = fs::read_to_string(&path).map_err(|e| line.error(format!("Could not read \"{}\": {}", path.display(), e)))?;
+        stack.push(canonical);
+        read_lines(Rc::from(path.display().to_string().as_str()), &included, path.parent().unwrap_or(Path::new("")), stack, lines)?;
+        stack.pop();
+    }
+
+    Ok(())
+}
+
+/// Removes a `;` comment, leaving semicolons inside strings alone
+fn strip_comment(text: &str) -> &str
+{
+    let mut in_string = false;
+    for (pos, c) in text.char_indices()
+    {
+        match c
+        {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &text[..pos],
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Splits off the first whitespace separated word
+fn split_word(text: &str) -> (&str, &str)
+{
+    match text.find(char::is_whitespace)
+    {
+        Some(pos) => (&text[..pos], text[pos..].trim_start()),
+        None => (text, "")
+    }
+}
+
+/// Parses a double quoted string with no escapes
+fn parse_string(text: &str) -> Option< String >
+{
+    let text = text.trim();
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"')
+    {
+        Some(text[1..text.len() - 1].to_string())
+    }
+    else
+    {
+        None
+    }
+}
+
+fn is_identifier(text: &str) -> bool
+{
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// An operand of an instruction
+#[derive(Debug)]
+enum Operand
+{
+    V(u8),
+    /// A range of registers, `Vx-Vy`
+    VRange(u8, u8),
+    I,
+    /// Memory at I, `[I]`
+    IndirectI,
+    /// A special register or operand keyword: DT, ST, K, F, HF, B or R
+    Keyword(&'static str),
+    /// A 16-bit address, `LONG expr`
+    Long(Expr),
+    Value(Expr),
+}
+
+const KEYWORDS: [&str; 7] = ["DT", "ST", "K", "F", "HF", "B", "R"];
+
+fn parse_register(text: &str) -> Option< u8 >
+{
+    let text = text.trim();
+    if text.len() == 2 && (text.starts_with('V') || text.starts_with('v'))
+    {
+        u8::from_str_radix(&text[1..], 16).ok()
+    }
+    else
+    {
+        None
+    }
+}
+
+fn parse_operand(text: &str) -> Result< Operand, String >
+{
+    let upper = text.to_ascii_uppercase();
+    if let Some(x) = parse_register(text)
+    {
+        return Ok(Operand::V(x));
+    }
+    if let Some(&keyword) = KEYWORDS.iter().find(|&&k| k == upper)
+    {
+        return Ok(Operand::Keyword(keyword));
+    }
+
+    match upper.as_str()
+    {
+        "I" => Ok(Operand::I),
+        "[I]" => Ok(Operand::IndirectI),
+        _ =>
+        {
+            if let Some(dash) = text.find('-')
+            {
+                if let (Some(x), Some(y)) = (parse_register(&text[..dash]), parse_register(&text[dash + 1..]))
+                {
+                    return Ok(Operand::VRange(x, y));
+                }
+            }
+
+            let (word, rest) = split_word(text);
+            if word.eq_ignore_ascii_case("long")
+            {
+                return Ok(Operand::Long(Expr::parse(rest)?));
+            }
+
+            Ok(Operand::Value(Expr::parse(text)?))
+        }
+    }
+}
+
+/// An element of a DB or DW directive
+enum Datum
+{
+    Value(Expr),
+    Text(String),
+}
+
+/// A line that produces bytes, waiting for the second pass
+enum Statement
+{
+    Instruction { mnemonic: String, operands: Vec< Operand > },
+    Bytes(Vec< Datum >),
+    Words(Vec< Expr >),
+}
+
+/// What a name stands for
+enum Symbol
+{
+    Label(usize),
+    Constant(Expr),
+}
+
+struct Assembler
+{
+    platform: Platform,
+    symbols: HashMap< String, Symbol >,
+}
+
+impl Assembler
+{
+    fn new(platform: Platform) -> Self
+    {
+        Assembler { platform, symbols: HashMap::new() }
+    }
+
+    fn run(&mut self, lines: &[Line]) -> Result< Vec< u8 >, AsmError >
+    {
+        // First pass: place every statement and define every symbol
+        let mut statements = Vec::new();
+        let mut addr = PROGRAM_START;
+        for (index, line) in lines.iter().enumerate()
+        {
+            addr = self.first_pass(line, index, addr, &mut statements).map_err(|e| line.error(e))?;
+            if addr > MEMORY_SIZE
+            {
+                return Err(line.error(format!("Program does not fit in memory, which ends at 0x{:X}", MEMORY_SIZE)));
+            }
+        }
+
+        // Second pass: encode with every symbol known
+        let mut rom = vec![0u8; addr - PROGRAM_START];
+        for (index, addr, statement) in statements.iter()
+        {
+            let line = &lines[*index];
+            let bytes = self.encode(statement).map_err(|e| line.error(e))?;
+            let offset = addr - PROGRAM_START;
+            rom[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+
+        Ok(rom)
+    }
+
+    /// Reads a line's label, directive or instruction. Returns the address
+    /// after the line
+    fn first_pass(&mut self, line: &Line, index: usize, addr: usize, statements: &mut Vec< (usize, usize, Statement) >) -> Result< usize, String >
+    {
+        let mut text = line.text.as_str();
+
+        // A label, alone or before a statement
+        if let Some(colon) = text.find(':')
+        {
+            let name = text[..colon].trim();
+            if is_identifier(name)
+            {
+                self.define(name, Symbol::Label(addr))?;
+                text = text[colon + 1..].trim();
+            }
+        }
+        if text.is_empty()
+        {
+            return Ok(addr);
+        }
+
+        let (word, rest) = split_word(text);
+
+        // `NAME EQU expr`
+        let (second, value) = split_word(rest);
+        if second.eq_ignore_ascii_case("equ")
+        {
+            if !is_identifier(word)
+            {
+                return Err(format!("\"{}\" is not a valid constant name", word));
+            }
+            return self.define(word, Symbol::Constant(Expr::parse(value)?)).map(|_| addr);
+        }
+
+        let mnemonic = word.to_ascii_uppercase();
+        let statement = match mnemonic.as_str()
+        {
+            "ORG" =>
+            {
+                let target = Expr::parse(rest)?.eval(&self.symbols, 0)?;
+                if target < addr as i64 || target > MEMORY_SIZE as i64
+                {
+                    return Err(format!("ORG 0x{:X} is before the current address 0x{:X} or past the end of memory", target, addr));
+                }
+                return Ok(target as usize);
+            },
+            "DB" =>
+            {
+                let data = split_operands(rest).iter().map(|item| match parse_string(item)
+                {
+                    Some(text) => Ok(Datum::Text(text)),
+                    None => Expr::parse(item).map(Datum::Value)
+                }).collect::< Result< Vec< _ >, _ > >()?;
+                Statement::Bytes(data)
+            },
+            "DW" => Statement::Words(split_operands(rest).iter().map(|item| Expr::parse(item)).collect::< Result< _, _ > >()?),
+            _ =>
+            {
+                let operands = split_operands(rest).iter().map(|item| parse_operand(item)).collect::< Result< _, _ > >()?;
+                Statement::Instruction { mnemonic, operands }
+            }
+        };
+
+        let size = statement_size(&statement);
+        statements.push((index, addr, statement));
+
+        Ok(addr + size)
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol) -> Result< (), String >
+    {
+        if parse_register(name).is_some() || KEYWORDS.iter().chain(["I", "LONG"].iter()).any(|k| k.eq_ignore_ascii_case(name))
+        {
+            return Err(format!("\"{}\" is a register name and cannot be redefined", name));
+        }
+        if self.symbols.contains_key(name)
+        {
+            return Err(format!("\"{}\" is already defined", name));
+        }
+
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+
+    fn encode(&self, statement: &Statement) -> Result< Vec< u8 >, String >
+    {
+        match statement
+        {
+            Statement::Bytes(data) =>
+            {
+                let mut bytes = Vec::new();
+                for datum in data.iter()
+                {
+                    match datum
+                    {
+                        Datum::Text(text) => bytes.extend_from_slice(text.as_bytes()),
+                        Datum::Value(expr) => bytes.push(self.byte(expr)?),
+                    }
+                }
+                Ok(bytes)
+            },
+            Statement::Words(words) =>
+            {
+                let mut bytes = Vec::new();
+                for expr in words.iter()
+                {
+                    bytes.extend_from_slice(&self.value(expr, 0xFFFF, "16 bits")?.to_be_bytes());
+                }
+                Ok(bytes)
+            },
+            Statement::Instruction { mnemonic, operands } =>
+            {
+                let instruction = self.instruction(mnemonic, operands)?;
+                if instruction.platform() > self.platform
+                {
+                    return Err(format!("{} needs the {} platform, but the target is {}", mnemonic, instruction.platform(), self.platform));
+                }
+
+                let mut bytes = instruction.encode().to_be_bytes().to_vec();
+                if let Some(Operand::Long(expr)) = operands.iter().find(|op| matches!(op, Operand::Long(_)))
+                {
+                    bytes.extend_from_slice(&self.value(expr, 0xFFFF, "16 bits")?.to_be_bytes());
+                }
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Matches a mnemonic and its operands to an instruction
+    fn instruction(&self, mnemonic: &str, operands: &[Operand]) -> Result< Instruction, String >
+    {
+        use self::Operand::*;
+        use crate::instruction::Instruction as In;
+
+        let addr = |expr: &Expr| self.value(expr, 0xFFF, "12 bits");
+        let byte = |expr: &Expr| self.byte(expr);
+        let nibble = |expr: &Expr| self.value(expr, 0xF, "4 bits").map(|n| n as u8);
+
+        let instruction = match (mnemonic, operands)
+        {
+            ("CLS", []) => In::Cls,
+            ("RET", []) => In::Ret,
+            ("SCD", [Value(n)]) => In::ScrollDown(nibble(n)?),
+            ("SCU", [Value(n)]) => In::ScrollUp(nibble(n)?),
+            ("SCR", []) => In::ScrollRight,
+            ("SCL", []) => In::ScrollLeft,
+            ("EXIT", []) => In::Exit,
+            ("LOW", []) => In::Low,
+            ("HIGH", []) => In::High,
+            ("JP", [Value(nnn)]) => In::Jp(addr(nnn)?),
+            ("JP", [V(0), Value(nnn)]) => In::JpV0(addr(nnn)?),
+            ("CALL", [Value(nnn)]) => In::Call(addr(nnn)?),
+            ("SE", [V(x), V(y)]) => In::SeVxVy(*x, *y),
+            ("SE", [V(x), Value(nn)]) => In::SeVxNn(*x, byte(nn)?),
+            ("SNE", [V(x), V(y)]) => In::SneVxVy(*x, *y),
+            ("SNE", [V(x), Value(nn)]) => In::SneVxNn(*x, byte(nn)?),
+            ("LD", [V(x), V(y)]) => In::LdVxVy(*x, *y),
+            ("LD", [V(x), Value(nn)]) => In::LdVxNn(*x, byte(nn)?),
+            ("LD", [I, Value(nnn)]) => In::LdI(addr(nnn)?),
+            ("LD", [I, Long(_)]) => In::LdILong,
+            ("LD", [V(x), Keyword("DT")]) => In::LdVxDt(*x),
+            ("LD", [V(x), Keyword("K")]) => In::LdVxK(*x),
+            ("LD", [Keyword("DT"), V(x)]) => In::LdDtVx(*x),
+            ("LD", [Keyword("ST"), V(x)]) => In::LdStVx(*x),
+            ("LD", [Keyword("F"), V(x)]) => In::LdFVx(*x),
+            ("LD", [Keyword("HF"), V(x)]) => In::LdHfVx(*x),
+            ("LD", [Keyword("B"), V(x)]) => In::LdBVx(*x),
+            ("LD", [IndirectI, V(x)]) => In::LdIVx(*x),
+            ("LD", [V(x), IndirectI]) => In::LdVxI(*x),
+            ("LD", [IndirectI, VRange(x, y)]) => In::SaveRange(*x, *y),
+            ("LD", [VRange(x, y), IndirectI]) => In::LoadRange(*x, *y),
+            ("LD", [Keyword("R"), V(x)]) => In::LdRVx(*x),
+            ("LD", [V(x), Keyword("R")]) => In::LdVxR(*x),
+            ("ADD", [V(x), V(y)]) => In::AddVxVy(*x, *y),
+            ("ADD", [V(x), Value(nn)]) => In::AddVxNn(*x, byte(nn)?),
+            ("ADD", [I, V(x)]) => In::AddIVx(*x),
+            ("OR", [V(x), V(y)]) => In::Or(*x, *y),
+            ("AND", [V(x), V(y)]) => In::And(*x, *y),
+            ("XOR", [V(x), V(y)]) => In::Xor(*x, *y),
+            ("SUB", [V(x), V(y)]) => In::Sub(*x, *y),
+            ("SUBN", [V(x), V(y)]) => In::Subn(*x, *y),
+            ("SHR", [V(x)]) => In::Shr(*x, *x),
+            ("SHR", [V(x), V(y)]) => In::Shr(*x, *y),
+            ("SHL", [V(x)]) => In::Shl(*x, *x),
+            ("SHL", [V(x), V(y)]) => In::Shl(*x, *y),
+            ("RND", [V(x), Value(nn)]) => In::Rnd(*x, byte(nn)?),
+            ("DRW", [V(x), V(y), Value(n)]) => In::Drw(*x, *y, nibble(n)?),
+            ("SKP", [V(x)]) => In::Skp(*x),
+            ("SKNP", [V(x)]) => In::Sknp(*x),
+            ("PLANE", [Value(n)]) => In::Plane(nibble(n)?),
+            ("AUDIO", []) => In::Audio,
+            ("PITCH", [V(x)]) => In::Pitch(*x),
+            _ if KNOWN_MNEMONICS.contains(&mnemonic) => return Err(format!("Invalid operands for {}", mnemonic)),
+            _ => return Err(format!("Unknown instruction \"{}\"", mnemonic))
+        };
+
+        Ok(instruction)
+    }
+
+    /// Evaluates an expression that must lie in 0..=max
+    fn value(&self, expr: &Expr, max: u16, width: &str) -> Result< u16, String >
+    {
+        let value = expr.eval(&self.symbols, 0)?;
+        if value < 0 || value > max as i64
+        {
+            return Err(format!("Value {} does not fit in {}", value, width));
+        }
+        Ok(value as u16)
+    }
+
+    /// Evaluates a byte. Negative values down to -128 are stored as two's complement
+    fn byte(&self, expr: &Expr) -> Result< u8, String >
+    {
+        let value = expr.eval(&self.symbols, 0)?;
+        if !(-128..=255).contains(&value)
+        {
+            return Err(format!("Value {} does not fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+}
+
+const KNOWN_MNEMONICS: [&str; 29] = [
+    "CLS", "RET", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "JP", "CALL", "SE", "SNE", "LD", "ADD",
+    "OR", "AND", "XOR", "SUB", "SUBN", "SHR", "SHL", "RND", "DRW", "SKP", "SKNP", "PLANE", "AUDIO", "PITCH",
+];
+
+fn statement_size(statement: &Statement) -> usize
+{
+    match statement
+    {
+        Statement::Instruction { operands, .. } => if operands.iter().any(|op| matches!(op, Operand::Long(_))) { 4 } else { 2 },
+        Statement::Bytes(data) => data.iter().map(|d| match d { Datum::Text(text) => text.len(), Datum::Value(_) => 1 }).sum(),
+        Statement::Words(words) => words.len() * 2,
+    }
+}
+
+/// Splits operands on commas outside strings
+fn split_operands(text: &str) -> Vec< &str >
+{
+    if text.trim().is_empty()
+    {
+        return Vec::new();
+    }
+
+    let mut operands = Vec::new();
+    let mut in_string = false;
+    let mut start = 0;
+    for (pos, c) in text.char_indices()
+    {
+        match c
+        {
+            '"' => in_string = !in_string,
+            ',' if !in_string =>
+            {
+                operands.push(text[start..pos].trim());
+                start = pos + 1;
+            },
+            _ => {}
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+/// A binary operator in a constant expression
+#[derive(Clone, Copy, Debug)]
+enum BinaryOp
+{
+    Or,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// A constant expression over numbers and symbols
+#[derive(Debug)]
+enum Expr
+{
+    Number(i64),
+    Symbol(String),
+    Negate(Box< Expr >),
+    Binary(BinaryOp, Box< Expr >, Box< Expr >),
+}
+
+impl Grammar for Expr
+{
+    type Op = BinaryOp;
+    type Expr = Expr;
+
+    const BINARY_OPS: &'static [(&'static str, BinaryOp, u8)] = &[
+        ("<<", BinaryOp::Shl, 3),
+        (">>", BinaryOp::Shr, 3),
+        ("|", BinaryOp::Or, 1),
+        ("&", BinaryOp::And, 2),
+        ("+", BinaryOp::Add, 4),
+        ("-", BinaryOp::Sub, 4),
+        ("*", BinaryOp::Mul, 5),
+        ("/", BinaryOp::Div, 5),
+    ];
+
+    const PUNCTUATION: &'static str = "()";
+
+    fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr
+    {
+        Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    fn unary(parser: &mut Parser< Expr >) -> Result< Expr, String >
+    {
+        let token = parser.operand()?;
+        match token.as_str()
+        {
+            "-" => Ok(Expr::Negate(Box::new(Expr::unary(parser)?))),
+            "(" =>
+            {
+                let expr = parser.binary(0)?;
+                parser.expect(")")?;
+                Ok(expr)
+            },
+            _ if is_identifier(&token) => Ok(Expr::Symbol(token)),
+            _ => parse_number(&token).map(Expr::Number).ok_or_else(|| format!("\"{}\" is not a number or name", token))
+        }
+    }
+}
+
+impl Expr
+{
+    fn parse(text: &str) -> Result< Expr, String >
+    {
+        if text.trim().is_empty()
+        {
+            return Err("Missing value".to_string());
+        }
+
+        Parser::< Expr >::parse(text)
+    }
+
+    /// Evaluates the expression. Constants are evaluated where they are used,
+    /// with depth counting how many are being expanded
+    fn eval(&self, symbols: &HashMap< String, Symbol >, depth: usize) -> Result< i64, String >
+    {
+        match self
+        {
+            Expr::Number(n) => Ok(*n),
+            Expr::Symbol(name) => match symbols.get(name)
+            {
+                Some(Symbol::Label(addr)) => Ok(*addr as i64),
+                Some(Symbol::Constant(_)) if depth >= MAX_NESTING => Err(format!("Constant \"{}\" is defined in terms of itself", name)),
+                Some(Symbol::Constant(expr)) => expr.eval(symbols, depth + 1),
+                None => Err(format!("Undefined name \"{}\"", name))
+            },
+            Expr::Negate(expr) => Ok(expr.eval(symbols, depth)?.wrapping_neg()),
+            Expr::Binary(op, lhs, rhs) =>
+            {
+                let lhs = lhs.eval(symbols, depth)?;
+                let rhs = rhs.eval(symbols, depth)?;
+                match op
+                {
+                    BinaryOp::Add => Ok(lhs.wrapping_add(rhs)),
+                    BinaryOp::Sub => Ok(lhs.wrapping_sub(rhs)),
+                    BinaryOp::Mul => Ok(lhs.wrapping_mul(rhs)),
+                    BinaryOp::Div if rhs == 0 => Err("Division by zero".to_string()),
+                    BinaryOp::Div => lhs.checked_div(rhs).ok_or_else(|| "Division overflows".to_string()),
+                    BinaryOp::And => Ok(lhs & rhs),
+                    BinaryOp::Or => Ok(lhs | rhs),
+                    BinaryOp::Shl => Ok(lhs.wrapping_shl(rhs as u32)),
+                    BinaryOp::Shr => Ok(lhs.wrapping_shr(rhs as u32)),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::disasm::{ self, Syntax };
+
+    const PLATFORMS: [Platform; 3] = [Platform::Chip8, Platform::SuperChip, Platform::XoChip];
+
+    /// Operand word given to F000 NNNN
+    const LONG_OPERAND: u16 = 0x1234;
+
+    /// Every instruction, once for each opcode that decodes to it
+    fn every_instruction() -> Vec< Instruction >
+    {
+        (0..=0xFFFF).filter_map(Instruction::decode).collect()
+    }
+
+    /// The bytes of an instruction, with its operand word if it has one
+    fn encode(instruction: Instruction) -> Vec< u8 >
+    {
+        let mut bytes = instruction.encode().to_be_bytes().to_vec();
+        if instruction.size() == 4
+        {
+            bytes.extend_from_slice(&LONG_OPERAND.to_be_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn every_instruction_round_trips()
+    {
+        for &platform in PLATFORMS.iter()
+        {
+            for instruction in every_instruction()
+            {
+                let text = disasm::format_instruction(instruction, LONG_OPERAND, Syntax::Cowgod, &|_| None);
+                let result = assemble(&text, platform);
+                if instruction.platform() <= platform
+                {
+                    assert_eq!(result.ok(), Some(encode(instruction)), "\"{}\" on {}", text, platform);
+                }
+                else
+                {
+                    assert!(result.is_err(), "\"{}\" assembled on {}", text, platform);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn disassembled_rom_round_trips()
+    {
+        let source = "\
+            start:
+                LD I, sprite
+                LD V0, 0x10
+                CALL draw
+                SE V0, 0x20
+                JP start
+            draw:
+                DRW V0, V1, 4
+                ADD V0, 4
+                RET
+            sprite:
+                DB 0xF0, 0x90, 0x90, 0xF0";
+
+        for &platform in PLATFORMS.iter()
+        {
+            let rom = assemble(source, platform).unwrap();
+            let listing = disasm::disassemble(&rom, Syntax::Cowgod);
+            assert_eq!(assemble(&listing, platform).unwrap(), rom, "{} listing:\n{}", platform, listing);
+        }
+    }
+
+    #[test]
+    fn error_has_line_number()
+    {
+        let error = assemble("CLS\n; comment\nLD V0, 1\nJP nowhere\nRET", Platform::Chip8).unwrap_err();
+        assert_eq!(error.line, 4);
+        assert_eq!(error.to_string(), "<input>:4: Undefined name \"nowhere\"");
+    }
+}
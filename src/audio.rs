@@ -0,0 +1,230 @@
+//! Sample generation for the sound timer. The generator is independent of
+//! any audio library: a frontend asks it for samples at its own rate and
+//! keeps it up to date with the CPU's sound timer. XO-CHIP programs that load
+//! an audio pattern hear the pattern instead of the beep
+
+use crate::cpu::{ CPU, TIMER_CLOCK };
+use crate::wav;
+
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+/// Default pitch of the beep in Hz
+pub const DEFAULT_FREQUENCY: f32 = 440.0;
+
+/// Sample rate used for recordings, and asked of audio devices
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// Default volume, from 0 to 1
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+/// Number of 1-bit samples in an XO-CHIP audio pattern
+const PATTERN_BITS: f32 = 128.0;
+
+/// Time taken to fade the beep in and out, which avoids clicks at the start
+/// and end of each beep
+const RAMP_SECONDS: f32 = 0.002;
+
+/// Shape of the beep
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Waveform
+{
+    #[default]
+    Square,
+    Sine,
+    Triangle,
+    Sawtooth,
+}
+
+impl FromStr for Waveform
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result< Self, Self::Err >
+    {
+        match s.to_ascii_lowercase().as_str()
+        {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "sawtooth" | "saw" => Ok(Waveform::Sawtooth),
+            _ => Err(format!("Unknown waveform \"{}\", expected square, sine, triangle or sawtooth", s))
+        }
+    }
+}
+
+impl fmt::Display for Waveform
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Waveform::Square => write!(f, "square"),
+            Waveform::Sine => write!(f, "sine"),
+            Waveform::Triangle => write!(f, "triangle"),
+            Waveform::Sawtooth => write!(f, "sawtooth"),
+        }
+    }
+}
+
+impl Waveform
+{
+    /// The waveform's value, from -1 to 1, at phase from 0 to 1
+    fn sample(self, phase: f32) -> f32
+    {
+        match self
+        {
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Sine => (phase * 2.0 * PI).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+        }
+    }
+}
+
+/// Rate in samples per second at which XO-CHIP plays the audio pattern for
+/// a pitch set by Fx3A. The default pitch of 64 plays at 4000 Hz
+pub fn pattern_playback_rate(pitch: u8) -> f32
+{
+    4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0)
+}
+
+/// Generates the sound played while the sound timer is above zero: a beep,
+/// or the XO-CHIP audio pattern if the program has loaded one
+#[derive(Clone, Debug)]
+pub struct Beeper
+{
+    pub frequency: f32,
+    pub volume: f32,
+    pub waveform: Waveform,
+    sample_rate: f32,
+    /// The XO-CHIP pattern and its pitch, played instead of the beep when set
+    pattern: Option< ([u8; 16], u8) >,
+    /// Position within the current period of the beep or pattern, from 0 to 1
+    phase: f32,
+    /// Current loudness, from 0 to volume, moving towards volume while the
+    /// sound is on and towards 0 while it is off
+    level: f32,
+    on: bool,
+}
+
+impl Beeper
+{
+    pub fn new(sample_rate: u32) -> Self
+    {
+        Beeper {
+            frequency: DEFAULT_FREQUENCY,
+            volume: DEFAULT_VOLUME,
+            waveform: Waveform::default(),
+            sample_rate: sample_rate as f32,
+            pattern: None,
+            phase: 0.0,
+            level: 0.0,
+            on: false,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32
+    {
+        self.sample_rate as u32
+    }
+
+    /// Turns the beep on or off, normally whenever the sound timer changes
+    /// between zero and non-zero
+    pub fn set_on(&mut self, on: bool)
+    {
+        self.on = on;
+    }
+
+    /// Sets the XO-CHIP pattern and pitch to play instead of the beep, or 
+    /// None to play the beep
+    pub fn set_pattern(&mut self, pattern: Option< ([u8; 16], u8) >)
+    {
+        self.pattern = pattern;
+    }
+
+    /// Follows the CPU's sound timer and, once F002 has loaded one, its audio
+    /// pattern and pitch. Call after every frame
+    pub fn update(&mut self, cpu: &CPU)
+    {
+        self.set_on(cpu.sound_timer > 0);
+        self.set_pattern(if cpu.audio_pattern_loaded { Some((cpu.audio_pattern, cpu.pitch)) } else { None });
+    }
+
+    /// Fills out with the next samples, each from -volume to volume
+    pub fn fill(&mut self, out: &mut [f32])
+    {
+        let target = if self.on { self.volume } else { 0.0 };
+        let ramp_step = self.volume.max(f32::EPSILON) / (RAMP_SECONDS * self.sample_rate);
+        let phase_step = match self.pattern
+        {
+            Some((_, pitch)) => pattern_playback_rate(pitch) / PATTERN_BITS / self.sample_rate,
+            None => self.frequency / self.sample_rate
+        };
+
+        for sample in out.iter_mut()
+        {
+            self.level = if self.level < target { (self.level + ramp_step).min(target) } else { (self.level - ramp_step).max(target) };
+            if self.level == 0.0
+            {
+                // Restart each beep at the start of a period
+                self.phase = 0.0;
+                *sample = 0.0;
+                continue;
+            }
+
+            let value = match &self.pattern
+            {
+                Some((pattern, _)) =>
+                {
+                    // Nearest-neighbour resampling of the bits, first byte and highest bit first
+                    let bit = (self.phase * PATTERN_BITS) as usize;
+                    if pattern[bit / 8] & (0x80 >> (bit % 8)) != 0 { 1.0 } else { -1.0 }
+                },
+                None => self.waveform.sample(self.phase)
+            };
+            *sample = value * self.level;
+            self.phase = (self.phase + phase_step).fract();
+        }
+    }
+}
+
+/// Records the sound of a run in emulated time, one frame at a time, so a
+/// recording is the same whatever speed the emulator ran at
+#[derive(Clone, Debug)]
+pub struct AudioRecorder
+{
+    beeper: Beeper,
+    samples: Vec< f32 >,
+    frames: u64,
+}
+
+impl AudioRecorder
+{
+    /// Records with the given beeper's sample rate and sound
+    pub fn new(beeper: Beeper) -> Self
+    {
+        AudioRecorder { beeper, samples: Vec::new(), frames: 0 }
+    }
+
+    /// Adds a frame of sound from the CPU's state. Call after every frame
+    pub fn record_frame(&mut self, cpu: &CPU)
+    {
+        // Spread the samples evenly when the rate is not a multiple of 60
+        let (rate, timer_hz, frame) = (self.beeper.sample_rate() as u64, TIMER_CLOCK as u64, self.frames);
+        let count = ((frame + 1) * rate / timer_hz - frame * rate / timer_hz) as usize;
+
+        self.beeper.update(cpu);
+        let start = self.samples.len();
+        self.samples.resize(start + count, 0.0);
+        self.beeper.fill(&mut self.samples[start..]);
+        self.frames += 1;
+    }
+
+    /// The recording so far as a WAV file
+    pub fn to_wav(&self) -> Vec< u8 >
+    {
+        wav::encode_mono(self.beeper.sample_rate(), &self.samples)
+    }
+}
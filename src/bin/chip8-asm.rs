@@ -0,0 +1,88 @@
+//! Assembles a source file into a CHIP-8 ROM
+
+extern crate chip8;
+
+use chip8::args::{ is_option, Args };
+use chip8::asm;
+use chip8::instruction::Platform;
+
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "\
+Usage: chip8-asm [OPTIONS] <SOURCE>
+
+Options:
+    -o, --output <FILE>       Where to write the ROM [default: SOURCE with a .ch8 extension]
+    --platform <PLATFORM>     Instructions to allow: chip8, schip or xochip [default: chip8]
+    -h, --help                Print this help";
+
+/// Settings for a run of the assembler
+struct Options
+{
+    source_path: PathBuf,
+    output_path: PathBuf,
+    platform: Platform,
+}
+
+fn main()
+{
+    let options = match parse_args(std::env::args().skip(1))
+    {
+        Ok(Some(options)) => options,
+        Ok(None) =>
+        {
+            println!("{}", USAGE);
+            return;
+        },
+        Err(e) =>
+        {
+            eprintln!("chip8-asm: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let rom = match asm::assemble_file(&options.source_path, options.platform)
+    {
+        Ok(rom) => rom,
+        Err(e) =>
+        {
+            eprintln!("chip8-asm: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = fs::write(&options.output_path, &rom)
+    {
+        eprintln!("chip8-asm: Could not write \"{}\": {}", options.output_path.display(), e);
+        process::exit(1);
+    }
+}
+
+/// Parses the command line, or returns None if help was asked for
+fn parse_args< I: Iterator< Item = String > >(args: I) -> Result< Option< Options >, String >
+{
+    let mut source_path = None;
+    let mut output_path = None;
+    let mut platform = Platform::Chip8;
+
+    let mut args = Args::new(args);
+    while let Some(flag) = args.next_arg()?
+    {
+        match flag.as_str()
+        {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => output_path = Some(PathBuf::from(args.value()?)),
+            "--platform" => platform = args.value()?.parse()?,
+            _ if is_option(&flag) => return Err(format!("Unknown option \"{}\"", flag)),
+            _ if source_path.is_some() => return Err(format!("Unexpected argument \"{}\"", flag)),
+            _ => source_path = Some(PathBuf::from(flag)),
+        }
+    }
+
+    let source_path = source_path.ok_or_else(|| "No source file given".to_string())?;
+    let output_path = output_path.unwrap_or_else(|| source_path.with_extension("ch8"));
+
+    Ok(Some(Options { source_path, output_path, platform }))
+}
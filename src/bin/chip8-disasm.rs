@@ -0,0 +1,69 @@
+//! Prints a disassembly of a CHIP-8 ROM
+
+extern crate chip8;
+
+use chip8::args::{ is_option, Args };
+use chip8::disasm::{ self, Syntax };
+
+use std::fs;
+use std::process;
+
+const USAGE: &str = "\
+Usage: chip8-disasm [OPTIONS] <ROM>
+
+Options:
+    --syntax <SYNTAX>     Mnemonics to print: cowgod or octo [default: cowgod]
+    --octo                Same as --syntax octo
+    -h, --help            Print this help";
+
+fn main()
+{
+    let (rom_path, syntax) = match parse_args(std::env::args().skip(1))
+    {
+        Ok(Some(args)) => args,
+        Ok(None) =>
+        {
+            println!("{}", USAGE);
+            return;
+        },
+        Err(e) =>
+        {
+            eprintln!("chip8-disasm: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    match fs::read(&rom_path)
+    {
+        Ok(rom) => print!("{}", disasm::disassemble(&rom, syntax)),
+        Err(e) =>
+        {
+            eprintln!("chip8-disasm: Could not read ROM file \"{}\": {}", rom_path, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Parses the ROM path and syntax from the arguments, or None if help was asked for
+fn parse_args< I: Iterator< Item = String > >(args: I) -> Result< Option< (String, Syntax) >, String >
+{
+    let mut rom_path = None;
+    let mut syntax = Syntax::Cowgod;
+
+    let mut args = Args::new(args);
+    while let Some(flag) = args.next_arg()?
+    {
+        match flag.as_str()
+        {
+            "-h" | "--help" => return Ok(None),
+            "--octo" => syntax = Syntax::Octo,
+            "--syntax" => syntax = args.value()?.parse()?,
+            _ if is_option(&flag) => return Err(format!("Unknown option \"{}\"", flag)),
+            _ if rom_path.is_some() => return Err(format!("Unexpected argument \"{}\"", flag)),
+            _ => rom_path = Some(flag),
+        }
+    }
+
+    let rom_path = rom_path.ok_or_else(|| "No ROM file given".to_string())?;
+    Ok(Some((rom_path, syntax)))
+}
@@ -0,0 +1,330 @@
+//! Command line parsing for the chip8-rs frontend
+
+use chip8::args::{ is_option, Args };
+use chip8::audio::{ Waveform, DEFAULT_FREQUENCY, DEFAULT_VOLUME };
+use chip8::cpu::CPU_CLOCK;
+use chip8::persistence::Persistence;
+use chip8::quirks::Quirks;
+use chip8::timing::Timing;
+
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: chip8-rs [OPTIONS] <ROM>
+
+ROM is a CHIP-8 binary, or Octo source if its name ends in .8o
+
+Options:
+    --hz <N>              Instructions executed per second [default: 600]
+    --timing <MODE>       fixed runs --hz instructions per second; vip gives each instruction
+                          its COSMAC VIP execution time and ignores --hz [default: fixed]
+    --speed <X>           Emulation speed as a multiple of real time, e.g. 0.5 or 2. Hold Tab
+                          to fast-forward and press [ and ] to halve or double it [default: 1]
+    --scale <N>           Size in window pixels of a low resolution pixel [default: 10]
+    --quirks <SPEC>       Interpreter quirks: a preset (vip, chip48, schip, octo)
+                          followed by optional flag overrides, e.g. schip,wrap_sprites=1
+                          [default: octo]
+    --fullscreen          Start in fullscreen
+    --palette <PALETTE>   Display colours: classic, green, amber, lcd, octo, a palette from
+                          --palette-file, or 2 or 4 hex colours such as #000000,#33FF66.
+                          F11 switches palette while running [default: classic]
+    --palette-file <FILE> Add the palettes in FILE, one per line as name: #RRGGBB #RRGGBB
+                          with optional third and fourth colours for XO-CHIP planes
+    --persistence <MODE>  Anti-flicker: off (the default), fade[:DECAY] to fade pixels out
+                          keeping DECAY of their brightness each frame [default: 0.6], or
+                          blend[:FRAMES] to show pixels lit in any of the last FRAMES frames
+                          [default: 2]
+    --paused              Start paused. Press P to pause and resume
+    --seed <N>            Seed for the random number generator used by Cxnn
+    --debug               Start paused in the debugger. Type help on stdin for commands.
+                          F12 breaks into the debugger at any time
+    --rewind <SECONDS>    How far back Backspace can rewind, 0 to disable [default: 10]
+    --mute                Do not play the sound timer's beep
+    --beep-hz <N>         Pitch of the beep in Hz [default: 440]
+    --volume <N>          Volume of the beep from 0 to 100 [default: 25]
+    --waveform <SHAPE>    Shape of the beep: square, sine, triangle or sawtooth [default: square]
+    --record-audio <FILE> Record the sound of the run, in emulated time, to a WAV file.
+                          Also works with --mute and --headless
+    --record-gif <FILE>   Record the display, in emulated time, to an animated GIF.
+                          Also works with --headless
+    --screenshot-dir <DIR>
+                          Where screenshots and recordings are written, named after the ROM
+                          and the frame number. PrintScreen takes a screenshot and G starts
+                          and stops a GIF recording [default: .]
+    --screenshot-scale <N>
+                          Size in image pixels of a screenshot or recording pixel, 1 for the
+                          screen's native resolution [default: 1]
+    -h, --help            Print this help
+
+Headless options:
+    --headless            Run without a window for a fixed time, then dump the machine state
+    --frames <N>          Frames to run, at 60 per second [default: 600]
+    --cycles <N>          Instructions to run instead of a number of frames
+    --press <F:KEY[:N]>   Hold hex KEY down for N frames starting at frame F [default N: 1].
+                          May be given more than once
+    --screenshot <FRAME>  Take a screenshot once FRAME frames have run. May be given more
+                          than once
+    --dump-display <FILE> Write the final screen to FILE, as PNG if it ends in .png and
+                          as text otherwise. PNGs are scaled by --screenshot-scale.
+                          - is stdout [default: -]
+    --dump-registers <FILE>
+                          Write the final registers to FILE as JSON. - is stdout";
+
+/// Options that only make sense for a headless run
+const HEADLESS_OPTIONS: [&str; 6] = ["--frames", "--cycles", "--press", "--screenshot", "--dump-display", "--dump-registers"];
+
+/// Settings for a run of the emulator. Builds without SDL only run headless
+/// and ignore the window options
+#[derive(Debug)]
+#[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+pub struct Options
+{
+    pub rom_path: PathBuf,
+    pub cpu_hz: u32,
+    pub timing: Timing,
+    pub speed: f64,
+    pub scale: u32,
+    pub quirks: Quirks,
+    pub fullscreen: bool,
+    pub palette: Option< String >,
+    pub palette_file: Option< PathBuf >,
+    pub persistence: Persistence,
+    pub paused: bool,
+    pub seed: Option< u64 >,
+    pub rewind_seconds: u32,
+    pub debug: bool,
+    pub mute: bool,
+    pub beep_hz: f32,
+    pub volume: f32,
+    pub waveform: Waveform,
+    pub record_audio: Option< PathBuf >,
+    pub record_gif: Option< PathBuf >,
+    pub screenshot_dir: PathBuf,
+    pub screenshot_scale: u32,
+    pub headless: bool,
+    pub run_length: RunLength,
+    pub key_presses: Vec< KeyPress >,
+    pub screenshot_frames: Vec< u64 >,
+    pub dump_display: Option< PathBuf >,
+    pub dump_registers: Option< PathBuf >,
+}
+
+/// How long a headless run lasts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunLength
+{
+    Frames(u64),
+    Cycles(u64),
+}
+
+/// A key held down during a headless run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyPress
+{
+    pub frame: u64,
+    pub key: usize,
+    pub frames: u64,
+}
+
+/// What the command line asks the frontend to do
+#[derive(Debug)]
+pub enum Command
+{
+    Run(Box< Options >),
+    Help,
+}
+
+/// Parses the command line arguments, not including the program name
+pub fn parse_args< I: Iterator< Item = String > >(args: I) -> Result< Command, String >
+{
+    let mut rom_path = None;
+    let mut cpu_hz = CPU_CLOCK as u32;
+    let mut timing = Timing::default();
+    let mut speed = 1.0;
+    let mut scale = 10;
+    let mut quirks = Quirks::default();
+    let mut fullscreen = false;
+    let mut palette = None;
+    let mut palette_file = None;
+    let mut persistence = Persistence::default();
+    let mut paused = false;
+    let mut seed = None;
+    let mut rewind_seconds = 10;
+    let mut debug = false;
+    let mut mute = false;
+    let mut beep_hz = DEFAULT_FREQUENCY;
+    let mut volume = DEFAULT_VOLUME;
+    let mut waveform = Waveform::default();
+    let mut record_audio = None;
+    let mut record_gif = None;
+    let mut screenshot_dir = PathBuf::from(".");
+    let mut screenshot_scale = 1;
+    let mut headless = false;
+    let mut run_length = RunLength::Frames(600);
+    let mut key_presses = Vec::new();
+    let mut screenshot_frames = Vec::new();
+    let mut dump_display = None;
+    let mut dump_registers = None;
+
+    // The first headless-only option seen, which is an error without --headless
+    let mut headless_option = None;
+
+    let mut args = Args::new(args);
+    while let Some(flag) = args.next_arg()?
+    {
+        if HEADLESS_OPTIONS.contains(&flag.as_str()) && headless_option.is_none()
+        {
+            headless_option = Some(flag.clone());
+        }
+
+        match flag.as_str()
+        {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--hz" => cpu_hz = parse_positive(&flag, &args.value()?)?,
+            "--timing" => timing = args.value()?.parse()?,
+            "--speed" => speed = parse_speed(&flag, &args.value()?)?,
+            "--scale" => scale = parse_positive(&flag, &args.value()?)?,
+            "--quirks" => quirks = args.value()?.parse()?,
+            "--fullscreen" => fullscreen = true,
+            "--palette" => palette = Some(args.value()?),
+            "--palette-file" => palette_file = Some(PathBuf::from(args.value()?)),
+            "--persistence" => persistence = args.value()?.parse()?,
+            "--paused" => paused = true,
+            "--debug" => debug = true,
+            "--seed" => seed = Some(parse_u64(&flag, &args.value()?)?),
+            "--rewind" => rewind_seconds = args.value()?.parse().map_err(|_| format!("{} expects a whole number of seconds", flag))?,
+            "--mute" => mute = true,
+            "--beep-hz" => beep_hz = parse_positive(&flag, &args.value()?)? as f32,
+            "--volume" => volume = parse_volume(&flag, &args.value()?)?,
+            "--waveform" => waveform = args.value()?.parse()?,
+            "--record-audio" => record_audio = Some(PathBuf::from(args.value()?)),
+            "--record-gif" => record_gif = Some(PathBuf::from(args.value()?)),
+            "--screenshot-dir" => screenshot_dir = PathBuf::from(args.value()?),
+            "--screenshot-scale" => screenshot_scale = parse_positive(&flag, &args.value()?)?,
+            "--headless" => headless = true,
+            "--frames" => run_length = RunLength::Frames(parse_u64(&flag, &args.value()?)?),
+            "--cycles" => run_length = RunLength::Cycles(parse_u64(&flag, &args.value()?)?),
+            "--press" => key_presses.push(parse_key_press(&flag, &args.value()?)?),
+            "--screenshot" => screenshot_frames.push(parse_positive(&flag, &args.value()?)? as u64),
+            "--dump-display" => dump_display = Some(PathBuf::from(args.value()?)),
+            "--dump-registers" => dump_registers = Some(PathBuf::from(args.value()?)),
+            _ if is_option(&flag) => return Err(format!("Unknown option \"{}\"", flag)),
+            _ =>
+            {
+                if rom_path.is_some()
+                {
+                    return Err(format!("Unexpected argument \"{}\"", flag));
+                }
+                rom_path = Some(PathBuf::from(flag));
+            }
+        }
+    }
+
+    let rom_path = rom_path.ok_or_else(|| "No ROM file given".to_string())?;
+    if let Some(flag) = headless_option.filter(|_| !headless)
+    {
+        return Err(format!("{} can only be used with --headless", flag));
+    }
+
+    Ok(Command::Run(Box::new(Options {
+        rom_path,
+        cpu_hz,
+        timing,
+        speed,
+        scale,
+        quirks,
+        fullscreen,
+        palette,
+        palette_file,
+        persistence,
+        paused,
+        seed,
+        rewind_seconds,
+        debug,
+        mute,
+        beep_hz,
+        volume,
+        waveform,
+        record_audio,
+        record_gif,
+        screenshot_dir,
+        screenshot_scale,
+        headless,
+        run_length,
+        key_presses,
+        screenshot_frames,
+        dump_display,
+        dump_registers,
+    })))
+}
+
+/// Parses a number greater than zero
+fn parse_positive(flag: &str, value: &str) -> Result< u32, String >
+{
+    match value.parse::< u32 >()
+    {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("{} expects a whole number greater than 0, got \"{}\"", flag, value))
+    }
+}
+
+/// Parses a volume percentage into a volume from 0 to 1
+fn parse_volume(flag: &str, value: &str) -> Result< f32, String >
+{
+    match value.parse::< u32 >()
+    {
+        Ok(n) if n <= 100 => Ok(n as f32 / 100.0),
+        _ => Err(format!("{} expects a whole number from 0 to 100, got \"{}\"", flag, value))
+    }
+}
+
+/// Parses a speed multiplier greater than zero
+fn parse_speed(flag: &str, value: &str) -> Result< f64, String >
+{
+    match value.parse::< f64 >()
+    {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok(n),
+        _ => Err(format!("{} expects a number greater than 0, got \"{}\"", flag, value))
+    }
+}
+
+/// Parses a decimal or 0x-prefixed hexadecimal number
+fn parse_u64(flag: &str, value: &str) -> Result< u64, String >
+{
+    let parsed = if value.starts_with("0x") || value.starts_with("0X")
+    {
+        u64::from_str_radix(&value[2..], 16)
+    }
+    else
+    {
+        value.parse::< u64 >()
+    };
+
+    parsed.map_err(|_| format!("{} expects a number, got \"{}\"", flag, value))
+}
+
+/// Parses a key press script entry, FRAME:KEY or FRAME:KEY:FRAMES with KEY in hex
+fn parse_key_press(flag: &str, value: &str) -> Result< KeyPress, String >
+{
+    let error = || format!("{} expects FRAME:KEY[:FRAMES] with KEY a hex digit, got \"{}\"", flag, value);
+
+    let parts: Vec< &str > = value.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3
+    {
+        return Err(error());
+    }
+
+    let frame = parts[0].parse::< u64 >().map_err(|_| error())?;
+    let key = match usize::from_str_radix(parts[1], 16)
+    {
+        Ok(key) if key <= 0xF => key,
+        _ => return Err(error())
+    };
+    let frames = match parts.get(2)
+    {
+        Some(n) => n.parse::< u64 >().map_err(|_| error())?,
+        None => 1
+    };
+
+    Ok(KeyPress { frame, key, frames })
+}
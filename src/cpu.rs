@@ -1,10 +1,14 @@
-use crate::display::{ Display, CHIP8_FONT };
+use crate::display::{ Display, CHIP8_FONT, SCHIP_FONT };
+use crate::error::Chip8Error;
+use crate::instruction::Instruction;
 use crate::keypad::Keypad;
+use crate::quirks::Quirks;
+use crate::state::{ self, StateReader, StateWriter };
 
 use rand::random;
-use std::error::Error;
 use std::fs::File;
 use std::io::Read;
+use std::ops::Range;
 use std::path::Path;
 
 /// The CPU clock speed in Hz
@@ -19,6 +23,42 @@ const CARRY_FLAG: usize = 15;
 /// The default stack size
 const STACK_SIZE: usize = 16;
 
+/// Size of memory in bytes. XO-CHIP extends the original 4096 bytes to 64K
+pub const MEMORY_SIZE: usize = 0x10000;
+
+/// Address programs are loaded at and start running from
+pub const PROGRAM_START: usize = 0x200;
+
+/// Address of the SUPER-CHIP large font, stored right after the small font
+const SCHIP_FONT_ADDR: usize = 0x50;
+
+/// Number of RPL user flags available to Fx75/Fx85
+pub const RPL_FLAG_COUNT: usize = 16;
+
+/// Whether an instruction read or wrote memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind
+{
+    Read,
+    Write,
+}
+
+/// A range of memory read or written by an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryAccess
+{
+    pub addr: usize,
+    pub len: usize,
+    pub kind: AccessKind,
+}
+
+/// Identifies a chip8-rs save state
+const SAVE_STATE_MAGIC: &[u8; 4] = b"C8SS";
+
+/// Version of the save state format. Bump whenever the saved fields change
+const SAVE_STATE_VERSION: u16 = 3;
+
+#[derive(Clone)]
 pub struct CPU
 {
     /// The current opcode
@@ -34,8 +74,9 @@ pub struct CPU
     /// Program counter
     pc: usize,
 
-    /// Chip-8's 4096 bytes of memory
-    pub memory: [u8; 4096],
+    /// Memory. Chip-8 programs use the first 4096 bytes; XO-CHIP programs 
+    /// can address all 64K
+    pub memory: [u8; MEMORY_SIZE],
 
     /// Chip-8 display
     pub display: Display,
@@ -57,67 +98,246 @@ pub struct CPU
 
     /// Sound timer register
     pub sound_timer: u8,
+
+    /// Interpreter behaviours the running ROM expects
+    pub quirks: Quirks,
+
+    /// SUPER-CHIP RPL user flags. These survive between programs on the HP-48, 
+    /// so frontends may persist them across runs
+    pub rpl: [u8; RPL_FLAG_COUNT],
+
+    /// Has the program exited with 00FD?
+    pub exited: bool,
+
+    /// XO-CHIP audio pattern buffer: 128 1-bit samples loaded by F002
+    pub audio_pattern: [u8; 16],
+
+    /// Has F002 loaded the audio pattern? Until it has, the sound timer 
+    /// plays the frontend's own beep
+    pub audio_pattern_loaded: bool,
+
+    /// XO-CHIP audio playback pitch set by Fx3A
+    pub pitch: u8,
+
+    /// State of the xorshift random number generator used by Cxnn
+    rng_state: u64,
+
+    /// Time left in the current frame under VIP timing, in microseconds.
+    /// Negative when the last instruction of the previous frame ran past its
+    /// end. See `timing::run_vip_frame`
+    pub(crate) vip_micros: i64,
+
+    /// Hash of the loaded ROM, used to match save states to it
+    rom_hash: u64,
+
+    /// Should data accesses to memory be recorded in memory_accesses?
+    trace_memory: bool,
+
+    /// Data accesses made by the last cycle, if tracing is enabled
+    memory_accesses: Vec< MemoryAccess >,
 }
 
 impl CPU
 {
-    /// Creates and returns a new instance of a Chip-8 CPU
-    pub fn new() -> Self
+    /// Creates and returns a new instance of a Chip-8 CPU with the given quirks
+    pub fn new(quirks: Quirks) -> Self
     {
         let mut cpu = CPU {
             opcode: 0u16,
             v: [0u8; 16],
             i: 0usize,
             pc: 0usize,
-            memory: [0u8; 4096],
+            memory: [0u8; MEMORY_SIZE],
             display: Display::new(),
             keypad: Keypad::new(),
             wait_for_key: None,
             stack: [0u16; STACK_SIZE],
             sp: 0usize,
             delay_timer: 0u8,
-            sound_timer: 0u8
+            sound_timer: 0u8,
+            quirks,
+            rpl: [0u8; RPL_FLAG_COUNT],
+            exited: false,
+            audio_pattern: [0u8; 16],
+            audio_pattern_loaded: false,
+            pitch: 64,
+            rng_state: 0u64,
+            vip_micros: 0i64,
+            rom_hash: 0u64,
+            trace_memory: false,
+            memory_accesses: Vec::new()
         };
 
+        cpu.set_rng_seed(random::< u64 >());
+
         // Load the font into memory
-        for i in 0..80
-        {
-            cpu.memory[i] = CHIP8_FONT[i];
-        }
+        cpu.memory[..CHIP8_FONT.len()].copy_from_slice(&CHIP8_FONT);
+        cpu.memory[SCHIP_FONT_ADDR..SCHIP_FONT_ADDR + SCHIP_FONT.len()].copy_from_slice(&SCHIP_FONT);
 
         // program space starts at 0x200
-        cpu.pc = 0x200;
+        cpu.pc = PROGRAM_START;
 
         cpu
     }
 
     /// Loads a Chip-8 ROM from file into the CPU's memory
-    pub fn load_rom(&mut self, path: &Path) -> Option< String >
+    pub fn load_rom(&mut self, path: &Path) -> Result< (), Chip8Error >
     {
-        let file = match File::open(path) {
-            Ok(f) => f,
-            Err(ref e) => return Some(format!("Could not open ROM file \"{}\". Error: {}", path.display(), Error::description(e)))
-        };
+        let io_error = |source| Chip8Error::RomIo { path: path.to_path_buf(), source };
 
-        for (i, b) in file.bytes().enumerate()
+        let mut rom = Vec::new();
+        File::open(path).and_then(|mut file| file.read_to_end(&mut rom)).map_err(io_error)?;
+
+        self.load_program(&rom).map_err(|e| match e
         {
-            match b
-            {
-                Ok(byte) => self.memory[self.pc + i] = byte,
-                Err(e) => return Some(format!("Error reading ROM file: {}", e.to_string()))
-            }
+            Chip8Error::ProgramTooLarge { size, max } => Chip8Error::RomTooLarge { path: path.to_path_buf(), size, max },
+            e => e
+        })
+    }
+
+    /// Loads a program that is already in memory, such as one compiled from
+    /// source, at the start of program space
+    pub fn load_program(&mut self, program: &[u8]) -> Result< (), Chip8Error >
+    {
+        let max = self.memory.len() - self.pc;
+        if program.len() > max
+        {
+            return Err(Chip8Error::ProgramTooLarge { size: program.len(), max });
         }
 
-        None
+        self.memory[self.pc..self.pc + program.len()].copy_from_slice(program);
+        self.rom_hash = state::rom_hash(program);
+
+        Ok(())
     }
 
-    /// Executes a single Chip-8 CPU cycle
-    pub fn cpu_cycle(&mut self)
+    /// Captures the whole machine in a versioned binary snapshot that 
+    /// `load_state` can restore exactly
+    pub fn save_state(&self) -> Vec< u8 >
     {
-        self.fetch_opcode();
-        self.execute_opcode();
+        let mut w = StateWriter::new();
+        w.bytes(SAVE_STATE_MAGIC);
+        w.u16(SAVE_STATE_VERSION);
+        w.u64(self.rom_hash);
+
+        w.u16(self.opcode);
+        w.bytes(&self.v);
+        w.u32(self.i as u32);
+        w.u32(self.pc as u32);
+        for &frame in self.stack.iter()
+        {
+            w.u16(frame);
+        }
+        w.u8(self.sp as u8);
+        w.u8(self.delay_timer);
+        w.u8(self.sound_timer);
+        w.bool(self.wait_for_key.is_some());
+        w.u8(self.wait_for_key.unwrap_or(0));
+        w.bool(self.quirks.shift_vy);
+        w.bool(self.quirks.load_store_increment_i);
+        w.bool(self.quirks.jump_vx);
+        w.bool(self.quirks.wrap_sprites);
+        w.bool(self.quirks.vf_reset);
+        w.bytes(&self.rpl);
+        w.bool(self.exited);
+        w.bytes(&self.audio_pattern);
+        w.bool(self.audio_pattern_loaded);
+        w.u8(self.pitch);
+        w.u64(self.rng_state);
+        w.u64(self.vip_micros as u64);
+        self.keypad.save_state(&mut w);
+        self.display.save_state(&mut w);
+        w.bytes(&self.memory);
+
+        w.into_inner()
     }
 
+    /// Restores a snapshot made by `save_state`. The snapshot must come from 
+    /// the same ROM. On error the CPU is left unchanged
+    pub fn load_state(&mut self, data: &[u8]) -> Result< (), Chip8Error >
+    {
+        let mut r = StateReader::new(data);
+        if r.bytes(SAVE_STATE_MAGIC.len())? != SAVE_STATE_MAGIC
+        {
+            return Err(Chip8Error::InvalidSaveState("not a chip8-rs save state".to_string()));
+        }
+
+        let version = r.u16()?;
+        if version != SAVE_STATE_VERSION
+        {
+            return Err(Chip8Error::InvalidSaveState(format!("version {} is not supported, expected {}", version, SAVE_STATE_VERSION)));
+        }
+
+        if r.u64()? != self.rom_hash
+        {
+            return Err(Chip8Error::SaveStateRomMismatch);
+        }
+
+        // Read into a copy so a bad snapshot leaves this CPU untouched
+        let mut cpu = self.clone();
+        cpu.opcode = r.u16()?;
+        cpu.v.copy_from_slice(r.bytes(16)?);
+        cpu.i = r.u32()? as usize;
+        cpu.pc = r.u32()? as usize;
+        for frame in cpu.stack.iter_mut()
+        {
+            *frame = r.u16()?;
+        }
+        cpu.sp = r.u8()? as usize;
+        cpu.delay_timer = r.u8()?;
+        cpu.sound_timer = r.u8()?;
+        let waiting = r.bool()?;
+        let wait_register = r.u8()?;
+        cpu.wait_for_key = if waiting { Some(wait_register) } else { None };
+        cpu.quirks.shift_vy = r.bool()?;
+        cpu.quirks.load_store_increment_i = r.bool()?;
+        cpu.quirks.jump_vx = r.bool()?;
+        cpu.quirks.wrap_sprites = r.bool()?;
+        cpu.quirks.vf_reset = r.bool()?;
+        cpu.rpl.copy_from_slice(r.bytes(RPL_FLAG_COUNT)?);
+        cpu.exited = r.bool()?;
+        cpu.audio_pattern.copy_from_slice(r.bytes(16)?);
+        cpu.audio_pattern_loaded = r.bool()?;
+        cpu.pitch = r.u8()?;
+        cpu.rng_state = r.u64()?;
+        cpu.vip_micros = r.u64()? as i64;
+        cpu.keypad.load_state(&mut r)?;
+        cpu.display.load_state(&mut r)?;
+        cpu.memory.copy_from_slice(r.bytes(MEMORY_SIZE)?);
+        r.finish()?;
+
+        if cpu.sp > STACK_SIZE || cpu.wait_for_key.is_some_and(|x| x > 0xF)
+        {
+            return Err(Chip8Error::InvalidSaveState("register values out of range".to_string()));
+        }
+
+        *self = cpu;
+        Ok(())
+    }
+
+    /// Executes a single Chip-8 CPU cycle. Does nothing once the program has exited. 
+    /// If the instruction fails the CPU is left as it was before the cycle, so 
+    /// the frontend can report the error and stop
+    pub fn cpu_cycle(&mut self) -> Result< (), Chip8Error >
+    {
+        if self.exited
+        {
+            return Ok(());
+        }
+
+        self.memory_accesses.clear();
+        self.fetch_opcode()?;
+        self.execute_opcode()
+    }
+
+    /// Seeds the random number generator used by Cxnn, making runs repeatable
+    pub fn set_rng_seed(&mut self, seed: u64)
+    {
+        // xorshift gets stuck at zero, so a zero seed is replaced
+        self.rng_state = if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed };
+    }
+
+    /// Decrements the delay and sound timers. Should be called at TIMER_CLOCK Hz
     pub fn update_cpu_timers(&mut self)
     {
         if self.delay_timer > 0
@@ -131,6 +351,44 @@ impl CPU
         }
     }
 
+    /// The general purpose registers V0 to VF
+    pub fn v(&self) -> &[u8; 16]
+    {
+        &self.v
+    }
+
+    /// The index register
+    pub fn i(&self) -> usize
+    {
+        self.i
+    }
+
+    /// The program counter
+    pub fn pc(&self) -> usize
+    {
+        self.pc
+    }
+
+    /// Enables or disables recording of the memory reads and writes made by 
+    /// each instruction. Instruction fetches are not recorded
+    pub fn set_memory_tracing(&mut self, enabled: bool)
+    {
+        self.trace_memory = enabled;
+        self.memory_accesses.clear();
+    }
+
+    /// The memory reads and writes made by the last cycle, if tracing is enabled
+    pub fn memory_accesses(&self) -> &[MemoryAccess]
+    {
+        &self.memory_accesses
+    }
+
+    /// The opcode at the program counter, which the next cycle will execute
+    pub fn peek_opcode(&self) -> Option< u16 >
+    {
+        self.read_word(self.pc).ok()
+    }
+
     pub fn is_waiting_for_key(&self) -> bool
     {
         self.wait_for_key.is_some()
@@ -149,70 +407,146 @@ impl CPU
             {
                 self.v[x as usize] = key as u8;
                 self.wait_for_key = None;
+                self.pc += 2;
             }
         }
     }
 
     /// Fetches the next opcode to execute from memory
-    fn fetch_opcode(&mut self)
+    fn fetch_opcode(&mut self) -> Result< (), Chip8Error >
+    {
+        self.opcode = self.read_word(self.pc)?;
+        Ok(())
+    }
+
+    /// Reads the big-endian 16-bit word at addr
+    fn read_word(&self, addr: usize) -> Result< u16, Chip8Error >
+    {
+        let range = self.memory_range(addr, 2)?;
+        Ok((self.memory[range.start] as u16) << 8 | (self.memory[range.start + 1] as u16))
+    }
+
+    /// Returns the range of len bytes of memory starting at addr, or an error 
+    /// if it runs past the end of memory
+    fn memory_range(&self, addr: usize, len: usize) -> Result< Range< usize >, Chip8Error >
+    {
+        if addr + len > self.memory.len()
+        {
+            return Err(Chip8Error::MemoryOutOfBounds { pc: self.pc, addr, len });
+        }
+
+        Ok(addr..addr + len)
+    }
+
+    /// The single path through which instructions read or write memory. Checks 
+    /// the access is in bounds, records it when tracing, and returns the range 
+    /// of memory to use
+    fn access_memory(&mut self, addr: usize, len: usize, kind: AccessKind) -> Result< Range< usize >, Chip8Error >
     {
-        self.opcode = (self.memory[self.pc] as u16) << 8 | (self.memory[self.pc + 1] as u16);
+        let range = self.memory_range(addr, len)?;
+        if self.trace_memory
+        {
+            self.memory_accesses.push(MemoryAccess { addr, len, kind });
+        }
+
+        Ok(range)
     }
 
     /// Executes the currently stored opcode
-    fn execute_opcode(&mut self)
-    {
-        // Get the value of each nibble X, Y, Z, W from opcode 0xXYZW for easy matching
-        let op = (
-            ((self.opcode & 0xF000) >> 12) as u8,
-            ((self.opcode & 0x0F00) >> 8) as u8,
-            ((self.opcode & 0x00F0) >> 4) as u8,
-            (self.opcode & 0x000F) as u8
-        );
-
-        // Match the opcode to the related instruction function
-        // http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
-        match op
-        {
-            (0x0, 0x0, 0xE, 0x0) => self.instr_cls(),
-            (0x0, 0x0, 0xE, 0xE) => self.instr_ret(),
-            (0x1, _, _, _) => self.instr_jp_addr(self.opcode & 0x0FFF),
-            (0x2, _, _, _) => self.instr_call_addr(self.opcode & 0x0FFF),
-            (0x3, x, _, _) => self.instr_se_vx_nn(x, (self.opcode & 0x00FF) as u8),
-            (0x4, x, _, _) => self.instr_sne_vx_nn(x, (self.opcode & 0x00FF) as u8),
-            (0x5, x, y, 0x0) => self.instr_se_vx_vy(x, y),
-            (0x6, x, _, _) => self.instr_ld_vx_nn(x, (self.opcode & 0x00FF) as u8),
-            (0x7, x, _, _) => self.instr_add_vx_nn(x, (self.opcode & 0x00FF) as u8),
-            (0x8, x, y, 0x0) => self.instr_ld_vx_vy(x, y),
-            (0x8, x, y, 0x1) => self.instr_or_vx_vy(x, y),
-            (0x8, x, y, 0x2) => self.instr_and_vx_vy(x, y),
-            (0x8, x, y, 0x3) => self.instr_xor_vx_vy(x, y),
-            (0x8, x, y, 0x4) => self.instr_add_vx_vy(x, y),
-            (0x8, x, y, 0x5) => self.instr_sub_vx_vy(x, y),
-            (0x8, x, y, 0x6) => self.instr_shr_vx_vy(x, y),
-            (0x8, x, y, 0x7) => self.instr_subn_vx_vy(x, y),
-            (0x8, x, y, 0xE) => self.instr_shl_vx_vy(x, y),
-            (0x9, x, y, 0x0) => self.instr_sne_vx_vy(x, y),
-            (0xA, _, _, _) => self.instr_ld_i_addr(self.opcode & 0x0FFF),
-            (0xB, _, _, _) => self.instr_jp_v0_addr(self.opcode & 0x0FFF),
-            (0xC, x, _, _) => self.instr_rnd_vx_nn(x, (self.opcode & 0x00FF) as u8),
-            (0xD, x, y, n) => self.instr_drw_vx_vy_nn(x, y, n),
-            (0xE, x, 0x9, 0xE) => self.instr_skp_vx(x),
-            (0xE, x, 0xA, 0x1) => self.instr_sknp_vx(x),
-            (0xF, x, 0x0, 0x7) => self.instr_ld_vx_dt(x),
-            (0xF, x, 0x0, 0xA) => self.instr_ld_vx_k(x),
-            (0xF, x, 0x1, 0x5) => self.instr_ld_dt_vx(x),
-            (0xF, x, 0x1, 0x8) => self.instr_ld_st_vx(x),
-            (0xF, x, 0x1, 0xE) => self.instr_add_i_vx(x),
-            (0xF, x, 0x2, 0x9) => self.instr_ld_f_vx(x),
-            (0xF, x, 0x3, 0x3) => self.instr_ld_b_vx(x),
-            (0xF, x, 0x5, 0x5) => self.instr_ld_i_vx(x),
-            (0xF, x, 0x6, 0x5) => self.instr_ld_vx_i(x),
-
-            _ => {}
+    fn execute_opcode(&mut self) -> Result< (), Chip8Error >
+    {
+        let instruction = match Instruction::decode(self.opcode)
+        {
+            Some(instruction) => instruction,
+            None => return Err(Chip8Error::UnknownOpcode { opcode: self.opcode, pc: self.pc })
+        };
+
+        // Match the instruction to the related instruction function
+        match instruction
+        {
+            Instruction::ScrollDown(n) => self.instr_scd_n(n),
+            Instruction::ScrollUp(n) => self.instr_scu_n(n),
+            Instruction::Cls => self.instr_cls(),
+            Instruction::Ret => self.instr_ret()?,
+            Instruction::ScrollRight => self.instr_scr(),
+            Instruction::ScrollLeft => self.instr_scl(),
+            Instruction::Exit => self.instr_exit(),
+            Instruction::Low => self.instr_low(),
+            Instruction::High => self.instr_high(),
+            Instruction::Jp(nnn) => self.instr_jp_addr(nnn),
+            Instruction::Call(nnn) => self.instr_call_addr(nnn)?,
+            Instruction::SeVxNn(x, nn) => self.instr_se_vx_nn(x, nn),
+            Instruction::SneVxNn(x, nn) => self.instr_sne_vx_nn(x, nn),
+            Instruction::SeVxVy(x, y) => self.instr_se_vx_vy(x, y),
+            Instruction::SaveRange(x, y) => self.instr_ld_i_vx_vy(x, y)?,
+            Instruction::LoadRange(x, y) => self.instr_ld_vx_vy_i(x, y)?,
+            Instruction::LdVxNn(x, nn) => self.instr_ld_vx_nn(x, nn),
+            Instruction::AddVxNn(x, nn) => self.instr_add_vx_nn(x, nn),
+            Instruction::LdVxVy(x, y) => self.instr_ld_vx_vy(x, y),
+            Instruction::Or(x, y) => self.instr_or_vx_vy(x, y),
+            Instruction::And(x, y) => self.instr_and_vx_vy(x, y),
+            Instruction::Xor(x, y) => self.instr_xor_vx_vy(x, y),
+            Instruction::AddVxVy(x, y) => self.instr_add_vx_vy(x, y),
+            Instruction::Sub(x, y) => self.instr_sub_vx_vy(x, y),
+            Instruction::Shr(x, y) => self.instr_shr_vx_vy(x, y),
+            Instruction::Subn(x, y) => self.instr_subn_vx_vy(x, y),
+            Instruction::Shl(x, y) => self.instr_shl_vx_vy(x, y),
+            Instruction::SneVxVy(x, y) => self.instr_sne_vx_vy(x, y),
+            Instruction::LdI(nnn) => self.instr_ld_i_addr(nnn),
+            Instruction::JpV0(nnn) => self.instr_jp_v0_addr(nnn),
+            Instruction::Rnd(x, nn) => self.instr_rnd_vx_nn(x, nn),
+            Instruction::Drw(x, y, n) => self.instr_drw_vx_vy_nn(x, y, n)?,
+            Instruction::Skp(x) => self.instr_skp_vx(x),
+            Instruction::Sknp(x) => self.instr_sknp_vx(x),
+            Instruction::LdILong => self.instr_ld_i_long()?,
+            Instruction::Plane(n) => self.instr_plane_n(n),
+            Instruction::Audio => self.instr_audio()?,
+            Instruction::LdVxDt(x) => self.instr_ld_vx_dt(x),
+            Instruction::LdVxK(x) => self.instr_ld_vx_k(x),
+            Instruction::LdDtVx(x) => self.instr_ld_dt_vx(x),
+            Instruction::LdStVx(x) => self.instr_ld_st_vx(x),
+            Instruction::AddIVx(x) => self.instr_add_i_vx(x),
+            Instruction::LdFVx(x) => self.instr_ld_f_vx(x),
+            Instruction::LdHfVx(x) => self.instr_ld_hf_vx(x),
+            Instruction::LdBVx(x) => self.instr_ld_b_vx(x)?,
+            Instruction::Pitch(x) => self.instr_pitch_vx(x),
+            Instruction::LdIVx(x) => self.instr_ld_i_vx(x)?,
+            Instruction::LdVxI(x) => self.instr_ld_vx_i(x)?,
+            Instruction::LdRVx(x) => self.instr_ld_r_vx(x),
+            Instruction::LdVxR(x) => self.instr_ld_vx_r(x),
+        }
+
+        Ok(())
+    }
+
+    /// Skips the next instruction if cond is true. The XO-CHIP F000 nnnn 
+    /// instruction is four bytes long and is skipped as a whole
+    fn skip_next_if(&mut self, cond: bool)
+    {
+        self.pc += 2;
+        if cond
+        {
+            self.pc += match self.read_word(self.pc) { Ok(0xF000) => 4, _ => 2 };
+        }
+    }
+
+    /// Sets VF to 0 after a logical operation if the vf_reset quirk is set
+    fn reset_vf_quirk(&mut self)
+    {
+        if self.quirks.vf_reset
+        {
+            self.v[CARRY_FLAG] = 0x0;
         }
     }
 
+    /// Stores the result of an arithmetic instruction in Vx, then the flag in 
+    /// VF. VF is written last so it holds the flag even when x is F
+    fn set_with_flag(&mut self, x: u8, value: u8, flag: bool)
+    {
+        self.v[x as usize] = value;
+        self.v[CARRY_FLAG] = flag as u8;
+    }
+
     /// Instruction executed by opcode 00E0 
     /// Clear the display
     fn instr_cls(&mut self)
@@ -223,12 +557,73 @@ impl CPU
 
     /// Instruction executed by opcode 00EE 
     /// Return from a subroutine
-    fn instr_ret(&mut self)
+    fn instr_ret(&mut self) -> Result< (), Chip8Error >
     {
+        if self.sp == 0
+        {
+            return Err(Chip8Error::StackUnderflow { pc: self.pc });
+        }
+
         self.sp -= 1;
         let addr = self.stack[self.sp];
         self.instr_jp_addr(addr);
         self.pc += 2;
+        Ok(())
+    }
+
+    /// Instruction executed by opcode 00Cn (SUPER-CHIP) 
+    /// Scroll the display down n pixels
+    fn instr_scd_n(&mut self, n: u8)
+    {
+        self.display.scroll_down(n as usize);
+        self.pc += 2;
+    }
+
+    /// Instruction executed by opcode 00Dn (XO-CHIP) 
+    /// Scroll the selected planes up n pixels
+    fn instr_scu_n(&mut self, n: u8)
+    {
+        self.display.scroll_up(n as usize);
+        self.pc += 2;
+    }
+
+    /// Instruction executed by opcode 00FB (SUPER-CHIP) 
+    /// Scroll the display right 4 pixels
+    fn instr_scr(&mut self)
+    {
+        self.display.scroll_right(4);
+        self.pc += 2;
+    }
+
+    /// Instruction executed by opcode 00FC (SUPER-CHIP) 
+    /// Scroll the display left 4 pixels
+    fn instr_scl(&mut self)
+    {
+        self.display.scroll_left(4);
+        self.pc += 2;
+    }
+
+    /// Instruction executed by opcode 00FD (SUPER-CHIP) 
+    /// Exit the interpreter
+    fn instr_exit(&mut self)
+    {
+        self.exited = true;
+    }
+
+    /// Instruction executed by opcode 00FE (SUPER-CHIP) 
+    /// Switch to 64x32 low resolution mode
+    fn instr_low(&mut self)
+    {
+        self.display.set_hires(false);
+        self.pc += 2;
+    }
+
+    /// Instruction executed by opcode 00FF (SUPER-CHIP) 
+    /// Switch to 128x64 high resolution mode
+    fn instr_high(&mut self)
+    {
+        self.display.set_hires(true);
+        self.pc += 2;
     }
 
     /// Instruction executed by opcode 1nnn 
@@ -240,32 +635,73 @@ impl CPU
 
     /// Instruction executed by opcode 2nnn 
     /// Call subroutine at location nnn
-    fn instr_call_addr(&mut self, addr: u16)
+    fn instr_call_addr(&mut self, addr: u16) -> Result< (), Chip8Error >
     {
+        if self.sp == STACK_SIZE
+        {
+            return Err(Chip8Error::StackOverflow { pc: self.pc });
+        }
+
         self.stack[self.sp] = self.pc as u16;
         self.sp += 1;
         self.instr_jp_addr(addr);
+        Ok(())
     }
 
     /// Instruction executed by opcode 3xnn 
     /// Skip next instruction if Vx = nn
     fn instr_se_vx_nn(&mut self, x: u8, nn: u8)
     {
-        self.pc += if self.v[x as usize] == nn { 4 } else { 2 };
+        self.skip_next_if(self.v[x as usize] == nn);
     }
 
     /// Instruction executed by opcode 4xnn 
     /// Skip next instruction if Vx != nn
     fn instr_sne_vx_nn(&mut self, x: u8, nn: u8)
     {
-        self.pc += if self.v[x as usize] != nn { 4 } else { 2 };
+        self.skip_next_if(self.v[x as usize] != nn);
     }
 
     /// Instruction executed by opcode 5xy0 
     /// Skip next instruction if Vx = Vy
     fn instr_se_vx_vy(&mut self, x: u8, y: u8)
     {
-        self.pc += if self.v[x as usize] == self.v[y as usize] { 4 } else { 2 };
+        self.skip_next_if(self.v[x as usize] == self.v[y as usize]);
+    }
+
+    /// Instruction executed by opcode 5xy2 (XO-CHIP) 
+    /// Stores registers Vx through Vy in memory starting at location I. 
+    /// The registers are stored in reverse order if x > y. I is not changed
+    fn instr_ld_i_vx_vy(&mut self, x: u8, y: u8) -> Result< (), Chip8Error >
+    {
+        let range = self.access_memory(self.i, x.abs_diff(y) as usize + 1, AccessKind::Write)?;
+        for (addr, reg) in range.zip(Self::register_range(x, y))
+        {
+            self.memory[addr] = self.v[reg];
+        }
+        self.pc += 2;
+        Ok(())
+    }
+
+    /// Instruction executed by opcode 5xy3 (XO-CHIP) 
+    /// Reads registers Vx through Vy from memory starting at location I. 
+    /// The registers are read in reverse order if x > y. I is not changed
+    fn instr_ld_vx_vy_i(&mut self, x: u8, y: u8) -> Result< (), Chip8Error >
+    {
+        let range = self.access_memory(self.i, x.abs_diff(y) as usize + 1, AccessKind::Read)?;
+        for (addr, reg) in range.zip(Self::register_range(x, y))
+        {
+            self.v[reg] = self.memory[addr];
+        }
+        self.pc += 2;
+        Ok(())
+    }
+
+    /// Register indices from x to y inclusive, counting down if x > y
+    fn register_range(x: u8, y: u8) -> Box< dyn Iterator< Item = usize > >
+    {
+        let (x, y) = (x as usize, y as usize);
+        if x <= y { Box::new(x..=y) } else { Box::new((y..=x).rev()) }
     }
 
     /// Instruction executed by opcode 6xnn 
@@ -296,7 +732,8 @@ impl CPU
     /// Set Vx = Vx OR Vy
     fn instr_or_vx_vy(&mut self, x: u8, y: u8)
     {
-        self.v[x as usize] = self.v[x as usize] | self.v[y as usize];
+        self.v[x as usize] |= self.v[y as usize];
+        self.reset_vf_quirk();
         self.pc += 2;
     }
 
@@ -304,7 +741,8 @@ impl CPU
     /// Set Vx = Vx AND Vy
     fn instr_and_vx_vy(&mut self, x: u8, y: u8)
     {
-        self.v[x as usize] = self.v[x as usize] & self.v[y as usize];
+        self.v[x as usize] &= self.v[y as usize];
+        self.reset_vf_quirk();
         self.pc += 2;
     }
 
@@ -312,7 +750,8 @@ impl CPU
     /// Set Vx = Vx XOR Vy
     fn instr_xor_vx_vy(&mut self, x: u8, y: u8)
     {
-        self.v[x as usize] = self.v[x as usize] ^ self.v[y as usize];
+        self.v[x as usize] ^= self.v[y as usize];
+        self.reset_vf_quirk();
         self.pc += 2;
     }
 
@@ -321,9 +760,8 @@ impl CPU
     /// Sets carry flag to 0x1 if the result is greater than 8 bits (i.e. > 255)
     fn instr_add_vx_vy(&mut self, x: u8, y: u8)
     {
-        let value = self.v[x as usize] as u16 + self.v[y as usize] as u16;
-        self.v[x as usize] = value as u8;
-        self.v[CARRY_FLAG] = if value > 255 { 0x1 } else { 0x0 };
+        let (value, carry) = self.v[x as usize].overflowing_add(self.v[y as usize]);
+        self.set_with_flag(x, value, carry);
         self.pc += 2;
     }
 
@@ -333,19 +771,19 @@ impl CPU
     /// and 0x1 if a borrow does not occur
     fn instr_sub_vx_vy(&mut self, x: u8, y: u8)
     {
-        let value = self.v[x as usize] as i8 - self.v[y as usize] as i8;
-        self.v[x as usize] = value as u8;
-        self.v[CARRY_FLAG] = if value < 0 { 0x0 } else { 0x1 };   
+        let (value, borrow) = self.v[x as usize].overflowing_sub(self.v[y as usize]);
+        self.set_with_flag(x, value, !borrow);
         self.pc += 2;
     }
 
     /// Instruction executed by opcode 8xy6 
     /// Store the value of Vy shifted right one bit in Vx then 
-    /// set the carry flag to the most significant bit prior to the shift
+    /// set the carry flag to the least significant bit prior to the shift. 
+    /// Shifts Vx in place instead unless the shift_vy quirk is set
     fn instr_shr_vx_vy(&mut self, x: u8, y: u8)
     {
-        self.v[CARRY_FLAG] = self.v[y as usize] & 0x80;
-        self.v[x as usize] = self.v[y as usize] >> 1;
+        let src = self.v[if self.quirks.shift_vy { y } else { x } as usize];
+        self.set_with_flag(x, src >> 1, src & 0x01 != 0);
         self.pc += 2;
     }
 
@@ -355,19 +793,19 @@ impl CPU
     /// and 0x1 if a borrow does not occur
     fn instr_subn_vx_vy(&mut self, x: u8, y: u8)
     {
-        let value = self.v[y as usize] as i8 - self.v[x as usize] as i8;
-        self.v[x as usize] = value as u8;
-        self.v[CARRY_FLAG] = if value < 0 { 0x0 } else { 0x1 };   
+        let (value, borrow) = self.v[y as usize].overflowing_sub(self.v[x as usize]);
+        self.set_with_flag(x, value, !borrow);
         self.pc += 2;
     }
 
     /// Instruction executed by opcode 8xyE 
     /// Store the value of Vy shifted left one bit in Vx then 
-    /// set the carry flag to the least significant bit prior to the shift
+    /// set the carry flag to the most significant bit prior to the shift. 
+    /// Shifts Vx in place instead unless the shift_vy quirk is set
     fn instr_shl_vx_vy(&mut self, x: u8, y: u8)
     {
-        self.v[CARRY_FLAG] = self.v[y as usize] & 0x01;
-        self.v[x as usize] = self.v[y as usize] << 1;
+        let src = self.v[if self.quirks.shift_vy { y } else { x } as usize];
+        self.set_with_flag(x, src << 1, src & 0x80 != 0);
         self.pc += 2;
     }
 
@@ -375,7 +813,7 @@ impl CPU
     /// Skip next instruction if Vx != Vy
     fn instr_sne_vx_vy(&mut self, x: u8, y: u8)
     {
-        self.pc += if self.v[x as usize] != self.v[y as usize] { 4 } else { 2 };
+        self.skip_next_if(self.v[x as usize] != self.v[y as usize]);
     }
 
     /// Instruction executed by opcode Annn 
@@ -387,32 +825,54 @@ impl CPU
     }
 
     /// Instruction executed by opcode Bnnn 
-    /// Jump to location nnn + V0
+    /// Jump to location nnn + V0, or to nnn + Vx if the jump_vx quirk is set
     fn instr_jp_v0_addr(&mut self, addr: u16)
     {
-        let v0 = self.v[0] as u16;
-        self.instr_jp_addr(addr + v0);
+        let reg = if self.quirks.jump_vx { (addr >> 8) as usize } else { 0 };
+        let offset = self.v[reg] as u16;
+        self.instr_jp_addr(addr + offset);
     }
     
     /// Instruction executed by opcode Cxnn 
     /// Set Vx = random byte AND nn
     fn instr_rnd_vx_nn(&mut self, x: u8, nn: u8)
     {
-        self.v[x as usize] = random::< u8 >() & nn;
+        self.v[x as usize] = self.next_random_byte() & nn;
         self.pc += 2;
     }
     
+    /// Advances the xorshift64* generator and returns its top byte
+    fn next_random_byte(&mut self) -> u8
+    {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        (self.rng_state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+    
     /// Instruction executed by opcode Dxyn 
     /// Display n-byte sprite starting at memory location I at (Vx, Vy) 
+    /// Dxy0 draws a 16x16 SUPER-CHIP sprite of 32 bytes instead 
+    /// With several XO-CHIP planes selected, the sprite data for each plane follows in turn 
     /// Sets the carry flag to 0x1 if a collision occurs
-    fn instr_drw_vx_vy_nn(&mut self, x: u8, y: u8, nn: u8)
+    fn instr_drw_vx_vy_nn(&mut self, x: u8, y: u8, nn: u8) -> Result< (), Chip8Error >
     {
         let x = self.v[x as usize] as usize;
         let y = self.v[y as usize] as usize;
-        let mem_start = self.i;
-        let mem_end = self.i + nn as usize;
+        let wrap = self.quirks.wrap_sprites;
+        let planes = self.display.selected_plane_count();
+        let collision = if nn == 0
+        {
+            let range = self.access_memory(self.i, 32 * planes, AccessKind::Read)?;
+            self.display.draw_large(x, y, &self.memory[range], wrap)
+        }
+        else
+        {
+            let range = self.access_memory(self.i, nn as usize * planes, AccessKind::Read)?;
+            self.display.draw(x, y, &self.memory[range], wrap)
+        };
         
-        if self.display.draw(x, y, &self.memory[mem_start..mem_end])
+        if collision
         {
             self.v[CARRY_FLAG] = 0x1;
         }
@@ -422,22 +882,51 @@ impl CPU
         }
 
         self.pc += 2;
+        Ok(())
     }
     
     /// Instruction executed by opcode Ex9E 
     /// Skip next instruction if key with the value Vx is pressed
     fn instr_skp_vx(&mut self, x: u8)
     {
-        self.pc += if self.keypad.get_key_state(self.v[x as usize] as usize) { 4 } else { 2 };
+        self.skip_next_if(self.keypad.get_key_state((self.v[x as usize] & 0xF) as usize));
     }
     
     /// Instruction executed by opcode ExA1 
     /// Skip next instruction if key with the value Vx is not pressed
     fn instr_sknp_vx(&mut self, x: u8)
     {
-        self.pc += if self.keypad.get_key_state(self.v[x as usize] as usize) { 2 } else { 4 };
+        self.skip_next_if(!self.keypad.get_key_state((self.v[x as usize] & 0xF) as usize));
     }
     
+    /// Instruction executed by opcode F000 nnnn (XO-CHIP) 
+    /// Set I = nnnn, the 16-bit word following the opcode
+    fn instr_ld_i_long(&mut self) -> Result< (), Chip8Error >
+    {
+        self.i = self.read_word(self.pc + 2)? as usize;
+        self.pc += 4;
+        Ok(())
+    }
+
+    /// Instruction executed by opcode Fn01 (XO-CHIP) 
+    /// Select the bitplanes given by the mask n for drawing, clearing and scrolling
+    fn instr_plane_n(&mut self, n: u8)
+    {
+        self.display.select_planes(n);
+        self.pc += 2;
+    }
+
+    /// Instruction executed by opcode F002 (XO-CHIP) 
+    /// Load the 16-byte audio pattern buffer from memory starting at location I
+    fn instr_audio(&mut self) -> Result< (), Chip8Error >
+    {
+        let range = self.access_memory(self.i, 16, AccessKind::Read)?;
+        self.audio_pattern.copy_from_slice(&self.memory[range]);
+        self.audio_pattern_loaded = true;
+        self.pc += 2;
+        Ok(())
+    }
+
     /// Instruction executed by opcode Fx07 
     /// Set Vx = delay timer value
     fn instr_ld_vx_dt(&mut self, x: u8)
@@ -473,7 +962,7 @@ impl CPU
     /// Set I = I + Vx
     fn instr_add_i_vx(&mut self, x: u8)
     {
-        self.i = self.i + self.v[x as usize] as usize;
+        self.i += self.v[x as usize] as usize;
         self.pc += 2;
     }
     
@@ -481,42 +970,650 @@ impl CPU
     /// Set I = location of sprite for digit Vx
     fn instr_ld_f_vx(&mut self, x: u8)
     {
-        self.i = (self.v[x as usize] * 5) as usize;
+        self.i = (self.v[x as usize] & 0xF) as usize * 5;
         self.pc += 2;
     }
     
+    /// Instruction executed by opcode Fx30 (SUPER-CHIP) 
+    /// Set I = location of the large sprite for digit Vx
+    fn instr_ld_hf_vx(&mut self, x: u8)
+    {
+        self.i = SCHIP_FONT_ADDR + (self.v[x as usize] & 0xF) as usize * 10;
+        self.pc += 2;
+    }
+
     /// Instruction executed by opcode Fx33 
     /// Store BCD representation of Vx in memory locations I, I + 1, and I + 2
-    fn instr_ld_b_vx(&mut self, x: u8)
+    fn instr_ld_b_vx(&mut self, x: u8) -> Result< (), Chip8Error >
     {
         let vx = self.v[x as usize];
-        self.memory[self.i] = vx / 100;
-        self.memory[self.i + 1] = (vx / 10) % 10;
-        self.memory[self.i + 2] = vx % 10;
+        let range = self.access_memory(self.i, 3, AccessKind::Write)?;
+        self.memory[range].copy_from_slice(&[vx / 100, (vx / 10) % 10, vx % 10]);
+        self.pc += 2;
+        Ok(())
+    }
+    
+    /// Instruction executed by opcode Fx3A (XO-CHIP) 
+    /// Set the audio playback pitch = Vx
+    fn instr_pitch_vx(&mut self, x: u8)
+    {
+        self.pitch = self.v[x as usize];
         self.pc += 2;
     }
     
     /// Instruction executed by opcode Fx55 
-    /// Stores registers V0 through Vx in memory starting at location I
-    fn instr_ld_i_vx(&mut self, x: u8)
+    /// Stores registers V0 through Vx in memory starting at location I. 
+    /// I is left at I + x + 1 if the load_store_increment_i quirk is set
+    fn instr_ld_i_vx(&mut self, x: u8) -> Result< (), Chip8Error >
     {
-        for i in 0..(x as usize + 1)
+        let n = x as usize + 1;
+        let range = self.access_memory(self.i, n, AccessKind::Write)?;
+        self.memory[range].copy_from_slice(&self.v[..n]);
+        if self.quirks.load_store_increment_i
         {
-            self.memory[self.i + i] = self.v[i];
+            self.i += n;
         }
-        self.i = self.i + x as usize + 1;
         self.pc += 2;
+        Ok(())
     }
     
     /// Instruction executed by opcode Fx65 
-    /// Reads registers V0 through Vx from memory starting at location I
-    fn instr_ld_vx_i(&mut self, x: u8)
+    /// Reads registers V0 through Vx from memory starting at location I. 
+    /// I is left at I + x + 1 if the load_store_increment_i quirk is set
+    fn instr_ld_vx_i(&mut self, x: u8) -> Result< (), Chip8Error >
     {
-        for i in 0..(x as usize + 1)
+        let n = x as usize + 1;
+        let range = self.access_memory(self.i, n, AccessKind::Read)?;
+        self.v[..n].copy_from_slice(&self.memory[range]);
+        if self.quirks.load_store_increment_i
         {
-            self.v[i] = self.memory[self.i + i];
+            self.i += n;
         }
-        self.i = self.i + x as usize + 1;
         self.pc += 2;
+        Ok(())
+    }
+    
+    /// Instruction executed by opcode Fx75 (SUPER-CHIP) 
+    /// Stores registers V0 through Vx in the RPL user flags
+    fn instr_ld_r_vx(&mut self, x: u8)
+    {
+        let n = x as usize + 1;
+        self.rpl[..n].copy_from_slice(&self.v[..n]);
+        self.pc += 2;
+    }
+    
+    /// Instruction executed by opcode Fx85 (SUPER-CHIP) 
+    /// Reads registers V0 through Vx from the RPL user flags
+    fn instr_ld_vx_r(&mut self, x: u8)
+    {
+        let n = x as usize + 1;
+        self.v[..n].copy_from_slice(&self.rpl[..n]);
+        self.pc += 2;
+    }
+}
+
+impl Default for CPU
+{
+    fn default() -> Self
+    {
+        Self::new(Quirks::default())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::display::{ HIRES_DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT };
+
+    /// One run of an instruction: the registers set before it and the 
+    /// registers and program counter expected after it
+    struct Case
+    {
+        opcode: u16,
+        before: &'static [(usize, u8)],
+        after: &'static [(usize, u8)],
+        pc: usize,
+    }
+
+    /// A CPU with the given program loaded at PROGRAM_START
+    fn cpu_with(quirks: Quirks, program: &[u16]) -> CPU
+    {
+        let mut cpu = CPU::new(quirks);
+        let bytes: Vec< u8 > = program.iter().flat_map(|word| word.to_be_bytes()).collect();
+        cpu.load_program(&bytes).unwrap();
+        cpu
+    }
+
+    /// Executes the first instruction of program
+    fn step(quirks: Quirks, program: &[u16], setup: impl FnOnce(&mut CPU)) -> CPU
+    {
+        let mut cpu = cpu_with(quirks, program);
+        setup(&mut cpu);
+        cpu.cpu_cycle().unwrap();
+        cpu
+    }
+
+    fn run_cases(quirks: Quirks, cases: &[Case])
+    {
+        for case in cases.iter()
+        {
+            let cpu = step(quirks, &[case.opcode], |cpu| {
+                for &(reg, value) in case.before.iter()
+                {
+                    cpu.v[reg] = value;
+                }
+            });
+            for &(reg, value) in case.after.iter()
+            {
+                assert_eq!(cpu.v[reg], value, "{:04X} with {:?}: V{:X}", case.opcode, case.before, reg);
+            }
+            assert_eq!(cpu.pc, case.pc, "{:04X} with {:?}: PC", case.opcode, case.before);
+        }
+    }
+
+    const NEXT: usize = PROGRAM_START + 2;
+    const SKIP: usize = PROGRAM_START + 4;
+
+    #[test]
+    fn cls()
+    {
+        let cpu = step(Quirks::OCTO, &[0x00E0], |cpu| { cpu.display.memory[3][4] = 1; });
+        assert!(cpu.display.memory.iter().all(|row| row.iter().all(|&pixel| pixel == 0)));
+        assert_eq!(cpu.pc, NEXT);
+    }
+
+    #[test]
+    fn call_and_ret()
+    {
+        let mut cpu = cpu_with(Quirks::OCTO, &[0x2206, 0x0000, 0x0000, 0x00EE]);
+        cpu.cpu_cycle().unwrap();
+        assert_eq!((cpu.pc, cpu.sp, cpu.stack[0]), (0x206, 1, PROGRAM_START as u16));
+        cpu.cpu_cycle().unwrap();
+        assert_eq!((cpu.pc, cpu.sp), (NEXT, 0));
+    }
+
+    #[test]
+    fn ret_underflow_and_call_overflow()
+    {
+        let mut cpu = cpu_with(Quirks::OCTO, &[0x00EE]);
+        assert!(matches!(cpu.cpu_cycle(), Err(Chip8Error::StackUnderflow { .. })));
+
+        let mut cpu = cpu_with(Quirks::OCTO, &[0x2200]);
+        for _ in 0..STACK_SIZE
+        {
+            cpu.cpu_cycle().unwrap();
+        }
+        assert!(matches!(cpu.cpu_cycle(), Err(Chip8Error::StackOverflow { .. })));
+    }
+
+    #[test]
+    fn scroll()
+    {
+        let cases: [(u16, (usize, usize)); 4] = [(0x00C2, (10, 12)), (0x00D3, (10, 7)), (0x00FB, (14, 10)), (0x00FC, (6, 10))];
+        for &(opcode, (x, y)) in cases.iter()
+        {
+            let cpu = step(Quirks::OCTO, &[opcode], |cpu| { cpu.display.memory[10][10] = 1; });
+            assert_eq!(cpu.display.memory[y][x], 1, "{:04X}", opcode);
+            assert_eq!(cpu.display.memory[10][10], 0, "{:04X}", opcode);
+            assert_eq!(cpu.pc, NEXT);
+        }
+    }
+
+    #[test]
+    fn exit_low_high()
+    {
+        let cpu = step(Quirks::OCTO, &[0x00FD], |_| {});
+        assert!(cpu.exited);
+        assert_eq!(cpu.pc, PROGRAM_START);
+
+        let cpu = step(Quirks::OCTO, &[0x00FF], |_| {});
+        assert!(cpu.display.is_hires());
+        assert_eq!(cpu.pc, NEXT);
+
+        let cpu = step(Quirks::OCTO, &[0x00FE], |cpu| cpu.display.set_hires(true));
+        assert!(!cpu.display.is_hires());
+        assert_eq!(cpu.pc, NEXT);
+    }
+
+    #[test]
+    fn jp()
+    {
+        run_cases(Quirks::OCTO, &[Case { opcode: 0x1ABC, before: &[], after: &[], pc: 0xABC }]);
+    }
+
+    #[test]
+    fn jp_v0()
+    {
+        run_cases(Quirks::OCTO, &[
+            Case { opcode: 0xB300, before: &[(0, 0x10), (3, 0x20)], after: &[], pc: 0x310 },
+            Case { opcode: 0xBFFF, before: &[(0, 0xFF)], after: &[], pc: 0x10FE },
+        ]);
+        run_cases(Quirks { jump_vx: true, ..Quirks::OCTO }, &[
+            Case { opcode: 0xB300, before: &[(0, 0x10), (3, 0x20)], after: &[], pc: 0x320 },
+        ]);
+    }
+
+    #[test]
+    fn skips()
+    {
+        run_cases(Quirks::OCTO, &[
+            Case { opcode: 0x3142, before: &[(1, 0x42)], after: &[], pc: SKIP },
+            Case { opcode: 0x3142, before: &[(1, 0x41)], after: &[], pc: NEXT },
+            Case { opcode: 0x4142, before: &[(1, 0x42)], after: &[], pc: NEXT },
+            Case { opcode: 0x4142, before: &[(1, 0x41)], after: &[], pc: SKIP },
+            Case { opcode: 0x5120, before: &[(1, 7), (2, 7)], after: &[], pc: SKIP },
+            Case { opcode: 0x5120, before: &[(1, 7), (2, 8)], after: &[], pc: NEXT },
+            Case { opcode: 0x9120, before: &[(1, 7), (2, 7)], after: &[], pc: NEXT },
+            Case { opcode: 0x9120, before: &[(1, 7), (2, 8)], after: &[], pc: SKIP },
+        ]);
+    }
+
+    #[test]
+    fn skip_over_long_load()
+    {
+        let cpu = step(Quirks::OCTO, &[0x3000, 0xF000, 0x1234], |_| {});
+        assert_eq!(cpu.pc, PROGRAM_START + 6);
+    }
+
+    #[test]
+    fn skip_keys()
+    {
+        for &(opcode, down, pc) in [(0xE19E, true, SKIP), (0xE19E, false, NEXT), (0xE1A1, true, NEXT), (0xE1A1, false, SKIP)].iter()
+        {
+            let cpu = step(Quirks::OCTO, &[opcode], |cpu| {
+                cpu.v[1] = 0xA;
+                cpu.keypad.set_key_state(0xA, down);
+            });
+            assert_eq!(cpu.pc, pc, "{:04X} with key down {}", opcode, down);
+        }
+
+        // Only the low nibble of Vx picks the key
+        for &(opcode, pc) in [(0xE19E, SKIP), (0xE1A1, NEXT)].iter()
+        {
+            let cpu = step(Quirks::OCTO, &[opcode], |cpu| {
+                cpu.v[1] = 0x10;
+                cpu.keypad.set_key_state(0x0, true);
+            });
+            assert_eq!(cpu.pc, pc, "{:04X} with V1 = 0x10", opcode);
+        }
+    }
+
+    #[test]
+    fn ld_and_add_immediate()
+    {
+        run_cases(Quirks::OCTO, &[
+            Case { opcode: 0x6A5C, before: &[], after: &[(0xA, 0x5C)], pc: NEXT },
+            Case { opcode: 0x7A05, before: &[(0xA, 0x10), (0xF, 0x7)], after: &[(0xA, 0x15), (0xF, 0x7)], pc: NEXT },
+            // 7xnn wraps without touching the carry flag
+            Case { opcode: 0x7AFF, before: &[(0xA, 0x02), (0xF, 0x0)], after: &[(0xA, 0x01), (0xF, 0x0)], pc: NEXT },
+        ]);
+    }
+
+    #[test]
+    fn ld_vx_vy()
+    {
+        run_cases(Quirks::OCTO, &[Case { opcode: 0x8120, before: &[(2, 0x33)], after: &[(1, 0x33), (2, 0x33)], pc: NEXT }]);
+    }
+
+    #[test]
+    fn logic()
+    {
+        run_cases(Quirks::OCTO, &[
+            Case { opcode: 0x8121, before: &[(1, 0b1100), (2, 0b1010), (0xF, 5)], after: &[(1, 0b1110), (0xF, 5)], pc: NEXT },
+            Case { opcode: 0x8122, before: &[(1, 0b1100), (2, 0b1010), (0xF, 5)], after: &[(1, 0b1000), (0xF, 5)], pc: NEXT },
+            Case { opcode: 0x8123, before: &[(1, 0b1100), (2, 0b1010), (0xF, 5)], after: &[(1, 0b0110), (0xF, 5)], pc: NEXT },
+        ]);
+        run_cases(Quirks::VIP, &[
+            Case { opcode: 0x8121, before: &[(1, 0b1100), (2, 0b1010), (0xF, 5)], after: &[(1, 0b1110), (0xF, 0)], pc: NEXT },
+            Case { opcode: 0x8122, before: &[(1, 0b1100), (2, 0b1010), (0xF, 5)], after: &[(1, 0b1000), (0xF, 0)], pc: NEXT },
+            Case { opcode: 0x8123, before: &[(1, 0b1100), (2, 0b1010), (0xF, 5)], after: &[(1, 0b0110), (0xF, 0)], pc: NEXT },
+        ]);
+    }
+
+    #[test]
+    fn add_vx_vy()
+    {
+        run_cases(Quirks::OCTO, &[
+            Case { opcode: 0x8124, before: &[(1, 0x10), (2, 0x20), (0xF, 1)], after: &[(1, 0x30), (0xF, 0)], pc: NEXT },
+            Case { opcode: 0x8124, before: &[(1, 0xF0), (2, 0x20)], after: &[(1, 0x10), (0xF, 1)], pc: NEXT },
+            Case { opcode: 0x8124, before: &[(1, 0xFF), (2, 0x01)], after: &[(1, 0x00), (0xF, 1)], pc: NEXT },
+            // The flag wins when VF is the destination
+            Case { opcode: 0x8F14, before: &[(1, 0x01), (0xF, 0x02)], after: &[(0xF, 0)], pc: NEXT },
+            Case { opcode: 0x8F14, before: &[(1, 0xFF), (0xF, 0x02)], after: &[(0xF, 1)], pc: NEXT },
+        ]);
+    }
+
+    #[test]
+    fn sub_vx_vy()
+    {
+        run_cases(Quirks::OCTO, &[
+            Case { opcode: 0x8125, before: &[(1, 0x30), (2, 0x10)], after: &[(1, 0x20), (0xF, 1)], pc: NEXT },
+            Case { opcode: 0x8125, before: &[(1, 0x10), (2, 0x10)], after: &[(1, 0x00), (0xF, 1)], pc: NEXT },
+            Case { opcode: 0x8125, before: &[(1, 0x10), (2, 0x30), (0xF, 1)], after: &[(1, 0xE0), (0xF, 0)], pc: NEXT },
+            // Values above 0x7F used to overflow an i8 cast
+            Case { opcode: 0x8125, before: &[(1, 0xC8), (2, 0x10)], after: &[(1, 0xB8), (0xF, 1)], pc: NEXT },
+            Case { opcode: 0x8125, before: &[(1, 0x10), (2, 0xC8)], after: &[(1, 0x48), (0xF, 0)], pc: NEXT },
+            Case { opcode: 0x8F15, before: &[(1, 0x01), (0xF, 0x05)], after: &[(0xF, 1)], pc: NEXT },
+        ]);
+    }
+
+    #[test]
+    fn subn_vx_vy()
+    {
+        run_cases(Quirks::OCTO, &[
+            Case { opcode: 0x8127, before: &[(1, 0x10), (2, 0x30)], after: &[(1, 0x20), (0xF, 1)], pc: NEXT },
+            Case { opcode: 0x8127, before: &[(1, 0x30), (2, 0x10), (0xF, 1)], after: &[(1, 0xE0), (0xF, 0)], pc: NEXT },
+            Case { opcode: 0x8127, before: &[(1, 0x10), (2, 0xC8)], after: &[(1, 0xB8), (0xF, 1)], pc: NEXT },
+            Case { opcode: 0x8127, before: &[(1, 0xC8), (2, 0x10)], after: &[(1, 0x48), (0xF, 0)], pc: NEXT },
+            Case { opcode: 0x8F17, before: &[(1, 0x01), (0xF, 0x05)], after: &[(0xF, 0)], pc: NEXT },
+        ]);
+    }
+
+    #[test]
+    fn shifts()
+    {
+        run_cases(Quirks { shift_vy: false, ..Quirks::OCTO }, &[
+            Case { opcode: 0x8126, before: &[(1, 0b0000_0101), (2, 0xFF)], after: &[(1, 0b0000_0010), (0xF, 1)], pc: NEXT },
+            Case { opcode: 0x8126, before: &[(1, 0b1000_0100), (0xF, 1)], after: &[(1, 0b0100_0010), (0xF, 0)], pc: NEXT },
+            Case { opcode: 0x812E, before: &[(1, 0b1000_0001), (2, 0x00)], after: &[(1, 0b0000_0010), (0xF, 1)], pc: NEXT },
+            Case { opcode: 0x812E, before: &[(1, 0b0100_0001), (0xF, 1)], after: &[(1, 0b1000_0010), (0xF, 0)], pc: NEXT },
+            Case { opcode: 0x8F06, before: &[(0xF, 0b0000_0010)], after: &[(0xF, 0)], pc: NEXT },
+            Case { opcode: 0x8F0E, before: &[(0xF, 0b1000_0000)], after: &[(0xF, 1)], pc: NEXT },
+        ]);
+        run_cases(Quirks { shift_vy: true, ..Quirks::OCTO }, &[
+            Case { opcode: 0x8126, before: &[(1, 0x00), (2, 0b0000_0011)], after: &[(1, 0b0000_0001), (2, 0b0000_0011), (0xF, 1)], pc: NEXT },
+            Case { opcode: 0x812E, before: &[(1, 0x00), (2, 0b1100_0000)], after: &[(1, 0b1000_0000), (2, 0b1100_0000), (0xF, 1)], pc: NEXT },
+        ]);
+    }
+
+    #[test]
+    fn ld_i()
+    {
+        let cpu = step(Quirks::OCTO, &[0xA123], |_| {});
+        assert_eq!((cpu.i, cpu.pc), (0x123, NEXT));
+
+        let cpu = step(Quirks::OCTO, &[0xF000, 0xBEEF], |_| {});
+        assert_eq!((cpu.i, cpu.pc), (0xBEEF, PROGRAM_START + 4));
+    }
+
+    #[test]
+    fn rnd()
+    {
+        let mut expected = CPU::new(Quirks::OCTO);
+        expected.set_rng_seed(7);
+        let byte = expected.next_random_byte();
+
+        let cpu = step(Quirks::OCTO, &[0xC10F], |cpu| cpu.set_rng_seed(7));
+        assert_eq!(cpu.v[1], byte & 0x0F);
+        assert_eq!(cpu.pc, NEXT);
+    }
+
+    #[test]
+    fn drw()
+    {
+        // Draw the font's 0 at (2, 1), then draw it again to erase it
+        let mut cpu = cpu_with(Quirks::OCTO, &[0xD125, 0xD125]);
+        cpu.v[1] = 2;
+        cpu.v[2] = 1;
+        cpu.v[0xF] = 1;
+        cpu.cpu_cycle().unwrap();
+        assert_eq!(cpu.v[0xF], 0);
+        assert_eq!(&cpu.display.memory[1][2..6], &[1, 1, 1, 1]);
+        assert_eq!(&cpu.display.memory[2][2..6], &[1, 0, 0, 1]);
+        assert_eq!(cpu.pc, NEXT);
+
+        cpu.cpu_cycle().unwrap();
+        assert_eq!(cpu.v[0xF], 1);
+        assert!(cpu.display.memory.iter().all(|row| row.iter().all(|&pixel| pixel == 0)));
+    }
+
+    #[test]
+    fn drw_large()
+    {
+        let cpu = step(Quirks::OCTO, &[0xD010], |cpu| {
+            cpu.display.set_hires(true);
+            cpu.i = 0x300;
+            cpu.memory[0x300..0x320].copy_from_slice(&[0xFF; 32]);
+        });
+        assert!(cpu.display.memory[..16].iter().all(|row| row[..16].iter().all(|&pixel| pixel == 1)));
+        assert_eq!(cpu.display.memory[0][16], 0);
+        assert_eq!(cpu.pc, NEXT);
+    }
+
+    #[test]
+    fn plane_and_audio()
+    {
+        let cpu = step(Quirks::OCTO, &[0xF201], |_| {});
+        assert_eq!((cpu.display.planes(), cpu.pc), (2, NEXT));
+
+        let cpu = step(Quirks::OCTO, &[0xF002], |cpu| {
+            cpu.i = 0x300;
+            for n in 0..16
+            {
+                cpu.memory[0x300 + n] = n as u8 * 3;
+            }
+        });
+        assert_eq!(cpu.audio_pattern, [0, 3, 6, 9, 12, 15, 18, 21, 24, 27, 30, 33, 36, 39, 42, 45]);
+        assert!(cpu.audio_pattern_loaded);
+        assert_eq!(cpu.pc, NEXT);
+
+        let cpu = step(Quirks::OCTO, &[0xF33A], |cpu| cpu.v[3] = 100);
+        assert_eq!((cpu.pitch, cpu.pc), (100, NEXT));
+    }
+
+    #[test]
+    fn timers()
+    {
+        let cpu = step(Quirks::OCTO, &[0xF107], |cpu| cpu.delay_timer = 42);
+        assert_eq!((cpu.v[1], cpu.pc), (42, NEXT));
+
+        let cpu = step(Quirks::OCTO, &[0xF115], |cpu| cpu.v[1] = 9);
+        assert_eq!((cpu.delay_timer, cpu.pc), (9, NEXT));
+
+        let cpu = step(Quirks::OCTO, &[0xF118], |cpu| cpu.v[1] = 8);
+        assert_eq!((cpu.sound_timer, cpu.pc), (8, NEXT));
+    }
+
+    #[test]
+    fn ld_vx_k()
+    {
+        let mut cpu = cpu_with(Quirks::OCTO, &[0xF30A, 0x6001]);
+        cpu.cpu_cycle().unwrap();
+        cpu.cpu_cycle().unwrap();
+        assert!(cpu.is_waiting_for_key());
+        assert_eq!(cpu.pc, PROGRAM_START);
+
+        // A key that is not down does not end the wait
+        cpu.stop_waiting_for_key(0x7);
+        assert!(cpu.is_waiting_for_key());
+
+        cpu.keypad.set_key_state(0x7, true);
+        cpu.stop_waiting_for_key(0x7);
+        assert!(!cpu.is_waiting_for_key());
+        assert_eq!((cpu.v[3], cpu.pc), (0x7, NEXT));
+
+        cpu.cpu_cycle().unwrap();
+        assert_eq!(cpu.v[0], 1);
+    }
+
+    #[test]
+    fn index_arithmetic()
+    {
+        let cpu = step(Quirks::OCTO, &[0xF11E], |cpu| {
+            cpu.i = 0x100;
+            cpu.v[1] = 0x20;
+        });
+        assert_eq!((cpu.i, cpu.pc), (0x120, NEXT));
+
+        let cpu = step(Quirks::OCTO, &[0xF129], |cpu| cpu.v[1] = 0x1A);
+        assert_eq!((cpu.i, cpu.pc), (0xA * 5, NEXT));
+
+        let cpu = step(Quirks::OCTO, &[0xF130], |cpu| cpu.v[1] = 0x3);
+        assert_eq!((cpu.i, cpu.pc), (SCHIP_FONT_ADDR + 30, NEXT));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn ld_b_vx()
+    {
+        for &(value, digits) in [(0u8, [0u8, 0, 0]), (7, [0, 0, 7]), (42, [0, 4, 2]), (255, [2, 5, 5])].iter()
+        {
+            let cpu = step(Quirks::OCTO, &[0xF133], |cpu| {
+                cpu.i = 0x300;
+                cpu.v[1] = value;
+            });
+            assert_eq!(&cpu.memory[0x300..0x303], &digits, "BCD of {}", value);
+            assert_eq!((cpu.i, cpu.pc), (0x300, NEXT));
+        }
+    }
+
+    #[test]
+    fn store_and_load_registers()
+    {
+        for &(quirks, i) in [(Quirks::OCTO, 0x303), (Quirks::SCHIP, 0x300)].iter()
+        {
+            let cpu = step(quirks, &[0xF255], |cpu| {
+                cpu.i = 0x300;
+                cpu.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+            });
+            assert_eq!(&cpu.memory[0x300..0x304], &[1, 2, 3, 0]);
+            assert_eq!((cpu.i, cpu.pc), (i, NEXT));
+
+            let cpu = step(quirks, &[0xF265], |cpu| {
+                cpu.i = 0x300;
+                cpu.memory[0x300..0x304].copy_from_slice(&[5, 6, 7, 8]);
+            });
+            assert_eq!(&cpu.v[..4], &[5, 6, 7, 0]);
+            assert_eq!((cpu.i, cpu.pc), (i, NEXT));
+        }
+    }
+
+    #[test]
+    fn store_and_load_register_ranges()
+    {
+        let cpu = step(Quirks::OCTO, &[0x5242], |cpu| {
+            cpu.i = 0x300;
+            cpu.v[2..5].copy_from_slice(&[1, 2, 3]);
+        });
+        assert_eq!(&cpu.memory[0x300..0x303], &[1, 2, 3]);
+        assert_eq!((cpu.i, cpu.pc), (0x300, NEXT));
+
+        let cpu = step(Quirks::OCTO, &[0x5423], |cpu| {
+            cpu.i = 0x300;
+            cpu.memory[0x300..0x303].copy_from_slice(&[1, 2, 3]);
+        });
+        assert_eq!(&cpu.v[2..5], &[3, 2, 1]);
+        assert_eq!((cpu.i, cpu.pc), (0x300, NEXT));
+    }
+
+    #[test]
+    fn rpl_flags()
+    {
+        let cpu = step(Quirks::OCTO, &[0xF275], |cpu| cpu.v[..4].copy_from_slice(&[1, 2, 3, 4]));
+        assert_eq!(&cpu.rpl[..4], &[1, 2, 3, 0]);
+        assert_eq!(cpu.pc, NEXT);
+
+        let cpu = step(Quirks::OCTO, &[0xF285], |cpu| cpu.rpl[..4].copy_from_slice(&[5, 6, 7, 8]));
+        assert_eq!(&cpu.v[..4], &[5, 6, 7, 0]);
+        assert_eq!(cpu.pc, NEXT);
+    }
+
+    #[test]
+    fn memory_out_of_bounds()
+    {
+        let mut cpu = cpu_with(Quirks::OCTO, &[0xF255]);
+        cpu.i = MEMORY_SIZE - 2;
+        assert!(matches!(cpu.cpu_cycle(), Err(Chip8Error::MemoryOutOfBounds { .. })));
+        assert_eq!(cpu.pc, PROGRAM_START);
+    }
+
+    #[test]
+    fn unknown_opcode()
+    {
+        let mut cpu = cpu_with(Quirks::OCTO, &[0x5121]);
+        assert!(matches!(cpu.cpu_cycle(), Err(Chip8Error::UnknownOpcode { opcode: 0x5121, .. })));
+    }
+
+    /// A CPU part way through a program that touches most of the saved state
+    fn busy_cpu() -> CPU
+    {
+        // hires, plane 3, i := long 0x300, sprite v0 v1 5, call 0x220, v2 := random 0xFF
+        let mut cpu = cpu_with(Quirks::SCHIP, &[0x00FF, 0xF301, 0xF000, 0x0300, 0xD015, 0x2220, 0xC2FF]);
+        cpu.memory[0x300..0x30A].copy_from_slice(&[0xF0, 0x90, 0xF0, 0x90, 0xF0, 0x0F, 0x09, 0x0F, 0x09, 0x0F]);
+        cpu.memory[0x220..0x222].copy_from_slice(&[0xF2, 0x0A]);
+        cpu.v[..2].copy_from_slice(&[3, 4]);
+        cpu.delay_timer = 30;
+        cpu.sound_timer = 7;
+        cpu.rpl[2] = 9;
+        cpu.audio_pattern[5] = 0xAA;
+        cpu.keypad.set_key_state(0xB, true);
+        for _ in 0..6
+        {
+            cpu.cpu_cycle().unwrap();
+        }
+        assert!(cpu.is_waiting_for_key());
+        cpu
+    }
+
+    #[test]
+    fn save_state_round_trip()
+    {
+        let cpu = busy_cpu();
+        let state = cpu.save_state();
+
+        let mut restored = cpu_with(Quirks::OCTO, &[0x00FF, 0xF301, 0xF000, 0x0300, 0xD015, 0x2220, 0xC2FF]);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!((restored.pc, restored.i, restored.sp, restored.v), (cpu.pc, cpu.i, cpu.sp, cpu.v));
+        assert_eq!(restored.quirks, Quirks::SCHIP);
+        assert!(restored.is_waiting_for_key());
+        assert!(restored.keypad.get_key_state(0xB));
+        assert_eq!((restored.display.is_hires(), restored.display.planes()), (true, 3));
+        assert!(restored.display.memory[..] == cpu.display.memory[..]);
+
+        // The random number generator carries on from the same point
+        let (mut a, mut b) = (cpu.clone(), restored);
+        a.stop_waiting_for_key(0);
+        b.stop_waiting_for_key(0);
+        a.cpu_cycle().unwrap();
+        b.cpu_cycle().unwrap();
+        assert_eq!(a.save_state(), b.save_state());
+    }
+
+    #[test]
+    fn load_state_rejects_bad_snapshots()
+    {
+        let state = busy_cpu().save_state();
+        let mut cpu = busy_cpu();
+        cpu.v[0] = 0x42;
+
+        let mut bad_magic = state.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(cpu.load_state(&bad_magic), Err(Chip8Error::InvalidSaveState(_))));
+
+        let mut bad_version = state.clone();
+        bad_version[4..6].copy_from_slice(&(SAVE_STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(cpu.load_state(&bad_version), Err(Chip8Error::InvalidSaveState(_))));
+
+        assert!(matches!(cpu.load_state(&state[..state.len() - 1]), Err(Chip8Error::InvalidSaveState(_))));
+
+        let mut other_rom = cpu_with(Quirks::OCTO, &[0x1200]);
+        assert!(matches!(other_rom.load_state(&state), Err(Chip8Error::SaveStateRomMismatch)));
+
+        // Failed loads leave the CPU as it was
+        assert_eq!(cpu.v[0], 0x42);
+    }
+
+    #[test]
+    fn load_state_masks_planes()
+    {
+        let mut state = busy_cpu().save_state();
+        let display_size = (HIRES_DISPLAY_WIDTH * HIRES_DISPLAY_HEIGHT) as usize;
+        let planes = state.len() - MEMORY_SIZE - display_size - 1;
+        state[planes] = 0xFF;
+
+        let mut cpu = busy_cpu();
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.display.planes(), 3);
+    }
+}
@@ -0,0 +1,740 @@
+//! An interactive debugger: breakpoints, stepping into, over and out of
+//! subroutines, and watches on memory, registers and expressions. It is
+//! driven by text commands so a frontend can feed it lines from stdin
+
+mod expr;
+
+pub use self::expr::{ BinaryOp, Expr, Register };
+
+use crate::cpu::{ AccessKind, CPU };
+use crate::error::Chip8Error;
+use crate::instruction::Instruction;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+pub const DEBUGGER_HELP: &str = "\
+Commands:
+    s, step [N]         Execute N instructions [default: 1]
+    n, next             Step over a subroutine call
+    f, finish           Run until the current subroutine returns
+    c, continue         Resume execution
+    p, pause            Pause execution
+    b, break <ADDR> [if <EXPR>]
+                        Set a breakpoint at ADDR, optionally only when EXPR is true
+    d, delete [ADDR]    Remove the breakpoint at ADDR, or all breakpoints
+    w, watch <ADDR>[-<END>] [r|w|rw]
+                        Break when an instruction reads and/or writes memory
+                        from ADDR to END inclusive [default: rw]
+    wr, watchreg <REG>  Break when a register (V0-VF, I, PC, SP, DT, ST) changes
+    when <EXPR>         Break when EXPR becomes true, e.g. v3 == 0x10 && i > 0x300.
+                        [ADDR] reads a byte of memory
+    uw, unwatch [N]     Remove watch N, or all watches
+    bl, breakpoints     List breakpoints and watches
+    r, regs             Show registers, stack and timers
+    m, mem <ADDR> [N]   Show N bytes of memory from ADDR [default: 16]
+    h, help             Show this help
+An empty line repeats the last command. Addresses are hexadecimal.";
+
+/// What the debugger is doing between commands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode
+{
+    /// No instructions are executed
+    Paused,
+
+    /// Execution runs until a breakpoint is hit
+    Running,
+
+    /// Execution runs until the call at a subroutine has returned to `pc`
+    /// with the stack back at depth `sp`
+    StepOver { pc: usize, sp: usize },
+
+    /// Execution runs until the stack is shallower than `sp`
+    Finish { sp: usize },
+}
+
+/// Something other than the program counter that stops execution
+enum Watch
+{
+    /// Instructions reading or writing memory from start to end inclusive
+    Memory { start: usize, end: usize, read: bool, write: bool },
+
+    /// Changes to a register, which last held `last`
+    Register { reg: Register, last: i64 },
+
+    /// An expression becoming true. Execution stops when it changes from 
+    /// false to true, so resuming does not stop again straight away
+    Condition { expr: Expr, text: String, was_true: bool },
+}
+
+impl Watch
+{
+    /// Checks the watch after an instruction, returning why it fired if it did
+    fn check(&mut self, cpu: &CPU) -> Option< String >
+    {
+        match self
+        {
+            Watch::Memory { start, end, read, write } =>
+            {
+                cpu.memory_accesses().iter().find(|access| {
+                    let wanted = match access.kind { AccessKind::Read => *read, AccessKind::Write => *write };
+                    wanted && access.addr <= *end && access.addr + access.len > *start
+                }).map(|access| {
+                    let verb = if access.kind == AccessKind::Read { "read" } else { "wrote" };
+                    format!("Watchpoint: instruction {} {} bytes at {:04X}", verb, access.len, access.addr)
+                })
+            },
+            Watch::Register { reg, last } =>
+            {
+                let value = reg.read(cpu);
+                let old = *last;
+                *last = value;
+                if value != old { Some(format!("Watchpoint: {} changed from {:X} to {:X}", reg, old, value)) } else { None }
+            },
+            Watch::Condition { expr, text, was_true } =>
+            {
+                let is_true = expr.eval(cpu) != 0;
+                let fired = is_true && !*was_true;
+                *was_true = is_true;
+                if fired { Some(format!("Condition is true: {}", text)) } else { None }
+            }
+        }
+    }
+
+    fn describe(&self) -> String
+    {
+        match self
+        {
+            Watch::Memory { start, end, read, write } =>
+            {
+                let kind = match (read, write) { (true, true) => "rw", (true, false) => "r", _ => "w" };
+                format!("watch {:04X}-{:04X} {}", start, end, kind)
+            },
+            Watch::Register { reg, .. } => format!("watchreg {}", reg),
+            Watch::Condition { text, .. } => format!("when {}", text)
+        }
+    }
+}
+
+/// An interactive debugger driving a CPU.
+///
+/// The frontend calls `cycle` wherever it would call `CPU::cpu_cycle`, and
+/// passes lines typed by the user to `execute`. While paused `cycle` does
+/// nothing, so the frontend should also stop the timers.
+pub struct Debugger
+{
+    mode: Mode,
+
+    /// Breakpoints by address, each with an optional condition
+    breakpoints: BTreeMap< usize, Option< Expr > >,
+
+    watches: Vec< Watch >,
+    last_command: String,
+
+    /// Set when resuming from a breakpoint so that the instruction under it
+    /// runs instead of immediately breaking again
+    skip_breakpoint: bool,
+}
+
+impl Debugger
+{
+    /// Creates a debugger. It starts paused if `paused` is set
+    pub fn new(paused: bool) -> Self
+    {
+        Debugger {
+            mode: if paused { Mode::Paused } else { Mode::Running },
+            breakpoints: BTreeMap::new(),
+            watches: Vec::new(),
+            last_command: String::new(),
+            skip_breakpoint: false,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool
+    {
+        self.mode == Mode::Paused
+    }
+
+    /// Stops execution before the next instruction
+    pub fn pause(&mut self)
+    {
+        self.mode = Mode::Paused;
+    }
+
+    /// Breaks before the instruction at addr is executed, if condition is 
+    /// None or evaluates to true
+    pub fn add_breakpoint(&mut self, addr: usize, condition: Option< Expr >)
+    {
+        self.breakpoints.insert(addr, condition);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: usize) -> bool
+    {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator< Item = usize > + '_
+    {
+        self.breakpoints.keys().cloned()
+    }
+
+    /// Breaks after an instruction reads (if read is set) or writes (if write 
+    /// is set) any memory from start to end inclusive
+    pub fn add_memory_watch(&mut self, start: usize, end: usize, read: bool, write: bool)
+    {
+        self.watches.push(Watch::Memory { start, end, read, write });
+    }
+
+    /// Breaks after an instruction changes the register
+    pub fn add_register_watch(&mut self, cpu: &CPU, reg: Register)
+    {
+        self.watches.push(Watch::Register { reg, last: reg.read(cpu) });
+    }
+
+    /// Breaks after an instruction makes the expression true
+    pub fn add_condition(&mut self, cpu: &CPU, text: &str) -> Result< (), String >
+    {
+        let expr = Expr::parse(text)?;
+        let was_true = expr.eval(cpu) != 0;
+        self.watches.push(Watch::Condition { expr, text: text.to_string(), was_true });
+        Ok(())
+    }
+
+    /// Executes one instruction unless paused. Returns a message if execution
+    /// stopped, e.g. because a breakpoint was hit or a step over finished
+    pub fn cycle(&mut self, cpu: &mut CPU) -> Result< Option< String >, Chip8Error >
+    {
+        if self.mode == Mode::Paused
+        {
+            return Ok(None);
+        }
+
+        if !self.skip_breakpoint
+        {
+            let hit = match self.breakpoints.get(&cpu.pc())
+            {
+                Some(Some(condition)) => condition.eval(cpu) != 0,
+                Some(None) => true,
+                None => false
+            };
+
+            if hit
+            {
+                self.mode = Mode::Paused;
+                return Ok(Some(format!("Breakpoint at {:04X}\n{}", cpu.pc(), registers(cpu))));
+            }
+        }
+        self.skip_breakpoint = false;
+
+        // Memory accesses are only recorded while something is watching them
+        let watching_memory = self.watches.iter().any(|watch| matches!(watch, Watch::Memory { .. }));
+        cpu.set_memory_tracing(watching_memory);
+
+        if let Err(e) = cpu.cpu_cycle()
+        {
+            self.mode = Mode::Paused;
+            return Err(e);
+        }
+
+        // Check every watch so register and condition watches stay up to date
+        let mut fired = Vec::new();
+        for watch in self.watches.iter_mut()
+        {
+            if let Some(message) = watch.check(cpu)
+            {
+                fired.push(message);
+            }
+        }
+
+        if !fired.is_empty()
+        {
+            self.mode = Mode::Paused;
+            return Ok(Some(format!("{}\n{}", fired.join("\n"), registers(cpu))));
+        }
+
+        let done = match self.mode
+        {
+            Mode::StepOver { pc, sp } => cpu.pc() == pc && cpu.sp == sp,
+            Mode::Finish { sp } => cpu.sp < sp,
+            _ => false
+        };
+
+        if done
+        {
+            self.mode = Mode::Paused;
+            return Ok(Some(registers(cpu)));
+        }
+
+        Ok(None)
+    }
+
+    /// Runs a command line and returns the text to show the user
+    pub fn execute(&mut self, cpu: &mut CPU, line: &str) -> String
+    {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+
+        let mut words = line.split_whitespace();
+        let command = match words.next()
+        {
+            Some(command) => command,
+            None => return String::new()
+        };
+        let arg = words.next();
+
+        match command
+        {
+            "s" | "step" =>
+            {
+                let count = match arg.map(|n| n.parse::< u32 >())
+                {
+                    None => 1,
+                    Some(Ok(n)) => n,
+                    Some(Err(_)) => return "step expects a number of instructions".to_string()
+                };
+                self.step(cpu, count)
+            },
+            "n" | "next" =>
+            {
+                match cpu.peek_opcode().and_then(Instruction::decode)
+                {
+                    Some(Instruction::Call(_)) =>
+                    {
+                        self.resume(Mode::StepOver { pc: cpu.pc() + 2, sp: cpu.sp });
+                        String::new()
+                    },
+                    _ => self.step(cpu, 1)
+                }
+            },
+            "f" | "finish" =>
+            {
+                if cpu.sp == 0
+                {
+                    return "Not inside a subroutine".to_string();
+                }
+                self.resume(Mode::Finish { sp: cpu.sp });
+                String::new()
+            },
+            "c" | "continue" =>
+            {
+                self.resume(Mode::Running);
+                String::new()
+            },
+            "p" | "pause" =>
+            {
+                self.pause();
+                registers(cpu)
+            },
+            "b" | "break" =>
+            {
+                let addr = match arg.and_then(parse_addr)
+                {
+                    Some(addr) => addr,
+                    None => return "break expects a hexadecimal address".to_string()
+                };
+
+                let condition = match words.next()
+                {
+                    None => None,
+                    Some("if") =>
+                    {
+                        let text = words.collect::< Vec< _ > >().join(" ");
+                        match Expr::parse(&text)
+                        {
+                            Ok(expr) => Some(expr),
+                            Err(e) => return format!("Invalid condition: {}", e)
+                        }
+                    },
+                    Some(_) => return "Expected \"if <EXPR>\" after the address".to_string()
+                };
+
+                let message = match condition
+                {
+                    Some(_) => format!("Conditional breakpoint set at {:04X}", addr),
+                    None => format!("Breakpoint set at {:04X}", addr)
+                };
+                self.add_breakpoint(addr, condition);
+                message
+            },
+            "w" | "watch" =>
+            {
+                let range = arg.and_then(|range| {
+                    let mut bounds = range.splitn(2, '-');
+                    let start = parse_addr(bounds.next()?)?;
+                    let end = match bounds.next() { Some(end) => parse_addr(end)?, None => start };
+                    if end >= start { Some((start, end)) } else { None }
+                });
+                let (start, end) = match range
+                {
+                    Some(range) => range,
+                    None => return "watch expects an address or range of addresses, e.g. 300-30F".to_string()
+                };
+
+                let (read, write) = match words.next()
+                {
+                    None | Some("rw") => (true, true),
+                    Some("r") => (true, false),
+                    Some("w") => (false, true),
+                    Some(other) => return format!("Unknown access \"{}\", expected r, w or rw", other)
+                };
+
+                self.add_memory_watch(start, end, read, write);
+                format!("Watching {:04X}-{:04X}", start, end)
+            },
+            "wr" | "watchreg" =>
+            {
+                match arg.and_then(Register::parse)
+                {
+                    Some(reg) =>
+                    {
+                        self.add_register_watch(cpu, reg);
+                        format!("Watching {}", reg)
+                    },
+                    None => "watchreg expects a register: V0-VF, I, PC, SP, DT or ST".to_string()
+                }
+            },
+            "when" =>
+            {
+                let text = line["when".len()..].trim();
+                match self.add_condition(cpu, text)
+                {
+                    Ok(()) => format!("Breaking when {}", text),
+                    Err(e) => format!("Invalid condition: {}", e)
+                }
+            },
+            "uw" | "unwatch" =>
+            {
+                match arg.map(|n| n.parse::< usize >())
+                {
+                    None =>
+                    {
+                        self.watches.clear();
+                        "Removed all watches".to_string()
+                    },
+                    Some(Ok(n)) if n >= 1 && n <= self.watches.len() =>
+                    {
+                        let watch = self.watches.remove(n - 1);
+                        format!("Removed {}", watch.describe())
+                    },
+                    Some(_) => format!("No watch with that number. Watches are numbered 1 to {}", self.watches.len())
+                }
+            },
+            "d" | "delete" =>
+            {
+                match arg.map(parse_addr)
+                {
+                    None =>
+                    {
+                        self.breakpoints.clear();
+                        "Deleted all breakpoints".to_string()
+                    },
+                    Some(Some(addr)) if self.remove_breakpoint(addr) => format!("Deleted breakpoint at {:04X}", addr),
+                    Some(Some(addr)) => format!("No breakpoint at {:04X}", addr),
+                    Some(None) => "delete expects a hexadecimal address".to_string()
+                }
+            },
+            "bl" | "breakpoints" =>
+            {
+                if self.breakpoints.is_empty() && self.watches.is_empty()
+                {
+                    return "No breakpoints or watches".to_string();
+                }
+
+                let breakpoints = self.breakpoints.iter().map(|(addr, condition)| {
+                    match condition
+                    {
+                        Some(_) => format!("break {:04X} (conditional)", addr),
+                        None => format!("break {:04X}", addr)
+                    }
+                });
+                let watches = self.watches.iter().enumerate().map(|(n, watch)| format!("#{} {}", n + 1, watch.describe()));
+                breakpoints.chain(watches).collect::< Vec< _ > >().join("\n")
+            },
+            "r" | "regs" => registers(cpu),
+            "m" | "mem" =>
+            {
+                let addr = match arg.and_then(parse_addr)
+                {
+                    Some(addr) => addr,
+                    None => return "mem expects a hexadecimal address".to_string()
+                };
+                let len = match words.next().map(|n| n.parse::< usize >())
+                {
+                    None => 16,
+                    Some(Ok(n)) => n,
+                    Some(Err(_)) => return "mem expects a number of bytes".to_string()
+                };
+                memory_dump(cpu, addr, len)
+            },
+            "h" | "help" => DEBUGGER_HELP.to_string(),
+            _ => format!("Unknown command \"{}\". Type help for a list of commands", command)
+        }
+    }
+
+    /// Executes count instructions, then stays paused. Breakpoints and
+    /// watches stop the step early, except a breakpoint on the first one
+    fn step(&mut self, cpu: &mut CPU, count: u32) -> String
+    {
+        self.resume(Mode::Running);
+        for _ in 0..count
+        {
+            match self.cycle(cpu)
+            {
+                Ok(Some(message)) => return message,
+                Ok(None) => {},
+                Err(e) => return format!("{}\n{}", e, registers(cpu))
+            }
+        }
+
+        self.mode = Mode::Paused;
+        registers(cpu)
+    }
+
+    /// Leaves the paused state in the given mode
+    fn resume(&mut self, mode: Mode)
+    {
+        self.skip_breakpoint = true;
+        self.mode = mode;
+    }
+}
+
+/// Formats the registers, stack and timers of the CPU
+pub fn registers(cpu: &CPU) -> String
+{
+    let mut out = String::new();
+    let opcode = cpu.peek_opcode().map_or("????".to_string(), |op| format!("{:04X}", op));
+    writeln!(out, "PC {:04X} ({})  I {:04X}  SP {:X}  DT {:02X}  ST {:02X}", cpu.pc(), opcode, cpu.i(), cpu.sp, cpu.delay_timer, cpu.sound_timer).unwrap();
+    for (n, values) in cpu.v().chunks(8).enumerate()
+    {
+        let regs: Vec< String > = values.iter().enumerate().map(|(j, value)| format!("V{:X} {:02X}", n * 8 + j, value)).collect();
+        writeln!(out, "{}", regs.join("  ")).unwrap();
+    }
+    let stack: Vec< String > = cpu.stack[..cpu.sp].iter().map(|addr| format!("{:04X}", addr)).collect();
+    write!(out, "Stack [{}]", stack.join(" ")).unwrap();
+
+    out
+}
+
+/// Formats len bytes of memory from addr as a hex dump, 16 bytes per line
+fn memory_dump(cpu: &CPU, addr: usize, len: usize) -> String
+{
+    let end = addr.saturating_add(len).min(cpu.memory.len());
+    if addr >= end
+    {
+        return format!("Address {:04X} is outside memory", addr);
+    }
+
+    let lines: Vec< String > = (addr..end).step_by(16).map(|line| {
+        let bytes: Vec< String > = cpu.memory[line..(line + 16).min(end)].iter().map(|b| format!("{:02X}", b)).collect();
+        format!("{:04X}: {}", line, bytes.join(" "))
+    }).collect();
+
+    lines.join("\n")
+}
+
+/// Parses a hexadecimal address, with or without a 0x prefix
+fn parse_addr(text: &str) -> Option< usize >
+{
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    usize::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::cpu::PROGRAM_START;
+    use crate::quirks::Quirks;
+
+    /// v0 := 1, call 0x208, v0 += 1, jump 0x206, then at 0x208:
+    /// v1 := 5, i := 0x300, save v0 - v1, return
+    const PROGRAM: [u16; 8] = [0x6001, 0x2208, 0x7001, 0x1206, 0x6105, 0xA300, 0xF155, 0x00EE];
+
+    fn cpu() -> CPU
+    {
+        let mut cpu = CPU::new(Quirks::OCTO);
+        let bytes: Vec< u8 > = PROGRAM.iter().flat_map(|word| word.to_be_bytes()).collect();
+        cpu.load_program(&bytes).unwrap();
+        cpu
+    }
+
+    /// Calls cycle up to max times, returning the first message
+    fn run(debugger: &mut Debugger, cpu: &mut CPU, max: usize) -> Option< String >
+    {
+        (0..max).find_map(|_| debugger.cycle(cpu).unwrap())
+    }
+
+    #[test]
+    fn breakpoint_stops_before_the_instruction()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(false), cpu());
+        debugger.execute(&mut cpu, "b 20A");
+
+        let message = run(&mut debugger, &mut cpu, 10).unwrap();
+        assert!(message.starts_with("Breakpoint at 020A"), "{}", message);
+        assert_eq!(cpu.pc(), 0x20A);
+        assert!(debugger.is_paused());
+
+        // Paused, nothing runs
+        assert_eq!(run(&mut debugger, &mut cpu, 10), None);
+        assert_eq!(cpu.pc(), 0x20A);
+
+        // Continuing runs the instruction under the breakpoint
+        debugger.execute(&mut cpu, "c");
+        assert_eq!(run(&mut debugger, &mut cpu, 10), None);
+        assert_eq!(cpu.pc(), 0x206);
+
+        debugger.execute(&mut cpu, "d 20A");
+        assert_eq!(debugger.breakpoints().count(), 0);
+    }
+
+    #[test]
+    fn conditional_breakpoint()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(false), cpu());
+        assert_eq!(debugger.execute(&mut cpu, "b 20C if v1 == 6"), "Conditional breakpoint set at 020C");
+        debugger.execute(&mut cpu, "b 206 if v0 == 2");
+
+        let message = run(&mut debugger, &mut cpu, 10).unwrap();
+        assert!(message.starts_with("Breakpoint at 0206"), "{}", message);
+
+        assert!(debugger.execute(&mut cpu, "b 200 if v0 ==").starts_with("Invalid condition"));
+        assert!(debugger.execute(&mut cpu, "b 200 unless v0").starts_with("Expected \"if"));
+    }
+
+    #[test]
+    fn step()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(true), cpu());
+        assert!(debugger.execute(&mut cpu, "s").starts_with("PC 0202"));
+        assert!(debugger.execute(&mut cpu, "step 2").starts_with("PC 020A"));
+        assert_eq!(cpu.sp, 1);
+
+        // An empty line repeats the last command
+        assert!(debugger.execute(&mut cpu, "").starts_with("PC 020E"));
+        assert!(debugger.is_paused());
+        assert_eq!(debugger.execute(&mut cpu, "s x"), "step expects a number of instructions");
+    }
+
+    #[test]
+    fn step_stops_at_a_breakpoint_after_the_first_instruction()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(true), cpu());
+        debugger.execute(&mut cpu, "b 200");
+        debugger.execute(&mut cpu, "b 208");
+        assert!(debugger.execute(&mut cpu, "s 5").starts_with("Breakpoint at 0208"));
+        assert!(debugger.is_paused());
+    }
+
+    #[test]
+    fn next_steps_over_calls()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(true), cpu());
+        debugger.execute(&mut cpu, "s");
+        debugger.execute(&mut cpu, "n");
+        assert!(run(&mut debugger, &mut cpu, 10).unwrap().starts_with("PC 0204"));
+        assert_eq!(cpu.sp, 0);
+
+        // Other instructions are a single step
+        assert!(debugger.execute(&mut cpu, "n").starts_with("PC 0206"));
+    }
+
+    #[test]
+    fn finish_runs_to_the_return()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(true), cpu());
+        assert_eq!(debugger.execute(&mut cpu, "f"), "Not inside a subroutine");
+
+        debugger.execute(&mut cpu, "s 3");
+        assert_eq!(cpu.sp, 1);
+        debugger.execute(&mut cpu, "finish");
+        assert!(run(&mut debugger, &mut cpu, 10).unwrap().starts_with("PC 0204"));
+        assert_eq!(cpu.sp, 0);
+    }
+
+    #[test]
+    fn registers_and_memory()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(true), cpu());
+        debugger.execute(&mut cpu, "s 5");
+        let regs = debugger.execute(&mut cpu, "r");
+        assert!(regs.starts_with("PC 020E (00EE)  I 0302  SP 1"), "{}", regs);
+        assert!(regs.contains("V0 01") && regs.contains("V1 05"), "{}", regs);
+
+        assert_eq!(debugger.execute(&mut cpu, "m 300 2"), "0300: 01 05");
+        assert_eq!(debugger.execute(&mut cpu, "m 200"), "0200: 60 01 22 08 70 01 12 06 61 05 A3 00 F1 55 00 EE");
+        assert_eq!(debugger.execute(&mut cpu, "m FFFF 18446744073709551615"), "FFFF: 00");
+        assert_eq!(debugger.execute(&mut cpu, "m 10000"), "Address 10000 is outside memory");
+    }
+
+    #[test]
+    fn memory_watch()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(false), cpu());
+        debugger.execute(&mut cpu, "w 300 r");
+        debugger.execute(&mut cpu, "w 301-3FF w");
+
+        let message = run(&mut debugger, &mut cpu, 10).unwrap();
+        assert!(message.starts_with("Watchpoint: instruction wrote 2 bytes at 0300"), "{}", message);
+        assert_eq!(cpu.pc(), 0x20E);
+
+        assert_eq!(debugger.execute(&mut cpu, "bl"), "#1 watch 0300-0300 r\n#2 watch 0301-03FF w");
+        assert!(debugger.execute(&mut cpu, "w 30F-300").starts_with("watch expects"));
+        assert!(debugger.execute(&mut cpu, "w 300 x").starts_with("Unknown access"));
+        assert_eq!(debugger.execute(&mut cpu, "uw 1"), "Removed watch 0300-0300 r");
+    }
+
+    #[test]
+    fn register_watch()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(false), cpu());
+        debugger.execute(&mut cpu, "wr v1");
+        let message = run(&mut debugger, &mut cpu, 10).unwrap();
+        assert!(message.starts_with("Watchpoint: V1 changed from 0 to 5"), "{}", message);
+        assert_eq!(cpu.pc(), 0x20A);
+
+        assert!(debugger.execute(&mut cpu, "wr vg").starts_with("watchreg expects"));
+    }
+
+    #[test]
+    fn stepping_keeps_register_watches_up_to_date()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(true), cpu());
+        debugger.execute(&mut cpu, "wr i");
+        assert!(debugger.execute(&mut cpu, "s 10").starts_with("Watchpoint: I changed from 0 to 300"));
+
+        // The next stop compares against the value when stepping stopped
+        debugger.execute(&mut cpu, "c");
+        let message = run(&mut debugger, &mut cpu, 20).unwrap();
+        assert!(message.starts_with("Watchpoint: I changed from 300 to 302"), "{}", message);
+
+        debugger.execute(&mut cpu, "c");
+        assert_eq!(run(&mut debugger, &mut cpu, 20), None);
+    }
+
+    #[test]
+    fn condition()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(false), cpu());
+        assert_eq!(debugger.execute(&mut cpu, "when v0 == 2 && sp == 0"), "Breaking when v0 == 2 && sp == 0");
+
+        let message = run(&mut debugger, &mut cpu, 10).unwrap();
+        assert!(message.starts_with("Condition is true: v0 == 2 && sp == 0"), "{}", message);
+        assert_eq!(cpu.pc(), 0x206);
+
+        // It only fires again once it has been false
+        debugger.execute(&mut cpu, "c");
+        assert_eq!(run(&mut debugger, &mut cpu, 10), None);
+
+        assert!(debugger.execute(&mut cpu, "when (v0").starts_with("Invalid condition"));
+    }
+
+    #[test]
+    fn unknown_command()
+    {
+        let (mut debugger, mut cpu) = (Debugger::new(true), cpu());
+        assert_eq!(debugger.execute(&mut cpu, "jump"), "Unknown command \"jump\". Type help for a list of commands");
+        assert_eq!(cpu.pc(), PROGRAM_START);
+    }
+}
@@ -0,0 +1,262 @@
+//! Expressions over machine state used by conditional breakpoints, e.g.
+//! `v3 == 0x10 && i > 0x300` or `[i + 1] != 0`
+
+use crate::cpu::CPU;
+use crate::expr::{ parse_number, Grammar, Parser };
+
+use std::fmt;
+
+/// A register that expressions and register watches can refer to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register
+{
+    V(u8),
+    I,
+    PC,
+    SP,
+    DT,
+    ST,
+}
+
+impl Register
+{
+    /// Parses a register name: v0-vf, i, pc, sp, dt or st
+    pub fn parse(name: &str) -> Option< Register >
+    {
+        let name = name.to_ascii_lowercase();
+        match name.as_str()
+        {
+            "i" => Some(Register::I),
+            "pc" => Some(Register::PC),
+            "sp" => Some(Register::SP),
+            "dt" => Some(Register::DT),
+            "st" => Some(Register::ST),
+            _ if name.len() == 2 && name.starts_with('v') => u8::from_str_radix(&name[1..], 16).ok().map(Register::V),
+            _ => None
+        }
+    }
+
+    /// Reads the register's current value
+    pub fn read(self, cpu: &CPU) -> i64
+    {
+        match self
+        {
+            Register::V(x) => cpu.v()[x as usize] as i64,
+            Register::I => cpu.i() as i64,
+            Register::PC => cpu.pc() as i64,
+            Register::SP => cpu.sp as i64,
+            Register::DT => cpu.delay_timer as i64,
+            Register::ST => cpu.sound_timer as i64,
+        }
+    }
+}
+
+impl fmt::Display for Register
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::PC => write!(f, "PC"),
+            Register::SP => write!(f, "SP"),
+            Register::DT => write!(f, "DT"),
+            Register::ST => write!(f, "ST"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp
+{
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr
+{
+    Number(i64),
+    Register(Register),
+
+    /// The byte of memory at an address, written `[addr]`
+    Memory(Box< Expr >),
+
+    Not(Box< Expr >),
+    Binary(BinaryOp, Box< Expr >, Box< Expr >),
+}
+
+impl Expr
+{
+    /// Parses an expression. Numbers are written as in the assembler.
+    /// Operators, loosest binding first, are `||`, `&&`, comparisons,
+    /// `|`, `&`, `+ -` and the prefix `!`
+    pub fn parse(text: &str) -> Result< Expr, String >
+    {
+        Parser::< Expr >::parse(text)
+    }
+
+    /// Evaluates the expression. Comparisons and logical operators give 1 for
+    /// true and 0 for false; reads of memory outside the address space give 0
+    pub fn eval(&self, cpu: &CPU) -> i64
+    {
+        match self
+        {
+            Expr::Number(n) => *n,
+            Expr::Register(reg) => reg.read(cpu),
+            Expr::Memory(addr) =>
+            {
+                let addr = addr.eval(cpu);
+                if addr >= 0 { cpu.memory.get(addr as usize).map_or(0, |&b| b as i64) } else { 0 }
+            },
+            Expr::Not(expr) => (expr.eval(cpu) == 0) as i64,
+            Expr::Binary(op, lhs, rhs) =>
+            {
+                let lhs = lhs.eval(cpu);
+                // Short-circuit the logical operators
+                match op
+                {
+                    BinaryOp::Or if lhs != 0 => return 1,
+                    BinaryOp::And if lhs == 0 => return 0,
+                    _ => {}
+                }
+                let rhs = rhs.eval(cpu);
+                match op
+                {
+                    BinaryOp::Or | BinaryOp::And => (rhs != 0) as i64,
+                    BinaryOp::Eq => (lhs == rhs) as i64,
+                    BinaryOp::Ne => (lhs != rhs) as i64,
+                    BinaryOp::Lt => (lhs < rhs) as i64,
+                    BinaryOp::Le => (lhs <= rhs) as i64,
+                    BinaryOp::Gt => (lhs > rhs) as i64,
+                    BinaryOp::Ge => (lhs >= rhs) as i64,
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                }
+            }
+        }
+    }
+}
+
+impl Grammar for Expr
+{
+    type Op = BinaryOp;
+    type Expr = Expr;
+
+    const BINARY_OPS: &'static [(&'static str, BinaryOp, u8)] = &[
+        ("||", BinaryOp::Or, 1),
+        ("&&", BinaryOp::And, 2),
+        ("==", BinaryOp::Eq, 3),
+        ("!=", BinaryOp::Ne, 3),
+        ("<=", BinaryOp::Le, 3),
+        (">=", BinaryOp::Ge, 3),
+        ("<", BinaryOp::Lt, 3),
+        (">", BinaryOp::Gt, 3),
+        ("|", BinaryOp::BitOr, 4),
+        ("&", BinaryOp::BitAnd, 5),
+        ("+", BinaryOp::Add, 6),
+        ("-", BinaryOp::Sub, 6),
+    ];
+
+    const PUNCTUATION: &'static str = "!()[]";
+
+    fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr
+    {
+        Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    fn unary(parser: &mut Parser< Expr >) -> Result< Expr, String >
+    {
+        let token = parser.operand()?;
+        match token.as_str()
+        {
+            "!" => Ok(Expr::Not(Box::new(Expr::unary(parser)?))),
+            "(" =>
+            {
+                let expr = parser.binary(0)?;
+                parser.expect(")")?;
+                Ok(expr)
+            },
+            "[" =>
+            {
+                let expr = parser.binary(0)?;
+                parser.expect("]")?;
+                Ok(Expr::Memory(Box::new(expr)))
+            },
+            _ => Register::parse(&token).map(Expr::Register)
+                .or_else(|| parse_number(&token).map(Expr::Number))
+                .ok_or_else(|| format!("Unexpected \"{}\"", token))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::quirks::Quirks;
+
+    fn eval(text: &str) -> i64
+    {
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.memory[0x301] = 0x42;
+        // i := 0x300
+        cpu.load_program(&[0xA3, 0x00]).unwrap();
+        cpu.cpu_cycle().unwrap();
+        Expr::parse(text).unwrap().eval(&cpu)
+    }
+
+    #[test]
+    fn precedence()
+    {
+        assert_eq!(eval("1 | 2 & 0"), 1);
+        assert_eq!(eval("1 == 1 | 2"), 0);
+        assert_eq!(eval("1 || 0 && 0"), 1);
+        assert_eq!(eval("2 + 3 == 5 && 1 < 2"), 1);
+        assert_eq!(eval("10 - 3 - 2"), 5);
+        assert_eq!(eval("(1 | 2) & 2"), 2);
+        assert_eq!(eval("!0 + 1"), 2);
+        assert_eq!(eval("!(0 + 1)"), 0);
+    }
+
+    #[test]
+    fn comparisons()
+    {
+        assert_eq!(eval("1 <= 1"), 1);
+        assert_eq!(eval("1 >= 2"), 0);
+        assert_eq!(eval("1 != 2"), 1);
+        assert_eq!(eval("2 > 1 && 1 < 2"), 1);
+    }
+
+    #[test]
+    fn registers_and_memory()
+    {
+        assert_eq!(eval("i"), 0x300);
+        assert_eq!(eval("PC == 0x202 && sp == 0 && v0 == 0 && VF == 0"), 1);
+        assert_eq!(eval("[i + 1]"), 0x42);
+        assert_eq!(eval("[0x10000]"), 0);
+        assert_eq!(eval("[0 - 1]"), 0);
+    }
+
+    #[test]
+    fn invalid()
+    {
+        for text in ["", "1 +", "(1", "[i", "1 2", "1 $ 2", "vg", "0xZ"].iter()
+        {
+            assert!(Expr::parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+}
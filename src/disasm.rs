@@ -0,0 +1,495 @@
+//! Disassembler for CHIP-8, SUPER-CHIP and XO-CHIP programs.
+//!
+//! Code is told apart from data by tracing every path the program can take
+//! from the entry point at 0x200: jumps, calls, both outcomes of each skip
+//! and the fall through of every other instruction. Bytes no path reaches
+//! are printed as data. Jump, call and `LD I` targets are given labels.
+
+use crate::cpu::PROGRAM_START;
+use crate::instruction::Instruction;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Column the address and raw word comment starts at
+const COMMENT_COLUMN: usize = 32;
+
+/// Most bytes printed on one data line
+const DATA_BYTES_PER_LINE: usize = 8;
+
+/// Assembly syntax the disassembler writes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syntax
+{
+    /// The mnemonics of Cowgod's Chip-8 technical reference, e.g. `LD VA, 0x02`
+    Cowgod,
+
+    /// The syntax of the Octo assembler, e.g. `va := 0x02`
+    Octo,
+}
+
+impl FromStr for Syntax
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result< Self, Self::Err >
+    {
+        match s.to_ascii_lowercase().as_str()
+        {
+            "cowgod" => Ok(Syntax::Cowgod),
+            "octo" => Ok(Syntax::Octo),
+            _ => Err(format!("Unknown syntax \"{}\", expected cowgod or octo", s))
+        }
+    }
+}
+
+/// What a label marks, which decides its name. When an address is the target
+/// of several kinds of reference the later kind wins
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind
+{
+    Data,
+    Jump,
+    Subroutine,
+}
+
+/// A line of the listing
+enum Line< 'a >
+{
+    Code { addr: usize, instruction: Instruction, raw: &'a [u8] },
+    Data { addr: usize, bytes: &'a [u8] },
+}
+
+/// Disassembles a ROM loaded at 0x200 into a listing in the given syntax. The
+/// listing can be fed back to an assembler; addresses and raw words are
+/// written in comments
+pub fn disassemble(rom: &[u8], syntax: Syntax) -> String
+{
+    let (code, mut labels) = trace(rom);
+    if syntax == Syntax::Octo && !rom.is_empty()
+    {
+        labels.entry(PROGRAM_START).or_insert(LabelKind::Jump);
+    }
+    let lines = layout(rom, &code, &labels);
+
+    // Drop labels that would fall inside a line, references to them are
+    // written as plain addresses
+    labels.retain(|addr, _| lines.iter().any(|line| line_addr(line) == *addr));
+
+    let mut out = String::new();
+    for line in lines.iter()
+    {
+        let addr = line_addr(line);
+        if let Some(&kind) = labels.get(&addr)
+        {
+            let name = label_name(addr, kind, syntax);
+            match syntax
+            {
+                Syntax::Cowgod => writeln!(out, "{}:", name).unwrap(),
+                Syntax::Octo => writeln!(out, ": {}", name).unwrap(),
+            }
+        }
+
+        let (text, raw) = match line
+        {
+            Line::Code { instruction, raw, .. } =>
+            {
+                let operand = if raw.len() == 4 { u16::from_be_bytes([raw[2], raw[3]]) } else { 0 };
+                let text = format_instruction(*instruction, operand, syntax, &|target| {
+                    labels.get(&(target as usize)).map(|&kind| label_name(target as usize, kind, syntax))
+                });
+                let words: Vec< String > = raw.chunks(2).map(|w| w.iter().map(|b| format!("{:02X}", b)).collect()).collect();
+                (text, words.join(" "))
+            },
+            Line::Data { bytes, .. } =>
+            {
+                let bytes: Vec< String > = bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+                let text = match syntax
+                {
+                    Syntax::Cowgod => format!("DB {}", bytes.join(", ")),
+                    Syntax::Octo => bytes.join(" "),
+                };
+                (text, String::new())
+            }
+        };
+
+        let comment = match syntax { Syntax::Cowgod => ';', Syntax::Octo => '#' };
+        let code = format!("    {}", text);
+        let line = format!("{:<width$} {} {:04X}  {}", code, comment, addr, raw, width = COMMENT_COLUMN - 1);
+        writeln!(out, "{}", line.trim_end()).unwrap();
+    }
+
+    out
+}
+
+/// Formats a single instruction. F000 NNNN takes its second word as operand,
+/// every other instruction ignores it. `label` names an address, or gives
+/// None to have it written as a number
+pub fn format_instruction(instruction: Instruction, operand: u16, syntax: Syntax, label: &dyn Fn(u16) -> Option< String >) -> String
+{
+    let addr = |nnn: u16| label(nnn).unwrap_or_else(|| format!("0x{:03X}", nnn));
+    match syntax
+    {
+        Syntax::Cowgod => format_cowgod(instruction, operand, &addr),
+        Syntax::Octo => format_octo(instruction, operand, &addr),
+    }
+}
+
+fn format_cowgod(instruction: Instruction, operand: u16, addr: &dyn Fn(u16) -> String) -> String
+{
+    use crate::instruction::Instruction::*;
+
+    match instruction
+    {
+        ScrollDown(n) => format!("SCD {}", n),
+        ScrollUp(n) => format!("SCU {}", n),
+        Cls => "CLS".to_string(),
+        Ret => "RET".to_string(),
+        ScrollRight => "SCR".to_string(),
+        ScrollLeft => "SCL".to_string(),
+        Exit => "EXIT".to_string(),
+        Low => "LOW".to_string(),
+        High => "HIGH".to_string(),
+        Jp(nnn) => format!("JP {}", addr(nnn)),
+        Call(nnn) => format!("CALL {}", addr(nnn)),
+        SeVxNn(x, nn) => format!("SE V{:X}, 0x{:02X}", x, nn),
+        SneVxNn(x, nn) => format!("SNE V{:X}, 0x{:02X}", x, nn),
+        SeVxVy(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        SaveRange(x, y) => format!("LD [I], V{:X}-V{:X}", x, y),
+        LoadRange(x, y) => format!("LD V{:X}-V{:X}, [I]", x, y),
+        LdVxNn(x, nn) => format!("LD V{:X}, 0x{:02X}", x, nn),
+        AddVxNn(x, nn) => format!("ADD V{:X}, 0x{:02X}", x, nn),
+        LdVxVy(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        AddVxVy(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        Shr(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        Subn(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        Shl(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        SneVxVy(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        LdI(nnn) => format!("LD I, {}", addr(nnn)),
+        JpV0(nnn) => format!("JP V0, {}", addr(nnn)),
+        Rnd(x, nn) => format!("RND V{:X}, 0x{:02X}", x, nn),
+        Drw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        Skp(x) => format!("SKP V{:X}", x),
+        Sknp(x) => format!("SKNP V{:X}", x),
+        LdILong => format!("LD I, LONG {}", addr(operand)),
+        Plane(n) => format!("PLANE {}", n),
+        Audio => "AUDIO".to_string(),
+        LdVxDt(x) => format!("LD V{:X}, DT", x),
+        LdVxK(x) => format!("LD V{:X}, K", x),
+        LdDtVx(x) => format!("LD DT, V{:X}", x),
+        LdStVx(x) => format!("LD ST, V{:X}", x),
+        AddIVx(x) => format!("ADD I, V{:X}", x),
+        LdFVx(x) => format!("LD F, V{:X}", x),
+        LdHfVx(x) => format!("LD HF, V{:X}", x),
+        LdBVx(x) => format!("LD B, V{:X}", x),
+        Pitch(x) => format!("PITCH V{:X}", x),
+        LdIVx(x) => format!("LD [I], V{:X}", x),
+        LdVxI(x) => format!("LD V{:X}, [I]", x),
+        LdRVx(x) => format!("LD R, V{:X}", x),
+        LdVxR(x) => format!("LD V{:X}, R", x),
+    }
+}
+
+/// Octo has no skip instructions, only `if ... then` which runs the next
+/// instruction when the condition holds, so each skip condition is inverted
+fn format_octo(instruction: Instruction, operand: u16, addr: &dyn Fn(u16) -> String) -> String
+{
+    use crate::instruction::Instruction::*;
+
+    match instruction
+    {
+        ScrollDown(n) => format!("scroll-down {}", n),
+        ScrollUp(n) => format!("scroll-up {}", n),
+        Cls => "clear".to_string(),
+        Ret => "return".to_string(),
+        ScrollRight => "scroll-right".to_string(),
+        ScrollLeft => "scroll-left".to_string(),
+        Exit => "exit".to_string(),
+        Low => "lores".to_string(),
+        High => "hires".to_string(),
+        Jp(nnn) => format!("jump {}", addr(nnn)),
+        Call(nnn) => format!(":call {}", addr(nnn)),
+        SeVxNn(x, nn) => format!("if v{:x} != 0x{:02X} then", x, nn),
+        SneVxNn(x, nn) => format!("if v{:x} == 0x{:02X} then", x, nn),
+        SeVxVy(x, y) => format!("if v{:x} != v{:x} then", x, y),
+        SaveRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+        LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+        LdVxNn(x, nn) => format!("v{:x} := 0x{:02X}", x, nn),
+        AddVxNn(x, nn) => format!("v{:x} += 0x{:02X}", x, nn),
+        LdVxVy(x, y) => format!("v{:x} := v{:x}", x, y),
+        Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+        And(x, y) => format!("v{:x} &= v{:x}", x, y),
+        Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+        AddVxVy(x, y) => format!("v{:x} += v{:x}", x, y),
+        Sub(x, y) => format!("v{:x} -= v{:x}", x, y),
+        Shr(x, y) => format!("v{:x} >>= v{:x}", x, y),
+        Subn(x, y) => format!("v{:x} =- v{:x}", x, y),
+        Shl(x, y) => format!("v{:x} <<= v{:x}", x, y),
+        SneVxVy(x, y) => format!("if v{:x} == v{:x} then", x, y),
+        LdI(nnn) => format!("i := {}", addr(nnn)),
+        JpV0(nnn) => format!("jump0 {}", addr(nnn)),
+        Rnd(x, nn) => format!("v{:x} := random 0x{:02X}", x, nn),
+        Drw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+        Skp(x) => format!("if v{:x} -key then", x),
+        Sknp(x) => format!("if v{:x} key then", x),
+        LdILong => format!("i := long {}", addr(operand)),
+        Plane(n) => format!("plane {}", n),
+        Audio => "audio".to_string(),
+        LdVxDt(x) => format!("v{:x} := delay", x),
+        LdVxK(x) => format!("v{:x} := key", x),
+        LdDtVx(x) => format!("delay := v{:x}", x),
+        LdStVx(x) => format!("buzzer := v{:x}", x),
+        AddIVx(x) => format!("i += v{:x}", x),
+        LdFVx(x) => format!("i := hex v{:x}", x),
+        LdHfVx(x) => format!("i := bighex v{:x}", x),
+        LdBVx(x) => format!("bcd v{:x}", x),
+        Pitch(x) => format!("pitch := v{:x}", x),
+        LdIVx(x) => format!("save v{:x}", x),
+        LdVxI(x) => format!("load v{:x}", x),
+        LdRVx(x) => format!("saveflags v{:x}", x),
+        LdVxR(x) => format!("loadflags v{:x}", x),
+    }
+}
+
+/// Follows every reachable path from the entry point, returning the address
+/// of each instruction found and the labelled addresses
+fn trace(rom: &[u8]) -> (BTreeMap< usize, Instruction >, BTreeMap< usize, LabelKind >)
+{
+    let word = |addr: usize| {
+        let offset = addr.checked_sub(PROGRAM_START)?;
+        Some(u16::from_be_bytes([*rom.get(offset)?, *rom.get(offset + 1)?]))
+    };
+
+    let mut code = BTreeMap::new();
+    let mut labels = BTreeMap::new();
+    let mut add_label = |addr: u16, kind: LabelKind| {
+        let entry = labels.entry(addr as usize).or_insert(kind);
+        *entry = (*entry).max(kind);
+    };
+
+    let mut pending = vec![PROGRAM_START];
+    while let Some(addr) = pending.pop()
+    {
+        if code.contains_key(&addr)
+        {
+            continue;
+        }
+
+        let instruction = match word(addr).and_then(Instruction::decode)
+        {
+            Some(instruction) => instruction,
+            None => continue
+        };
+        if instruction == Instruction::LdILong
+        {
+            match word(addr + 2)
+            {
+                Some(target) => add_label(target, LabelKind::Data),
+                None => continue
+            }
+        }
+        code.insert(addr, instruction);
+
+        let next = addr + instruction.size();
+        match instruction
+        {
+            Instruction::Ret | Instruction::Exit => {},
+            Instruction::Jp(nnn) =>
+            {
+                add_label(nnn, LabelKind::Jump);
+                pending.push(nnn as usize);
+            },
+            Instruction::JpV0(nnn) =>
+            {
+                // The real target depends on V0, so only the start of the
+                // jump table is known
+                add_label(nnn, LabelKind::Jump);
+                pending.push(nnn as usize);
+            },
+            Instruction::Call(nnn) =>
+            {
+                add_label(nnn, LabelKind::Subroutine);
+                pending.push(nnn as usize);
+                pending.push(next);
+            },
+            Instruction::LdI(nnn) =>
+            {
+                add_label(nnn, LabelKind::Data);
+                pending.push(next);
+            },
+            _ if instruction.is_skip() =>
+            {
+                let skipped = match word(next) { Some(0xF000) => 4, _ => 2 };
+                pending.push(next);
+                pending.push(next + skipped);
+            },
+            _ => pending.push(next)
+        }
+    }
+
+    (code, labels)
+}
+
+/// Splits the ROM into code and data lines. Data lines end early at labels
+/// so every label in a data region starts a line
+fn layout< 'a >(rom: &'a [u8], code: &BTreeMap< usize, Instruction >, labels: &BTreeMap< usize, LabelKind >) -> Vec< Line< 'a > >
+{
+    let end = PROGRAM_START + rom.len();
+    let bytes = |addr: usize, len: usize| &rom[addr - PROGRAM_START..addr - PROGRAM_START + len];
+
+    let mut lines = Vec::new();
+    let mut addr = PROGRAM_START;
+    while addr < end
+    {
+        if let Some(&instruction) = code.get(&addr)
+        {
+            lines.push(Line::Code { addr, instruction, raw: bytes(addr, instruction.size()) });
+            addr += instruction.size();
+            continue;
+        }
+
+        let mut len = 1;
+        while len < DATA_BYTES_PER_LINE && addr + len < end && !code.contains_key(&(addr + len)) && !labels.contains_key(&(addr + len))
+        {
+            len += 1;
+        }
+        lines.push(Line::Data { addr, bytes: bytes(addr, len) });
+        addr += len;
+    }
+
+    lines
+}
+
+fn line_addr(line: &Line) -> usize
+{
+    match line
+    {
+        Line::Code { addr, .. } | Line::Data { addr, .. } => *addr,
+    }
+}
+
+/// Octo starts running programs at the label `main`, so the entry point is
+/// always given that name in Octo syntax
+fn label_name(addr: usize, kind: LabelKind, syntax: Syntax) -> String
+{
+    if syntax == Syntax::Octo && addr == PROGRAM_START
+    {
+        return "main".to_string();
+    }
+
+    match kind
+    {
+        LabelKind::Data => format!("data_{:04X}", addr),
+        LabelKind::Jump => format!("label_{:04X}", addr),
+        LabelKind::Subroutine => format!("sub_{:04X}", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// A call, a data reference, a skip over a jump, a loop and a return,
+    /// followed by unreached bytes that decode as CLS and a labelled table
+    const ROM: [u8; 26] = [
+        0x22, 0x0A, 0xA2, 0x0E, 0x30, 0x00, 0x12, 0x04, 0x12, 0x08, 0x00, 0xEE, 0x00, 0xE0,
+        0xFF, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B,
+    ];
+
+    #[test]
+    fn trace_follows_every_path()
+    {
+        let (code, labels) = trace(&ROM);
+        assert_eq!(code.keys().copied().collect::< Vec< _ > >(), vec![0x200, 0x202, 0x204, 0x206, 0x208, 0x20A]);
+        assert_eq!(labels.into_iter().collect::< Vec< _ > >(), vec![
+            (0x204, LabelKind::Jump),
+            (0x208, LabelKind::Jump),
+            (0x20A, LabelKind::Subroutine),
+            (0x20E, LabelKind::Data),
+        ]);
+    }
+
+    #[test]
+    fn skips_step_over_long_loads()
+    {
+        // SE V0, 0 / LD I, LONG 0x0300 / EXIT
+        let (code, labels) = trace(&[0x30, 0x00, 0xF0, 0x00, 0x03, 0x00, 0x00, 0xFD]);
+        assert_eq!(code.keys().copied().collect::< Vec< _ > >(), vec![0x200, 0x202, 0x206]);
+        assert_eq!(labels.get(&0x300), Some(&LabelKind::Data));
+    }
+
+    #[test]
+    fn strongest_label_kind_wins()
+    {
+        // LD I, 0x204 / CALL 0x204 / JP 0x204 all name the same address
+        let (_, labels) = trace(&[0xA2, 0x04, 0x22, 0x04, 0x12, 0x04]);
+        assert_eq!(labels.get(&0x204), Some(&LabelKind::Subroutine));
+    }
+
+    #[test]
+    fn cowgod_listing()
+    {
+        let expected = "    CALL sub_020A               ; 0200  220A
+    LD I, data_020E             ; 0202  A20E
+label_0204:
+    SE V0, 0x00                 ; 0204  3000
+    JP label_0204               ; 0206  1204
+label_0208:
+    JP label_0208               ; 0208  1208
+sub_020A:
+    RET                         ; 020A  00EE
+    DB 0x00, 0xE0               ; 020C
+data_020E:
+    DB 0xFF, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07 ; 020E
+    DB 0x08, 0x09, 0x0A, 0x0B   ; 0216
+";
+        assert_eq!(disassemble(&ROM, Syntax::Cowgod), expected);
+    }
+
+    #[test]
+    fn octo_listing()
+    {
+        let expected = ": main
+    :call sub_020A              # 0200  220A
+    i := data_020E              # 0202  A20E
+: label_0204
+    if v0 != 0x00 then          # 0204  3000
+    jump label_0204             # 0206  1204
+: label_0208
+    jump label_0208             # 0208  1208
+: sub_020A
+    return                      # 020A  00EE
+    0x00 0xE0                   # 020C
+: data_020E
+    0xFF 0x01 0x02 0x03 0x04 0x05 0x06 0x07 # 020E
+    0x08 0x09 0x0A 0x0B         # 0216
+";
+        assert_eq!(disassemble(&ROM, Syntax::Octo), expected);
+    }
+
+    #[test]
+    fn labels_inside_lines_are_written_as_addresses()
+    {
+        // LD I, 0x203 points into the second byte of the jump
+        let listing = disassemble(&[0xA2, 0x03, 0x12, 0x02, 0x07], Syntax::Cowgod);
+        assert!(listing.contains("LD I, 0x203"), "{}", listing);
+        assert!(!listing.contains("data_0203"), "{}", listing);
+
+        // A trailing odd byte is data
+        assert!(listing.ends_with("    DB 0x07                     ; 0204\n"), "{}", listing);
+    }
+
+    #[test]
+    fn syntax_names()
+    {
+        assert_eq!("Octo".parse(), Ok(Syntax::Octo));
+        assert_eq!("cowgod".parse(), Ok(Syntax::Cowgod));
+        assert!("nasm".parse::< Syntax >().is_err());
+    }
+}
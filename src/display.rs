@@ -1,11 +1,13 @@
-use sdl2::pixels::Color;
+use crate::error::Chip8Error;
+use crate::state::{ StateReader, StateWriter };
 
+/// Size of the low resolution (CHIP-8) display
 pub const DISPLAY_WIDTH: i32 = 64;
 pub const DISPLAY_HEIGHT: i32 = 32;
-pub const DISPLAY_PIXEL_SCALE: i32 = 10;
 
-pub const DISPLAY_COLOR_PIXEL_ON: Color = Color { r: 0xFF, g: 0xFF, b: 0xFF, a: 0xFF };
-pub const DISPLAY_COLOR_PIXEL_OFF: Color = Color { r: 0x0, g: 0x0, b: 0x0, a: 0xFF };
+/// Size of the SUPER-CHIP high resolution display
+pub const HIRES_DISPLAY_WIDTH: i32 = 128;
+pub const HIRES_DISPLAY_HEIGHT: i32 = 64;
 
 pub static CHIP8_FONT: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0,   // 0
@@ -26,12 +28,48 @@ pub static CHIP8_FONT: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80    // F
 ];
 
+/// The SUPER-CHIP 8x10 font used by Fx30. SCHIP 1.1 only defines the digits 
+/// 0-9; A-F are the glyphs Octo uses
+pub static SCHIP_FONT: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF,     // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF,     // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,     // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,     // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03,     // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,     // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,     // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18,     // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,     // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,     // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3,     // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC,     // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C,     // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC,     // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,     // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0      // F
+];
+
+/// Number of XO-CHIP bitplanes
+pub const PLANE_COUNT: usize = 2;
+
+/// Characters `Display::to_text` uses for each combination of plane bits
+pub const TEXT_PIXELS: [char; 4] = ['.', '#', '+', '@'];
+
+#[derive(Clone)]
 pub struct Display
 {
-    /// The display is comprised of 64x32 pixels so we represent the display 
-    /// memory as an array of 2048 bytes
-    /// For a single pixel: 1 means the pixel is ON and 0 means the pixel is OFF
-    pub memory: [[u8; DISPLAY_WIDTH as usize]; DISPLAY_HEIGHT as usize],
+    /// The display is at most 128x64 pixels. In low resolution mode only the 
+    /// top-left 64x32 pixels are used
+    /// Each pixel holds one bit per XO-CHIP bitplane: bit 0 is plane 1 and 
+    /// bit 1 is plane 2. Plain CHIP-8 programs only use plane 1, so a pixel 
+    /// is 1 when ON and 0 when OFF
+    pub memory: [[u8; HIRES_DISPLAY_WIDTH as usize]; HIRES_DISPLAY_HEIGHT as usize],
+
+    /// Is the display in SUPER-CHIP high resolution mode?
+    hires: bool,
+
+    /// Bitmask of the planes affected by drawing, clearing and scrolling
+    planes: u8,
 }
 
 impl Display
@@ -40,36 +78,243 @@ impl Display
     pub fn new() -> Self
     {
         Display {
-            memory: [[0u8; DISPLAY_WIDTH as usize]; DISPLAY_HEIGHT as usize],
+            memory: [[0u8; HIRES_DISPLAY_WIDTH as usize]; HIRES_DISPLAY_HEIGHT as usize],
+            hires: false,
+            planes: 0x1,
         }
     }
 
+    /// Width in pixels of the active resolution
+    pub fn width(&self) -> usize
+    {
+        if self.hires { HIRES_DISPLAY_WIDTH as usize } else { DISPLAY_WIDTH as usize }
+    }
+
+    /// Height in pixels of the active resolution
+    pub fn height(&self) -> usize
+    {
+        if self.hires { HIRES_DISPLAY_HEIGHT as usize } else { DISPLAY_HEIGHT as usize }
+    }
+
+    pub fn is_hires(&self) -> bool
+    {
+        self.hires
+    }
+
+    /// Switch between low and high resolution. Every plane is cleared
+    pub fn set_hires(&mut self, hires: bool)
+    {
+        self.hires = hires;
+        self.memory = [[0u8; HIRES_DISPLAY_WIDTH as usize]; HIRES_DISPLAY_HEIGHT as usize];
+    }
+
+    /// Bitmask of the selected planes
+    pub fn planes(&self) -> u8
+    {
+        self.planes
+    }
+
+    /// Select the planes affected by drawing, clearing and scrolling
+    pub fn select_planes(&mut self, mask: u8)
+    {
+        self.planes = mask & ((1 << PLANE_COUNT) - 1);
+    }
+
+    /// Number of planes currently selected
+    pub fn selected_plane_count(&self) -> usize
+    {
+        self.planes.count_ones() as usize
+    }
+
+    /// Turn every pixel of the selected planes off
     pub fn clear(&mut self)
     {
-        self.memory = [[0u8; DISPLAY_WIDTH as usize]; DISPLAY_HEIGHT as usize];
+        let mask = self.planes;
+        for row in self.memory.iter_mut()
+        {
+            for pixel in row.iter_mut()
+            {
+                *pixel &= !mask;
+            }
+        }
+    }
+
+    /// XOR an 8 pixel wide sprite onto the selected planes at (x, y). When 
+    /// several planes are selected the sprite holds the data for each plane 
+    /// in turn, lowest plane first. The starting position always wraps; pixels 
+    /// past the edge wrap around if `wrap` is set and are clipped otherwise. 
+    /// Returns true if any pixel was turned off (a collision)
+    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8], wrap: bool) -> bool
+    {
+        let rows: Vec< u16 > = sprite.iter().map(|&row| (row as u16) << 8).collect();
+        self.draw_planes(x, y, &rows, 8, wrap)
+    }
+
+    /// XOR a 16x16 SUPER-CHIP sprite onto the selected planes at (x, y). The 
+    /// sprite is 32 bytes per plane, two per row. Planes, wrapping and collision 
+    /// work as in `draw`
+    pub fn draw_large(&mut self, x: usize, y: usize, sprite: &[u8], wrap: bool) -> bool
+    {
+        let rows: Vec< u16 > = sprite.chunks(2).map(|row| (row[0] as u16) << 8 | row[1] as u16).collect();
+        self.draw_planes(x, y, &rows, 16, wrap)
+    }
+
+    /// Writes the display mode and pixels to a save state
+    pub(crate) fn save_state(&self, w: &mut StateWriter)
+    {
+        w.bool(self.hires);
+        w.u8(self.planes);
+        for row in self.memory.iter()
+        {
+            w.bytes(row);
+        }
+    }
+
+    /// Restores the display written by `save_state`
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result< (), Chip8Error >
+    {
+        self.hires = r.bool()?;
+        self.planes = r.u8()? & ((1 << PLANE_COUNT) - 1);
+        for row in self.memory.iter_mut()
+        {
+            row.copy_from_slice(r.bytes(HIRES_DISPLAY_WIDTH as usize)?);
+        }
+
+        Ok(())
+    }
+
+    /// Scroll the selected planes up by n pixels
+    pub fn scroll_up(&mut self, n: usize)
+    {
+        self.scroll(0, -(n as isize));
     }
 
-    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool
+    /// Scroll the selected planes down by n pixels
+    pub fn scroll_down(&mut self, n: usize)
     {
+        self.scroll(0, n as isize);
+    }
+
+    /// Scroll the selected planes right by n pixels
+    pub fn scroll_right(&mut self, n: usize)
+    {
+        self.scroll(n as isize, 0);
+    }
+
+    /// Scroll the selected planes left by n pixels
+    pub fn scroll_left(&mut self, n: usize)
+    {
+        self.scroll(-(n as isize), 0);
+    }
+
+    /// The active screen as text, one line per row. Each pixel is a character
+    /// from `TEXT_PIXELS` chosen by its plane bits: `.` when off, `#` in
+    /// plane 1 only, `+` in plane 2 only and `@` in both
+    pub fn to_text(&self) -> String
+    {
+        let mut text = String::with_capacity((self.width() + 1) * self.height());
+        for row in self.memory.iter().take(self.height())
+        {
+            text.extend(row.iter().take(self.width()).map(|&pixel| TEXT_PIXELS[pixel as usize & 0x3]));
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Move the selected planes by (dx, dy) pixels. Pixels scrolled in from 
+    /// outside the display are off
+    fn scroll(&mut self, dx: isize, dy: isize)
+    {
+        let (w, h) = (self.width() as isize, self.height() as isize);
+        let mask = self.planes;
+        let old = self.memory;
+        for y in 0..h
+        {
+            for x in 0..w
+            {
+                let (src_x, src_y) = (x - dx, y - dy);
+                let src = if src_x >= 0 && src_x < w && src_y >= 0 && src_y < h
+                {
+                    old[src_y as usize][src_x as usize]
+                }
+                else
+                {
+                    0
+                };
+
+                let pixel = &mut self.memory[y as usize][x as usize];
+                *pixel = (*pixel & !mask) | (src & mask);
+            }
+        }
+    }
+
+    /// Split the rows of a sprite between the selected planes and draw each part
+    fn draw_planes(&mut self, x: usize, y: usize, rows: &[u16], width: usize, wrap: bool) -> bool
+    {
+        let count = self.selected_plane_count();
+        if count == 0
+        {
+            return false;
+        }
+
+        let rows_per_plane = rows.len() / count;
         let mut collision = false;
-        let h = sprite.len();
-        for j in 0..h
+        let mut part = rows.chunks(rows_per_plane.max(1));
+        for plane in 0..PLANE_COUNT
         {
-            for i in 0..8
+            let bit = 1 << plane;
+            if self.planes & bit == 0
             {
-                let ypos = (y + j) % DISPLAY_HEIGHT as usize;
-                let xpos = (x + i) % DISPLAY_WIDTH as usize;
-                if (sprite[j] & (0x80 >> i)) != 0x00
+                continue;
+            }
+
+            if let Some(plane_rows) = part.next()
+            {
+                collision |= self.draw_rows(x, y, plane_rows, width, wrap, bit);
+            }
+        }
+
+        collision
+    }
+
+    /// XOR rows of a sprite `width` pixels wide onto a single plane. The most 
+    /// significant bit of each row is the leftmost pixel
+    fn draw_rows(&mut self, x: usize, y: usize, rows: &[u16], width: usize, wrap: bool, plane: u8) -> bool
+    {
+        let (w, h) = (self.width(), self.height());
+        let x = x % w;
+        let y = y % h;
+        let mut collision = false;
+        for (j, row) in rows.iter().enumerate()
+        {
+            for i in 0..width
+            {
+                if !wrap && (x + i >= w || y + j >= h)
+                {
+                    continue;
+                }
+
+                let ypos = (y + j) % h;
+                let xpos = (x + i) % w;
+                if (row & (0x8000 >> i)) != 0x00
                 {
-                    if self.memory[ypos][xpos] == 0x01
+                    if self.memory[ypos][xpos] & plane != 0
                     {
                         collision = true;
                     }
-                    self.memory[ypos][xpos] ^= 0x01;
+                    self.memory[ypos][xpos] ^= plane;
                 }
             }
         }
 
         collision
     }
-}
\ No newline at end of file
+}
+
+impl Default for Display
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
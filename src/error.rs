@@ -0,0 +1,78 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors raised while loading or running a program
+#[derive(Debug)]
+pub enum Chip8Error
+{
+    /// The ROM file could not be opened or read
+    RomIo { path: PathBuf, source: io::Error },
+
+    /// The ROM does not fit in program memory
+    RomTooLarge { path: PathBuf, size: usize, max: usize },
+
+    /// A program given directly rather than from a file does not fit in
+    /// program memory
+    ProgramTooLarge { size: usize, max: usize },
+
+    /// The opcode at pc is not a known instruction
+    UnknownOpcode { opcode: u16, pc: usize },
+
+    /// A subroutine call was made with all stack levels in use
+    StackOverflow { pc: usize },
+
+    /// A return was made with an empty stack
+    StackUnderflow { pc: usize },
+
+    /// The instruction at pc accessed len bytes of memory starting at addr,
+    /// which runs past the end of memory
+    MemoryOutOfBounds { pc: usize, addr: usize, len: usize },
+
+    /// The data is not a save state this version can read
+    InvalidSaveState(String),
+
+    /// The save state was made while running a different ROM
+    SaveStateRomMismatch,
+}
+
+impl fmt::Display for Chip8Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Chip8Error::RomIo { path, source } =>
+                write!(f, "Could not read ROM file \"{}\": {}", path.display(), source),
+            Chip8Error::RomTooLarge { path, size, max } =>
+                write!(f, "ROM file \"{}\" is {} bytes but at most {} bytes fit in memory", path.display(), size, max),
+            Chip8Error::ProgramTooLarge { size, max } =>
+                write!(f, "Program is {} bytes but at most {} bytes fit in memory", size, max),
+            Chip8Error::UnknownOpcode { opcode, pc } =>
+                write!(f, "Unknown opcode {:04X} at {:04X}", opcode, pc),
+            Chip8Error::StackOverflow { pc } =>
+                write!(f, "Stack overflow at {:04X}", pc),
+            Chip8Error::StackUnderflow { pc } =>
+                write!(f, "Stack underflow at {:04X}", pc),
+            Chip8Error::MemoryOutOfBounds { pc, addr, len } =>
+                write!(f, "Instruction at {:04X} accessed {} bytes at {:04X}, past the end of memory", pc, len, addr),
+            Chip8Error::InvalidSaveState(reason) =>
+                write!(f, "Invalid save state: {}", reason),
+            Chip8Error::SaveStateRomMismatch =>
+                write!(f, "Save state belongs to a different ROM"),
+        }
+    }
+}
+
+impl Error for Chip8Error
+{
+    fn source(&self) -> Option< &(dyn Error + 'static) >
+    {
+        match self
+        {
+            Chip8Error::RomIo { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Tokenizing and precedence climbing shared by the assembler's constant
+//! expressions and the debugger's conditions. Each defines a `Grammar` with
+//! its own operators and operands
+
+use std::marker::PhantomData;
+
+/// The operators and operands of an expression language
+pub(crate) trait Grammar: Sized
+{
+    type Op: Copy + 'static;
+    type Expr;
+
+    /// Binary operators by token, with their binding power. Longer tokens
+    /// must come first so `<=` is not read as `<`
+    const BINARY_OPS: &'static [(&'static str, Self::Op, u8)];
+
+    /// Single character tokens other than the binary operators
+    const PUNCTUATION: &'static str;
+
+    fn binary(op: Self::Op, lhs: Self::Expr, rhs: Self::Expr) -> Self::Expr;
+
+    /// Parses an operand: a number or name, or a prefix operator or bracket
+    /// and whatever it applies to
+    fn unary(parser: &mut Parser< Self >) -> Result< Self::Expr, String >;
+}
+
+pub(crate) struct Parser< G >
+{
+    tokens: Vec< String >,
+    pos: usize,
+    grammar: PhantomData< G >,
+}
+
+impl< G: Grammar > Parser< G >
+{
+    /// Parses the whole of text as one expression
+    pub fn parse(text: &str) -> Result< G::Expr, String >
+    {
+        let mut parser: Parser< G > = Parser { tokens: tokenize::< G >(text)?, pos: 0, grammar: PhantomData };
+        let expr = parser.binary(0)?;
+        match parser.tokens.get(parser.pos)
+        {
+            None => Ok(expr),
+            Some(token) => Err(format!("Unexpected \"{}\"", token))
+        }
+    }
+
+    /// The next token, which must exist
+    pub fn operand(&mut self) -> Result< String, String >
+    {
+        let token = self.tokens.get(self.pos).cloned().ok_or_else(|| "Unexpected end of expression".to_string())?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    pub fn expect(&mut self, expected: &str) -> Result< (), String >
+    {
+        match self.tokens.get(self.pos)
+        {
+            Some(token) if token == expected => {},
+            Some(token) => return Err(format!("Expected \"{}\", found \"{}\"", expected, token)),
+            None => return Err(format!("Expected \"{}\" at end of expression", expected))
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Parses operators binding tighter than min_power by precedence climbing
+    pub fn binary(&mut self, min_power: u8) -> Result< G::Expr, String >
+    {
+        let mut lhs = G::unary(self)?;
+        loop
+        {
+            let op = self.tokens.get(self.pos).and_then(|token| G::BINARY_OPS.iter().find(|(op, _, _)| op == token));
+            match op
+            {
+                Some(&(_, op, power)) if power > min_power =>
+                {
+                    self.pos += 1;
+                    let rhs = self.binary(power)?;
+                    lhs = G::binary(op, lhs, rhs);
+                },
+                _ => return Ok(lhs)
+            }
+        }
+    }
+}
+
+/// Splits an expression into words, operators and punctuation. A word is
+/// letters, digits, `_` and `.`, optionally after a `#` or `$` hex prefix
+fn tokenize< G: Grammar >(text: &str) -> Result< Vec< String >, String >
+{
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty()
+    {
+        let len = if rest.starts_with(|c: char| is_word(c) || c == '#' || c == '$')
+        {
+            1 + rest[1..].find(|c: char| !is_word(c)).unwrap_or(rest.len() - 1)
+        }
+        else if let Some(&(op, _, _)) = G::BINARY_OPS.iter().find(|(op, _, _)| rest.starts_with(op))
+        {
+            op.len()
+        }
+        else if rest.starts_with(|c| G::PUNCTUATION.contains(c))
+        {
+            1
+        }
+        else
+        {
+            return Err(format!("Unexpected character '{}'", rest.chars().next().unwrap()));
+        };
+
+        tokens.push(rest[..len].to_string());
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// Parses a decimal, 0x/#/$ hexadecimal or 0b binary number
+pub(crate) fn parse_number(token: &str) -> Option< i64 >
+{
+    let lower = token.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('#')).or_else(|| lower.strip_prefix('$'))
+    {
+        i64::from_str_radix(hex, 16).ok()
+    }
+    else if let Some(binary) = lower.strip_prefix("0b")
+    {
+        i64::from_str_radix(binary, 2).ok()
+    }
+    else
+    {
+        lower.parse().ok()
+    }
+}
@@ -0,0 +1,474 @@
+//! Animated GIF recording of the display. Frames are captured once per
+//! emulated frame and runs of identical frames are merged into one, so a
+//! mostly still screen costs little. The encoder is a minimal GIF89a writer
+//! with its own LZW compressor
+
+use crate::cpu::TIMER_CLOCK;
+use crate::display::{ Display, DISPLAY_WIDTH, DISPLAY_HEIGHT, HIRES_DISPLAY_WIDTH };
+use crate::palette::Palette;
+
+use std::collections::HashMap;
+
+/// Shortest frame delay in hundredths of a second. Most viewers show frames
+/// with a shorter delay for a tenth of a second instead
+const MIN_DELAY: u64 = 2;
+
+/// Bits per pixel index, enough for the four pixel values
+const MIN_CODE_SIZE: u8 = 2;
+
+/// Largest LZW code. The code table starts over when it is full
+const MAX_CODE: u16 = 4095;
+
+/// Largest scale that keeps a high resolution image within GIF's 16-bit width
+const MAX_SCALE: u32 = u16::MAX as u32 / HIRES_DISPLAY_WIDTH as u32;
+
+/// A screen shown for one or more frames in a row
+#[derive(Clone, Debug)]
+struct Frame
+{
+    width: usize,
+    height: usize,
+    /// Pixel values of the active screen, row by row
+    pixels: Vec< u8 >,
+    /// Number of 60 Hz frames the screen was shown for
+    frames: u64,
+}
+
+/// Records the display a frame at a time in emulated time, so a recording
+/// plays back at the speed of the program whatever speed the emulator ran at
+#[derive(Clone, Debug)]
+pub struct GifRecorder
+{
+    palette: Palette,
+    scale: u32,
+    frames: Vec< Frame >,
+}
+
+impl GifRecorder
+{
+    /// Records in the palette's colours with each low resolution pixel
+    /// drawn scale pixels square. The scale is limited to what fits in a GIF
+    pub fn new(palette: &Palette, scale: u32) -> Self
+    {
+        GifRecorder { palette: palette.clone(), scale: scale.clamp(1, MAX_SCALE), frames: Vec::new() }
+    }
+
+    /// Number of frames recorded so far, including merged ones
+    pub fn frames(&self) -> u64
+    {
+        self.frames.iter().map(|frame| frame.frames).sum()
+    }
+
+    /// Adds a frame of the display. Call after every frame
+    pub fn record_frame(&mut self, display: &Display)
+    {
+        let (width, height) = (display.width(), display.height());
+        let pixels: Vec< u8 > = display.memory.iter().take(height).flat_map(|row| row[..width].iter().copied()).collect();
+
+        match self.frames.last_mut()
+        {
+            Some(last) if last.width == width && last.pixels == pixels => last.frames += 1,
+            _ => self.frames.push(Frame { width, height, pixels, frames: 1 })
+        }
+    }
+
+    /// The recording so far as an animated GIF that loops forever. The image
+    /// is the size of the largest resolution recorded, with low resolution
+    /// frames scaled up to fill it
+    pub fn to_gif(&self) -> Vec< u8 >
+    {
+        let blank = Frame {
+            width: DISPLAY_WIDTH as usize,
+            height: DISPLAY_HEIGHT as usize,
+            pixels: vec![0; (DISPLAY_WIDTH * DISPLAY_HEIGHT) as usize],
+            frames: 1,
+        };
+        let frames: Vec< &Frame > = if self.frames.is_empty() { vec![&blank] } else { self.frames.iter().collect() };
+
+        let base_width = frames.iter().map(|frame| frame.width).max().unwrap();
+        let base_height = frames.iter().map(|frame| frame.height).max().unwrap();
+        let (width, height) = (base_width * self.scale as usize, base_height * self.scale as usize);
+
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&(width as u16).to_le_bytes());
+        gif.extend_from_slice(&(height as u16).to_le_bytes());
+        // Global colour table of 4 colours, 8 bits per channel
+        gif.extend_from_slice(&[0xF1, 0, 0]);
+        for &(r, g, b) in self.palette.colors.iter()
+        {
+            gif.extend_from_slice(&[r, g, b]);
+        }
+        // Loop forever
+        gif.extend_from_slice(&[0x21, 0xFF, 0x0B]);
+        gif.extend_from_slice(b"NETSCAPE2.0");
+        gif.extend_from_slice(&[0x03, 0x01, 0x00, 0x00, 0x00]);
+
+        for (frame, delay) in frame_delays(&frames)
+        {
+            let data = lzw_encode(&scale_pixels(frame, width / frame.width, height / frame.height));
+
+            // A delay too long for GIF's 16 bits is split over copies of the frame
+            let mut remaining = delay;
+            while remaining > 0
+            {
+                let part = remaining.min(u16::MAX as u64);
+                remaining -= part;
+
+                // Graphic control extension: leave the frame in place, then wait
+                gif.extend_from_slice(&[0x21, 0xF9, 0x04, 0x04]);
+                gif.extend_from_slice(&(part as u16).to_le_bytes());
+                gif.extend_from_slice(&[0x00, 0x00]);
+
+                // Image descriptor covering the whole image, with no local colour table
+                gif.push(0x2C);
+                gif.extend_from_slice(&[0, 0, 0, 0]);
+                gif.extend_from_slice(&(width as u16).to_le_bytes());
+                gif.extend_from_slice(&(height as u16).to_le_bytes());
+                gif.push(0x00);
+
+                gif.push(MIN_CODE_SIZE);
+                for block in data.chunks(255)
+                {
+                    gif.push(block.len() as u8);
+                    gif.extend_from_slice(block);
+                }
+                gif.push(0x00);
+            }
+        }
+
+        gif.push(0x3B);
+        gif
+    }
+}
+
+/// Pairs each frame with its delay in hundredths of a second. Delays are
+/// measured from the start of the recording so rounding does not add up.
+/// A frame too short to show is dropped and its time given to the next
+fn frame_delays< 'a >(frames: &[&'a Frame]) -> Vec< (&'a Frame, u64) >
+{
+    let centiseconds = |frames: u64| (frames * 100 + TIMER_CLOCK as u64 / 2) / TIMER_CLOCK as u64;
+
+    let mut delays = Vec::new();
+    let (mut elapsed, mut shown) = (0, 0);
+    for (i, &frame) in frames.iter().enumerate()
+    {
+        elapsed += frame.frames;
+        let end = centiseconds(elapsed);
+        let last = i == frames.len() - 1;
+        if end - shown >= MIN_DELAY || last
+        {
+            delays.push((frame, (end - shown).max(MIN_DELAY)));
+            shown = end;
+        }
+    }
+    delays
+}
+
+/// The pixels of a frame with each pixel repeated x_scale times across and
+/// y_scale times down
+fn scale_pixels(frame: &Frame, x_scale: usize, y_scale: usize) -> Vec< u8 >
+{
+    let mut pixels = Vec::with_capacity(frame.pixels.len() * x_scale * y_scale);
+    for row in frame.pixels.chunks(frame.width)
+    {
+        for _ in 0..y_scale
+        {
+            for &pixel in row.iter()
+            {
+                pixels.extend(std::iter::repeat_n(pixel & 0x3, x_scale));
+            }
+        }
+    }
+    pixels
+}
+
+/// Compresses pixel indices with GIF's variable code size LZW
+fn lzw_encode(indices: &[u8]) -> Vec< u8 >
+{
+    let clear_code: u16 = 1 << MIN_CODE_SIZE;
+    let end_code = clear_code + 1;
+
+    let mut writer = BitWriter::default();
+    let mut table: HashMap< (u16, u8), u16 > = HashMap::new();
+    let mut code_size = MIN_CODE_SIZE + 1;
+    let mut next_code = end_code + 1;
+
+    writer.write(clear_code, code_size);
+    let mut current: Option< u16 > = None;
+    for &index in indices.iter()
+    {
+        let prefix = match current
+        {
+            Some(prefix) => prefix,
+            None =>
+            {
+                current = Some(index as u16);
+                continue;
+            }
+        };
+
+        if let Some(&code) = table.get(&(prefix, index))
+        {
+            current = Some(code);
+            continue;
+        }
+
+        writer.write(prefix, code_size);
+        if next_code > MAX_CODE
+        {
+            writer.write(clear_code, code_size);
+            table.clear();
+            code_size = MIN_CODE_SIZE + 1;
+            next_code = end_code + 1;
+        }
+        else
+        {
+            // The decoder grows its code size one code later than it adds the entry
+            if next_code >= 1 << code_size
+            {
+                code_size += 1;
+            }
+            table.insert((prefix, index), next_code);
+            next_code += 1;
+        }
+        current = Some(index as u16);
+    }
+
+    if let Some(prefix) = current
+    {
+        writer.write(prefix, code_size);
+    }
+    writer.write(end_code, code_size);
+    writer.finish()
+}
+
+/// Packs codes into bytes, least significant bit first
+#[derive(Default)]
+struct BitWriter
+{
+    bytes: Vec< u8 >,
+    bits: u32,
+    count: u8,
+}
+
+impl BitWriter
+{
+    fn write(&mut self, code: u16, size: u8)
+    {
+        self.bits |= (code as u32) << self.count;
+        self.count += size;
+        while self.count >= 8
+        {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec< u8 >
+    {
+        if self.count > 0
+        {
+            self.bytes.push(self.bits as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// Reads codes packed least significant bit first
+    struct BitReader< 'a >
+    {
+        bytes: &'a [u8],
+        bit: usize,
+    }
+
+    impl BitReader< '_ >
+    {
+        fn read(&mut self, size: u8) -> u16
+        {
+            let mut code = 0;
+            for n in 0..size as usize
+            {
+                let byte = self.bytes[(self.bit + n) / 8];
+                code |= (((byte >> ((self.bit + n) % 8)) & 1) as u16) << n;
+            }
+            self.bit += size as usize;
+            code
+        }
+    }
+
+    /// A plain GIF LZW decoder, returning the indices and the number of
+    /// clear codes read
+    fn lzw_decode(data: &[u8]) -> (Vec< u8 >, usize)
+    {
+        let clear_code = 1 << MIN_CODE_SIZE;
+        let end_code = clear_code + 1;
+        let initial: Vec< Vec< u8 > > = (0..=end_code).map(|code| vec![code as u8]).collect();
+
+        let mut reader = BitReader { bytes: data, bit: 0 };
+        let (mut indices, mut clears) = (Vec::new(), 0);
+        let mut table = initial.clone();
+        let mut code_size = MIN_CODE_SIZE + 1;
+        let mut previous: Option< Vec< u8 > > = None;
+        loop
+        {
+            let code = reader.read(code_size) as usize;
+            if code == clear_code
+            {
+                clears += 1;
+                table = initial.clone();
+                code_size = MIN_CODE_SIZE + 1;
+                previous = None;
+                continue;
+            }
+            if code == end_code
+            {
+                return (indices, clears);
+            }
+
+            let entry = match (table.get(code), &previous)
+            {
+                (Some(entry), _) => entry.clone(),
+                (None, Some(previous)) if code == table.len() => [&previous[..], &previous[..1]].concat(),
+                _ => panic!("code {} is not in the table of {}", code, table.len())
+            };
+            indices.extend_from_slice(&entry);
+            if let Some(previous) = previous.filter(|_| table.len() <= MAX_CODE as usize)
+            {
+                table.push([&previous[..], &entry[..1]].concat());
+            }
+            if table.len() == 1 << code_size && code_size < 12
+            {
+                code_size += 1;
+            }
+            previous = Some(entry);
+        }
+    }
+
+    /// Splits a GIF made by `to_gif` into the delay and pixels of each frame,
+    /// checking its structure along the way
+    fn decode(gif: &[u8]) -> Vec< (u16, Vec< u8 >) >
+    {
+        let u16_at = |pos: usize| u16::from_le_bytes([gif[pos], gif[pos + 1]]);
+        assert_eq!(&gif[..6], b"GIF89a");
+        assert_eq!(gif[10], 0xF1);
+
+        let sub_blocks = |mut pos: usize| {
+            let mut data = Vec::new();
+            while gif[pos] != 0
+            {
+                data.extend_from_slice(&gif[pos + 1..pos + 1 + gif[pos] as usize]);
+                pos += 1 + gif[pos] as usize;
+            }
+            (data, pos + 1)
+        };
+
+        let mut frames = Vec::new();
+        let mut delay = 0;
+        let mut pos = 13 + 4 * 3;
+        loop
+        {
+            match gif[pos]
+            {
+                0x21 =>
+                {
+                    if gif[pos + 1] == 0xF9
+                    {
+                        delay = u16_at(pos + 4);
+                    }
+                    pos = sub_blocks(pos + 2).1;
+                },
+                0x2C =>
+                {
+                    assert_eq!((u16_at(pos + 5), u16_at(pos + 7)), (u16_at(6), u16_at(8)));
+                    assert_eq!(gif[pos + 10], MIN_CODE_SIZE);
+                    let (data, next) = sub_blocks(pos + 11);
+                    frames.push((delay, lzw_decode(&data).0));
+                    pos = next;
+                },
+                0x3B =>
+                {
+                    assert_eq!(pos, gif.len() - 1);
+                    return frames;
+                },
+                byte => panic!("unexpected block 0x{:02X} at {}", byte, pos)
+            }
+        }
+    }
+
+    #[test]
+    fn lzw_round_trips()
+    {
+        let mut seed = 1u32;
+        let noise: Vec< u8 > = (0..50_000).map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as u8 & 0x3
+        }).collect();
+        let inputs: [Vec< u8 >; 5] = [vec![], vec![3], vec![0; 10_000], (0..10_000).map(|n| (n % 4) as u8).collect(), noise];
+        for indices in inputs.iter()
+        {
+            let (decoded, clears) = lzw_decode(&lzw_encode(indices));
+            assert!(decoded == *indices, "{} indices", indices.len());
+            assert!(clears >= 1);
+        }
+
+        // Enough varied input fills the table, which starts over at code 4095
+        assert!(lzw_decode(&lzw_encode(&inputs[4])).1 > 1);
+    }
+
+    #[test]
+    fn header_and_frames()
+    {
+        let palette = Palette::default();
+        let mut recorder = GifRecorder::new(&palette, 2);
+        let mut display = Display::new();
+        for _ in 0..3
+        {
+            recorder.record_frame(&display);
+        }
+        display.memory[1][2] = 1;
+        recorder.record_frame(&display);
+        assert_eq!(recorder.frames(), 4);
+
+        let gif = recorder.to_gif();
+        assert_eq!(gif[6..10], [128, 0, 64, 0]);
+        for (n, &(r, g, b)) in palette.colors.iter().enumerate()
+        {
+            assert_eq!(gif[13 + n * 3..16 + n * 3], [r, g, b]);
+        }
+
+        let frames = decode(&gif);
+        assert_eq!(frames.iter().map(|&(delay, _)| delay).collect::< Vec< _ > >(), vec![5, 2]);
+        assert!(frames[0].1.iter().all(|&pixel| pixel == 0));
+        let lit: Vec< usize > = (0..frames[1].1.len()).filter(|&n| frames[1].1[n] == 1).collect();
+        assert_eq!(lit, vec![2 * 128 + 4, 2 * 128 + 5, 3 * 128 + 4, 3 * 128 + 5]);
+    }
+
+    #[test]
+    fn long_delays_are_split()
+    {
+        let mut recorder = GifRecorder::new(&Palette::default(), 1);
+        let display = Display::new();
+        // 12 minutes of one screen is 72000 hundredths of a second
+        for _ in 0..12 * 60 * TIMER_CLOCK as u64
+        {
+            recorder.record_frame(&display);
+        }
+
+        let delays: Vec< u16 > = decode(&recorder.to_gif()).into_iter().map(|(delay, _)| delay).collect();
+        assert_eq!(delays, vec![u16::MAX, (72_000 - u16::MAX as u32) as u16]);
+    }
+
+    #[test]
+    fn scale_fits_in_16_bits()
+    {
+        let recorder = GifRecorder::new(&Palette::default(), u32::MAX);
+        assert!(HIRES_DISPLAY_WIDTH as u64 * recorder.scale as u64 <= u16::MAX as u64);
+        assert_eq!(GifRecorder::new(&Palette::default(), 0).scale, 1);
+    }
+}
@@ -0,0 +1,177 @@
+//! Runs a ROM without a window for a fixed number of frames or cycles, then
+//! dumps the screen and registers. Used for batch jobs and CI
+
+use crate::cli::{ KeyPress, Options, RunLength };
+use crate::{ audio_recorder, gif_recorder, load_program, palettes, save_audio_recording, save_gif, save_screenshot };
+use chip8::cpu::CPU;
+use chip8::error::Chip8Error;
+use chip8::machine::{ instructions_per_frame, Machine };
+use chip8::persistence::PersistenceBuffer;
+use chip8::png;
+
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// Runs the ROM given in options, then writes the requested dumps. The dumps
+/// are written even if the program hits an error, to help find out why
+pub fn run(options: &Options) -> Result< (), String >
+{
+    let (palettes, selected) = palettes(options)?;
+    let mut cpu = CPU::new(options.quirks);
+    if let Some(seed) = options.seed
+    {
+        cpu.set_rng_seed(seed);
+    }
+    load_program(&mut cpu, &options.rom_path)?;
+
+    let palette = &palettes[selected];
+    let mut machine = Machine::new(cpu, options.timing);
+    let mut recorder = audio_recorder(options);
+    let mut persistence = PersistenceBuffer::new(options.persistence);
+    let mut gif = gif_recorder(options, palette);
+    let mut screenshots = Vec::new();
+    let result = run_machine(&mut machine, options, |machine| {
+        let (cpu, frame) = (&machine.cpu, machine.frames());
+        if let Some(recorder) = &mut recorder
+        {
+            recorder.record_frame(cpu);
+        }
+        persistence.update(&cpu.display, palette);
+        if let Some(gif) = &mut gif
+        {
+            gif.record_frame(&cpu.display);
+        }
+        if options.screenshot_frames.contains(&frame)
+        {
+            screenshots.push((frame, cpu.display.clone()));
+        }
+    });
+    let cpu = &machine.cpu;
+
+    for (frame, display) in screenshots.iter()
+    {
+        save_screenshot(options, display, palette, *frame)?;
+    }
+
+    // With no dump asked for, print the screen so the run shows something
+    let dump_display = match (&options.dump_display, &options.dump_registers)
+    {
+        (Some(path), _) => Some(path.as_path()),
+        (None, None) => Some(Path::new("-")),
+        (None, Some(_)) => None
+    };
+    if let Some(path) = dump_display
+    {
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+        {
+            write_output(path, &png::encode_pixels(cpu.display.width(), &persistence.colors(&cpu.display, palette), options.screenshot_scale))?;
+        }
+        else
+        {
+            write_output(path, cpu.display.to_text().as_bytes())?;
+        }
+    }
+    if let Some(path) = &options.dump_registers
+    {
+        write_output(path, registers_json(&machine).as_bytes())?;
+    }
+
+    if let Some(recorder) = &recorder
+    {
+        save_audio_recording(options, recorder)?;
+    }
+    if let (Some(gif), Some(path)) = (&gif, &options.record_gif)
+    {
+        save_gif(path, gif)?;
+    }
+
+    result.map_err(|e| e.to_string())
+}
+
+/// Runs frames until the run length is reached or the program exits,
+/// calling after_frame at the end of each
+fn run_machine< F: FnMut(&Machine) >(machine: &mut Machine, options: &Options, mut after_frame: F) -> Result< (), Chip8Error >
+{
+    loop
+    {
+        // Instructions left before the run is over
+        let remaining = match options.run_length
+        {
+            RunLength::Frames(frames) if machine.frames() >= frames => return Ok(()),
+            RunLength::Frames(_) => u64::MAX,
+            RunLength::Cycles(cycles) => cycles.saturating_sub(machine.cycles())
+        };
+        if remaining == 0 || machine.cpu.exited
+        {
+            return Ok(());
+        }
+
+        let frame = machine.frames();
+        set_keys(&mut machine.cpu, &options.key_presses, frame);
+
+        let mut ran = 0;
+        let instructions = instructions_per_frame(options.cpu_hz, frame);
+        machine.run_frame_with(instructions, |cpu| -> Result< bool, Chip8Error > {
+            if ran == remaining
+            {
+                return Ok(false);
+            }
+            cpu.cpu_cycle()?;
+            ran += 1;
+            Ok(true)
+        })?;
+
+        after_frame(machine);
+    }
+}
+
+/// Sets the keypad to the keys the script holds down during a frame
+fn set_keys(cpu: &mut CPU, key_presses: &[KeyPress], frame: u64)
+{
+    for key in 0..16
+    {
+        let down = key_presses.iter().any(|press| press.key == key && frame >= press.frame && frame < press.frame.saturating_add(press.frames));
+        let was_down = cpu.keypad.get_key_state(key);
+        cpu.keypad.set_key_state(key, down);
+        if down && !was_down && cpu.is_waiting_for_key()
+        {
+            cpu.stop_waiting_for_key(key);
+        }
+    }
+}
+
+/// The registers, stack and timers as a JSON object
+fn registers_json(machine: &Machine) -> String
+{
+    let cpu = &machine.cpu;
+    let list = |values: &mut dyn Iterator< Item = u64 >| values.map(|v| v.to_string()).collect::< Vec< _ > >().join(", ");
+
+    let mut json = String::new();
+    writeln!(json, "{{").unwrap();
+    writeln!(json, "  \"pc\": {},", cpu.pc()).unwrap();
+    writeln!(json, "  \"i\": {},", cpu.i()).unwrap();
+    writeln!(json, "  \"v\": [{}],", list(&mut cpu.v().iter().map(|&v| v as u64))).unwrap();
+    writeln!(json, "  \"sp\": {},", cpu.sp).unwrap();
+    writeln!(json, "  \"stack\": [{}],", list(&mut cpu.stack.iter().take(cpu.sp).map(|&frame| frame as u64))).unwrap();
+    writeln!(json, "  \"delay_timer\": {},", cpu.delay_timer).unwrap();
+    writeln!(json, "  \"sound_timer\": {},", cpu.sound_timer).unwrap();
+    writeln!(json, "  \"hires\": {},", cpu.display.is_hires()).unwrap();
+    writeln!(json, "  \"exited\": {},", cpu.exited).unwrap();
+    writeln!(json, "  \"frames\": {},", machine.frames()).unwrap();
+    writeln!(json, "  \"cycles\": {}", machine.cycles()).unwrap();
+    writeln!(json, "}}").unwrap();
+    json
+}
+
+/// Writes data to a file, or to stdout if the path is -
+fn write_output(path: &Path, data: &[u8]) -> Result< (), String >
+{
+    if path == Path::new("-")
+    {
+        use std::io::Write;
+        return std::io::stdout().write_all(data).map_err(|e| e.to_string());
+    }
+
+    fs::write(path, data).map_err(|e| format!("Could not write \"{}\": {}", path.display(), e))
+}
@@ -0,0 +1,326 @@
+//! Decoding of opcodes into instructions, shared by the CPU and the
+//! disassembler
+
+use std::fmt;
+use std::str::FromStr;
+
+/// The interpreter an instruction first appeared in. Each platform includes
+/// the instructions of the ones before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Platform
+{
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl FromStr for Platform
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result< Self, Self::Err >
+    {
+        match s.to_ascii_lowercase().as_str()
+        {
+            "chip8" => Ok(Platform::Chip8),
+            "schip" => Ok(Platform::SuperChip),
+            "xochip" => Ok(Platform::XoChip),
+            _ => Err(format!("Unknown platform \"{}\", expected chip8, schip or xochip", s))
+        }
+    }
+}
+
+impl fmt::Display for Platform
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Platform::Chip8 => write!(f, "chip8"),
+            Platform::SuperChip => write!(f, "schip"),
+            Platform::XoChip => write!(f, "xochip"),
+        }
+    }
+}
+
+/// A decoded CHIP-8, SUPER-CHIP or XO-CHIP instruction. Register operands are
+/// register numbers 0x0-0xF
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction
+{
+    /// 00CN: scroll the display down n pixels
+    ScrollDown(u8),
+    /// 00DN: scroll the display up n pixels
+    ScrollUp(u8),
+    /// 00E0: clear the display
+    Cls,
+    /// 00EE: return from a subroutine
+    Ret,
+    /// 00FB: scroll the display right 4 pixels
+    ScrollRight,
+    /// 00FC: scroll the display left 4 pixels
+    ScrollLeft,
+    /// 00FD: exit the interpreter
+    Exit,
+    /// 00FE: switch to low resolution
+    Low,
+    /// 00FF: switch to high resolution
+    High,
+    /// 1NNN: jump to nnn
+    Jp(u16),
+    /// 2NNN: call the subroutine at nnn
+    Call(u16),
+    /// 3XNN: skip if Vx == nn
+    SeVxNn(u8, u8),
+    /// 4XNN: skip if Vx != nn
+    SneVxNn(u8, u8),
+    /// 5XY0: skip if Vx == Vy
+    SeVxVy(u8, u8),
+    /// 5XY2: store Vx through Vy at I
+    SaveRange(u8, u8),
+    /// 5XY3: load Vx through Vy from I
+    LoadRange(u8, u8),
+    /// 6XNN: Vx = nn
+    LdVxNn(u8, u8),
+    /// 7XNN: Vx += nn
+    AddVxNn(u8, u8),
+    /// 8XY0: Vx = Vy
+    LdVxVy(u8, u8),
+    /// 8XY1: Vx |= Vy
+    Or(u8, u8),
+    /// 8XY2: Vx &= Vy
+    And(u8, u8),
+    /// 8XY3: Vx ^= Vy
+    Xor(u8, u8),
+    /// 8XY4: Vx += Vy with carry
+    AddVxVy(u8, u8),
+    /// 8XY5: Vx -= Vy with borrow
+    Sub(u8, u8),
+    /// 8XY6: shift right
+    Shr(u8, u8),
+    /// 8XY7: Vx = Vy - Vx with borrow
+    Subn(u8, u8),
+    /// 8XYE: shift left
+    Shl(u8, u8),
+    /// 9XY0: skip if Vx != Vy
+    SneVxVy(u8, u8),
+    /// ANNN: I = nnn
+    LdI(u16),
+    /// BNNN: jump to nnn plus V0 (or Vx with the jump quirk)
+    JpV0(u16),
+    /// CXNN: Vx = random & nn
+    Rnd(u8, u8),
+    /// DXYN: draw an n-row sprite, or a 16x16 sprite when n is 0
+    Drw(u8, u8, u8),
+    /// EX9E: skip if key Vx is pressed
+    Skp(u8),
+    /// EXA1: skip if key Vx is not pressed
+    Sknp(u8),
+    /// F000 NNNN: I = the 16-bit word that follows
+    LdILong,
+    /// FN01: select the drawing planes
+    Plane(u8),
+    /// F002: load the audio pattern from I
+    Audio,
+    /// FX07: Vx = delay timer
+    LdVxDt(u8),
+    /// FX0A: wait for a key press and store it in Vx
+    LdVxK(u8),
+    /// FX15: delay timer = Vx
+    LdDtVx(u8),
+    /// FX18: sound timer = Vx
+    LdStVx(u8),
+    /// FX1E: I += Vx
+    AddIVx(u8),
+    /// FX29: I = small font sprite for digit Vx
+    LdFVx(u8),
+    /// FX30: I = large font sprite for digit Vx
+    LdHfVx(u8),
+    /// FX33: store the BCD of Vx at I
+    LdBVx(u8),
+    /// FX3A: set the audio pitch to Vx
+    Pitch(u8),
+    /// FX55: store V0 through Vx at I
+    LdIVx(u8),
+    /// FX65: load V0 through Vx from I
+    LdVxI(u8),
+    /// FX75: save V0 through Vx to the RPL flags
+    LdRVx(u8),
+    /// FX85: load V0 through Vx from the RPL flags
+    LdVxR(u8),
+}
+
+impl Instruction
+{
+    /// Decodes an opcode, or returns None if it is not a known instruction.
+    /// The operand of F000 NNNN is in the following word and is not decoded
+    pub fn decode(opcode: u16) -> Option< Instruction >
+    {
+        use self::Instruction::*;
+
+        // Get the value of each nibble X, Y, Z, W from opcode 0xXYZW for easy matching
+        let op = (
+            ((opcode & 0xF000) >> 12) as u8,
+            ((opcode & 0x0F00) >> 8) as u8,
+            ((opcode & 0x00F0) >> 4) as u8,
+            (opcode & 0x000F) as u8
+        );
+        let nnn = opcode & 0x0FFF;
+        let nn = (opcode & 0x00FF) as u8;
+
+        // http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
+        let instruction = match op
+        {
+            (0x0, 0x0, 0xC, n) => ScrollDown(n),
+            (0x0, 0x0, 0xD, n) => ScrollUp(n),
+            (0x0, 0x0, 0xE, 0x0) => Cls,
+            (0x0, 0x0, 0xE, 0xE) => Ret,
+            (0x0, 0x0, 0xF, 0xB) => ScrollRight,
+            (0x0, 0x0, 0xF, 0xC) => ScrollLeft,
+            (0x0, 0x0, 0xF, 0xD) => Exit,
+            (0x0, 0x0, 0xF, 0xE) => Low,
+            (0x0, 0x0, 0xF, 0xF) => High,
+            (0x1, _, _, _) => Jp(nnn),
+            (0x2, _, _, _) => Call(nnn),
+            (0x3, x, _, _) => SeVxNn(x, nn),
+            (0x4, x, _, _) => SneVxNn(x, nn),
+            (0x5, x, y, 0x0) => SeVxVy(x, y),
+            (0x5, x, y, 0x2) => SaveRange(x, y),
+            (0x5, x, y, 0x3) => LoadRange(x, y),
+            (0x6, x, _, _) => LdVxNn(x, nn),
+            (0x7, x, _, _) => AddVxNn(x, nn),
+            (0x8, x, y, 0x0) => LdVxVy(x, y),
+            (0x8, x, y, 0x1) => Or(x, y),
+            (0x8, x, y, 0x2) => And(x, y),
+            (0x8, x, y, 0x3) => Xor(x, y),
+            (0x8, x, y, 0x4) => AddVxVy(x, y),
+            (0x8, x, y, 0x5) => Sub(x, y),
+            (0x8, x, y, 0x6) => Shr(x, y),
+            (0x8, x, y, 0x7) => Subn(x, y),
+            (0x8, x, y, 0xE) => Shl(x, y),
+            (0x9, x, y, 0x0) => SneVxVy(x, y),
+            (0xA, _, _, _) => LdI(nnn),
+            (0xB, _, _, _) => JpV0(nnn),
+            (0xC, x, _, _) => Rnd(x, nn),
+            (0xD, x, y, n) => Drw(x, y, n),
+            (0xE, x, 0x9, 0xE) => Skp(x),
+            (0xE, x, 0xA, 0x1) => Sknp(x),
+            (0xF, 0x0, 0x0, 0x0) => LdILong,
+            (0xF, n, 0x0, 0x1) => Plane(n),
+            (0xF, 0x0, 0x0, 0x2) => Audio,
+            (0xF, x, 0x0, 0x7) => LdVxDt(x),
+            (0xF, x, 0x0, 0xA) => LdVxK(x),
+            (0xF, x, 0x1, 0x5) => LdDtVx(x),
+            (0xF, x, 0x1, 0x8) => LdStVx(x),
+            (0xF, x, 0x1, 0xE) => AddIVx(x),
+            (0xF, x, 0x2, 0x9) => LdFVx(x),
+            (0xF, x, 0x3, 0x0) => LdHfVx(x),
+            (0xF, x, 0x3, 0x3) => LdBVx(x),
+            (0xF, x, 0x3, 0xA) => Pitch(x),
+            (0xF, x, 0x5, 0x5) => LdIVx(x),
+            (0xF, x, 0x6, 0x5) => LdVxI(x),
+            (0xF, x, 0x7, 0x5) => LdRVx(x),
+            (0xF, x, 0x8, 0x5) => LdVxR(x),
+
+            _ => return None
+        };
+
+        Some(instruction)
+    }
+
+    /// Encodes the instruction as an opcode, the inverse of `decode`. Operands
+    /// are masked to the width of their field
+    pub fn encode(self) -> u16
+    {
+        use self::Instruction::*;
+
+        let xy = |base: u16, x: u8, y: u8, n: u16| base | ((x as u16 & 0xF) << 8) | ((y as u16 & 0xF) << 4) | n;
+        let xnn = |base: u16, x: u8, nn: u8| base | ((x as u16 & 0xF) << 8) | nn as u16;
+        let x = |base: u16, x: u8| base | ((x as u16 & 0xF) << 8);
+
+        match self
+        {
+            ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+            ScrollUp(n) => 0x00D0 | (n as u16 & 0xF),
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            Low => 0x00FE,
+            High => 0x00FF,
+            Jp(nnn) => 0x1000 | (nnn & 0x0FFF),
+            Call(nnn) => 0x2000 | (nnn & 0x0FFF),
+            SeVxNn(vx, nn) => xnn(0x3000, vx, nn),
+            SneVxNn(vx, nn) => xnn(0x4000, vx, nn),
+            SeVxVy(vx, vy) => xy(0x5000, vx, vy, 0x0),
+            SaveRange(vx, vy) => xy(0x5000, vx, vy, 0x2),
+            LoadRange(vx, vy) => xy(0x5000, vx, vy, 0x3),
+            LdVxNn(vx, nn) => xnn(0x6000, vx, nn),
+            AddVxNn(vx, nn) => xnn(0x7000, vx, nn),
+            LdVxVy(vx, vy) => xy(0x8000, vx, vy, 0x0),
+            Or(vx, vy) => xy(0x8000, vx, vy, 0x1),
+            And(vx, vy) => xy(0x8000, vx, vy, 0x2),
+            Xor(vx, vy) => xy(0x8000, vx, vy, 0x3),
+            AddVxVy(vx, vy) => xy(0x8000, vx, vy, 0x4),
+            Sub(vx, vy) => xy(0x8000, vx, vy, 0x5),
+            Shr(vx, vy) => xy(0x8000, vx, vy, 0x6),
+            Subn(vx, vy) => xy(0x8000, vx, vy, 0x7),
+            Shl(vx, vy) => xy(0x8000, vx, vy, 0xE),
+            SneVxVy(vx, vy) => xy(0x9000, vx, vy, 0x0),
+            LdI(nnn) => 0xA000 | (nnn & 0x0FFF),
+            JpV0(nnn) => 0xB000 | (nnn & 0x0FFF),
+            Rnd(vx, nn) => xnn(0xC000, vx, nn),
+            Drw(vx, vy, n) => xy(0xD000, vx, vy, n as u16 & 0xF),
+            Skp(vx) => x(0xE09E, vx),
+            Sknp(vx) => x(0xE0A1, vx),
+            LdILong => 0xF000,
+            Plane(n) => x(0xF001, n),
+            Audio => 0xF002,
+            LdVxDt(vx) => x(0xF007, vx),
+            LdVxK(vx) => x(0xF00A, vx),
+            LdDtVx(vx) => x(0xF015, vx),
+            LdStVx(vx) => x(0xF018, vx),
+            AddIVx(vx) => x(0xF01E, vx),
+            LdFVx(vx) => x(0xF029, vx),
+            LdHfVx(vx) => x(0xF030, vx),
+            LdBVx(vx) => x(0xF033, vx),
+            Pitch(vx) => x(0xF03A, vx),
+            LdIVx(vx) => x(0xF055, vx),
+            LdVxI(vx) => x(0xF065, vx),
+            LdRVx(vx) => x(0xF075, vx),
+            LdVxR(vx) => x(0xF085, vx),
+        }
+    }
+
+    /// Size of the instruction in bytes, including any operand word
+    pub fn size(self) -> usize
+    {
+        match self
+        {
+            Instruction::LdILong => 4,
+            _ => 2
+        }
+    }
+
+    /// The first platform that has this instruction
+    pub fn platform(self) -> Platform
+    {
+        use self::Instruction::*;
+
+        match self
+        {
+            ScrollDown(_) | ScrollRight | ScrollLeft | Exit | Low | High | Drw(_, _, 0) | LdHfVx(_) | LdRVx(_) | LdVxR(_) => Platform::SuperChip,
+            ScrollUp(_) | SaveRange(..) | LoadRange(..) | LdILong | Plane(_) | Audio | Pitch(_) => Platform::XoChip,
+            _ => Platform::Chip8
+        }
+    }
+
+    /// True for the instructions that skip the next instruction on a condition
+    pub fn is_skip(self) -> bool
+    {
+        use self::Instruction::*;
+
+        matches!(self, SeVxNn(..) | SneVxNn(..) | SeVxVy(..) | SneVxVy(..) | Skp(_) | Sknp(_))
+    }
+}
@@ -1,6 +1,8 @@
-use sdl2::keyboard::Keycode;
-use std::collections::HashMap;
+use crate::error::Chip8Error;
+use crate::state::{ StateReader, StateWriter };
 
+/// State of the 16-key hexadecimal keypad, indexed by key value 0x0 to 0xF
+#[derive(Clone)]
 pub struct Keypad
 {
     keys: [bool; 16],
@@ -24,27 +26,31 @@ impl Keypad
     {
         self.keys[index] = state;
     }
+
+    /// Writes the key states to a save state as a bitmask
+    pub(crate) fn save_state(&self, w: &mut StateWriter)
+    {
+        let mask = self.keys.iter().enumerate().fold(0u16, |mask, (i, &down)| mask | (down as u16) << i);
+        w.u16(mask);
+    }
+
+    /// Restores the key states written by `save_state`
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result< (), Chip8Error >
+    {
+        let mask = r.u16()?;
+        for (i, key) in self.keys.iter_mut().enumerate()
+        {
+            *key = mask & (1 << i) != 0;
+        }
+
+        Ok(())
+    }
 }
 
-pub fn get_sdl_keybinds() -> HashMap< Keycode, usize >
+impl Default for Keypad
 {
-    let mut hm = HashMap::new();
-    hm.insert(Keycode::Num1, 0x1);
-    hm.insert(Keycode::Num2, 0x2);
-    hm.insert(Keycode::Num3, 0x3);
-    hm.insert(Keycode::Num4, 0xC);
-    hm.insert(Keycode::Q, 0x4);
-    hm.insert(Keycode::W, 0x5);
-    hm.insert(Keycode::E, 0x6);
-    hm.insert(Keycode::R, 0xD);
-    hm.insert(Keycode::A, 0x7);
-    hm.insert(Keycode::S, 0x8);
-    hm.insert(Keycode::D, 0x9);
-    hm.insert(Keycode::F, 0xE);
-    hm.insert(Keycode::Z, 0xA);
-    hm.insert(Keycode::X, 0x0);
-    hm.insert(Keycode::C, 0xB);
-    hm.insert(Keycode::V, 0xF);
-
-    hm
-}
\ No newline at end of file
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
@@ -0,0 +1,43 @@
+//! Core of the chip8-rs emulator.
+//!
+//! This crate has no dependency on any windowing or audio library. A frontend
+//! owns a `Machine`, feeds key state into its CPU's `keypad`, calls
+//! `run_frame` 60 times per emulated second, renders the CPU's `display` and
+//! plays samples from an `audio::Beeper` while the sound timer runs.
+
+#![allow(clippy::upper_case_acronyms)]
+
+extern crate rand;
+
+pub mod args;
+pub mod asm;
+pub mod audio;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod display;
+pub mod error;
+mod expr;
+pub mod gif;
+pub mod instruction;
+pub mod keypad;
+pub mod machine;
+pub mod octo;
+pub mod palette;
+pub mod persistence;
+pub mod png;
+pub mod quirks;
+pub mod rewind;
+mod state;
+pub mod timing;
+pub mod wav;
+
+pub use crate::cpu::CPU;
+pub use crate::debugger::Debugger;
+pub use crate::display::Display;
+pub use crate::error::Chip8Error;
+pub use crate::instruction::Instruction;
+pub use crate::keypad::Keypad;
+pub use crate::machine::Machine;
+pub use crate::quirks::Quirks;
+pub use crate::rewind::RewindBuffer;
@@ -0,0 +1,89 @@
+//! Runs a CPU a frame at a time. A frame is a batch of instructions followed
+//! by one tick of the 60 Hz timers, so emulated time is independent of how
+//! often the frontend calls in
+
+use crate::cpu::{ CPU, TIMER_CLOCK };
+use crate::error::Chip8Error;
+use crate::timing::{ self, Timing };
+
+/// A CPU and the frame scheduling around it
+pub struct Machine
+{
+    pub cpu: CPU,
+    pub timing: Timing,
+    frames: u64,
+    cycles: u64,
+}
+
+impl Machine
+{
+    pub fn new(cpu: CPU, timing: Timing) -> Self
+    {
+        Machine { cpu, timing, frames: 0, cycles: 0 }
+    }
+
+    /// Number of frames run so far
+    pub fn frames(&self) -> u64
+    {
+        self.frames
+    }
+
+    /// Number of instructions run so far
+    pub fn cycles(&self) -> u64
+    {
+        self.cycles
+    }
+
+    /// Runs one frame: instructions_per_frame instructions, then a timer tick.
+    /// With VIP timing the number of instructions is decided by their cost
+    /// instead. The frame ends early if the program exits
+    pub fn run_frame(&mut self, instructions_per_frame: u32) -> Result< (), Chip8Error >
+    {
+        self.run_frame_with(instructions_per_frame, |cpu| cpu.cpu_cycle().map(|_| true))
+    }
+
+    /// Like `run_frame`, but runs each instruction with step, which returns
+    /// whether it ran one. Returning false ends the frame early, e.g. at a
+    /// breakpoint; the timers still tick
+    pub fn run_frame_with< E, F >(&mut self, instructions_per_frame: u32, mut step: F) -> Result< (), E >
+        where F: FnMut(&mut CPU) -> Result< bool, E >
+    {
+        let cycles = &mut self.cycles;
+        let mut counted_step = |cpu: &mut CPU| -> Result< bool, E > {
+            let ran = step(cpu)?;
+            if ran
+            {
+                *cycles += 1;
+            }
+            Ok(ran)
+        };
+
+        match self.timing
+        {
+            Timing::Fixed =>
+            {
+                for _ in 0..instructions_per_frame
+                {
+                    if self.cpu.exited || !counted_step(&mut self.cpu)?
+                    {
+                        break;
+                    }
+                }
+                self.cpu.update_cpu_timers();
+            },
+            Timing::Vip => timing::run_vip_frame(&mut self.cpu, counted_step)?
+        }
+
+        self.frames += 1;
+        Ok(())
+    }
+}
+
+/// Instructions to run in a frame to average cpu_hz instructions a second.
+/// When cpu_hz is not a multiple of 60 the remainder is spread evenly over
+/// the frames of each second
+pub fn instructions_per_frame(cpu_hz: u32, frame: u64) -> u32
+{
+    let (cpu_hz, timer_hz) = (cpu_hz as u64, TIMER_CLOCK as u64);
+    ((frame + 1) * cpu_hz / timer_hz - frame * cpu_hz / timer_hz) as u32
+}
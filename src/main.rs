@@ -1,166 +1,156 @@
+extern crate chip8;
+#[cfg(feature = "sdl")]
 extern crate sdl2;
-extern crate rand;
-extern crate time;
-
-mod cpu;
-mod display;
-mod keypad;
-
-use crate::cpu::CPU;
-use crate::display::{ 
-    DISPLAY_WIDTH, 
-    DISPLAY_HEIGHT, 
-    DISPLAY_PIXEL_SCALE, 
-    DISPLAY_COLOR_PIXEL_ON, 
-    DISPLAY_COLOR_PIXEL_OFF 
-};
-
-use sdl2::pixels::Color;
-use sdl2::rect::Rect;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::render::WindowCanvas;
-use std::thread;
-use time::{ Duration, SteadyTime };
-
-fn main() -> Result< (), String >
+
+mod cli;
+mod headless;
+#[cfg(feature = "sdl")]
+mod window;
+
+use crate::cli::{ Command, Options };
+use chip8::audio::{ AudioRecorder, Beeper, DEFAULT_SAMPLE_RATE };
+use chip8::cpu::CPU;
+use chip8::display::Display;
+use chip8::gif::GifRecorder;
+use chip8::octo;
+use chip8::palette::{ self, Palette };
+use chip8::png;
+
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::process;
+
+fn main()
 {
-    // Initialize SDL
-    let sdl_context = sdl2::init()?;
-    let video_subsys = sdl_context.video()?;
-    let mut timer_subsys = sdl_context.timer()?;
-
-    // Create the SDL window
-    let window = video_subsys
-        .window("chip8-rs", display::DISPLAY_WIDTH as u32 * display::DISPLAY_PIXEL_SCALE as u32, display::DISPLAY_HEIGHT as u32 * display::DISPLAY_PIXEL_SCALE as u32)
-        .opengl()
-        .position_centered()
-        .build()
-        .map_err(|e| e.to_string())?;
-
-    // Create the SDL drawing canvas and texture
-    let mut canvas = window
-        .into_canvas()
-        .accelerated()
-        .build()
-        .map_err(|e| e.to_string())?;
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.clear();
-    canvas.present();
-
-    // Create input stuff
-    let mut event_pump = sdl_context.event_pump().map_err(|e| e.to_string())?;
-    let key_binds = keypad::get_sdl_keybinds();
-
-    // Time handling
-    let mut time = SteadyTime::now();
-    let mut last_cpu_time = SteadyTime::now();
-    let mut last_timers_time = time;
-    let timers_step = Duration::nanoseconds(10i64.pow(9) / (cpu::TIMER_CLOCK as i64));
-    let cpu_step = Duration::nanoseconds(10i64.pow(9) / (cpu::CPU_CLOCK as i64));
-
-    // Framerate handling
-    let fps = 60.0;
-    let mut fps_time = timer_subsys.ticks();
-    let mut prev_fps_time;
-    let mut dt;
-    let mut update_timer = 0.0;
-    let max_dt = 1000.0 / fps;
-
-    // Create the Chip-8 CPU & load a rom
-    let mut cpu = cpu::CPU::new();
-    cpu.load_rom(std::path::Path::new("ROMS/PONG.ch8"));
-
-    // Main application loop
-    'running: loop
+    let options = match cli::parse_args(std::env::args().skip(1))
     {
-        // Update FPS time variables
-        prev_fps_time = fps_time;
-        fps_time = timer_subsys.ticks();
-        dt = fps_time - prev_fps_time;
-
-        // Handle SDL events
-        for event in event_pump.poll_iter()
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) =>
         {
-            match event
-            {
-                // Quit events
-                Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => break 'running,
-
-                // Keydown events
-                Event::KeyDown { keycode, .. } => 
-                {
-                    // Send the key down event to the CPU
-                    if let Some(value) = key_binds.get(&keycode.unwrap())
-                    {
-                        let key = *value;
-                        cpu.keypad.set_key_state(key, true);
-                        if cpu.is_waiting_for_key()
-                        {
-                            cpu.stop_waiting_for_key(key);
-                        }
-                    }
-                },
-
-                // Keyup events
-                Event::KeyUp { keycode, .. } =>
-                {
-                    if let Some(value) = key_binds.get(&keycode.unwrap())
-                    {
-                        let key = *value;
-                        cpu.keypad.set_key_state(key, false);
-                    }
-                },
-
-                _ => { continue; }
-            }
-        }
-
-        // CPU cycle
-        time = SteadyTime::now();
-        if time - last_cpu_time >= cpu_step
+            println!("{}", cli::USAGE);
+            return;
+        },
+        Err(e) =>
         {
-            last_cpu_time = time;
-            cpu.cpu_cycle();
+            eprintln!("chip8-rs: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
         }
+    };
 
-        // Update CPU timers
-        time = SteadyTime::now();
-        if time - last_timers_time >= timers_step
-        {
-            last_timers_time = time;
-            cpu.update_cpu_timers();
-        }
+    let result = if options.headless { headless::run(&options) } else { run_window(&options) };
+    if let Err(e) = result
+    {
+        eprintln!("chip8-rs: {}", e);
+        process::exit(1);
+    }
+}
 
-        // Render
-        draw_display(&mut canvas, &mut cpu);
-        while update_timer >= max_dt
-        {
-            update_timer -= max_dt;
-            canvas.present();
-        }
-        update_timer += dt as f32;
+#[cfg(feature = "sdl")]
+fn run_window(options: &Options) -> Result< (), String >
+{
+    window::run(options)
+}
 
-        // Avoid overloading CPU by sleeping thread
-        thread::sleep(::std::time::Duration::from_millis(1));
+#[cfg(not(feature = "sdl"))]
+fn run_window(_options: &Options) -> Result< (), String >
+{
+    Err("This build has no SDL support, so only --headless is available".to_string())
+}
+
+/// Loads the ROM into memory, compiling it first if it is Octo source
+fn load_program(cpu: &mut CPU, rom_path: &Path) -> Result< (), String >
+{
+    if rom_path.extension().is_some_and(|ext| ext == "8o")
+    {
+        let program = octo::compile_file(rom_path).map_err(|e| e.to_string())?;
+        cpu.load_program(&program).map_err(|e| e.to_string())
+    }
+    else
+    {
+        cpu.load_rom(rom_path).map_err(|e| e.to_string())
     }
+}
+
+/// A beeper making the sound chosen in options
+fn beeper(options: &Options, sample_rate: u32) -> Beeper
+{
+    let mut beeper = Beeper::new(sample_rate);
+    beeper.frequency = options.beep_hz;
+    beeper.volume = options.volume;
+    beeper.waveform = options.waveform;
+    beeper
+}
 
-    Ok(())
+/// A recorder for --record-audio, if it was given
+fn audio_recorder(options: &Options) -> Option< AudioRecorder >
+{
+    options.record_audio.as_ref().map(|_| AudioRecorder::new(beeper(options, DEFAULT_SAMPLE_RATE)))
 }
 
-fn draw_display(canvas: &mut WindowCanvas, cpu: &mut CPU)
+/// Writes the recording made for --record-audio
+fn save_audio_recording(options: &Options, recorder: &AudioRecorder) -> Result< (), String >
 {
-    canvas.set_draw_color(DISPLAY_COLOR_PIXEL_OFF);
-    canvas.clear();
-    canvas.set_draw_color(DISPLAY_COLOR_PIXEL_ON);
-    for y in 0..DISPLAY_HEIGHT as i32
+    match &options.record_audio
     {
-        for x in 0..DISPLAY_WIDTH as i32
-        {
-            if cpu.display.memory[y as usize][x as usize] == 1u8
-            {
-                canvas.fill_rect(Rect::new(x * DISPLAY_PIXEL_SCALE, y * DISPLAY_PIXEL_SCALE, DISPLAY_PIXEL_SCALE as u32, DISPLAY_PIXEL_SCALE as u32)).unwrap();
-            }
-        }
+        Some(path) => fs::write(path, recorder.to_wav()).map_err(|e| format!("Could not write \"{}\": {}", path.display(), e)),
+        None => Ok(())
+    }
+}
+
+/// Path in the screenshot directory for a capture starting at the given
+/// frame, named after the ROM and the frame
+fn capture_path(options: &Options, frame: u64, extension: &str) -> PathBuf
+{
+    let name = options.rom_path.file_stem().map_or("screenshot".into(), |stem| stem.to_string_lossy());
+    options.screenshot_dir.join(format!("{}-{:06}.{}", name, frame, extension))
+}
+
+/// Writes a screenshot of the display, taken at the given frame, to the
+/// screenshot directory
+fn save_screenshot(options: &Options, display: &Display, palette: &Palette, frame: u64) -> Result< PathBuf, String >
+{
+    let path = capture_path(options, frame, "png");
+    fs::write(&path, png::encode_display(display, palette, options.screenshot_scale))
+        .map_err(|e| format!("Could not write screenshot \"{}\": {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// A recorder for --record-gif, if it was given
+fn gif_recorder(options: &Options, palette: &Palette) -> Option< GifRecorder >
+{
+    options.record_gif.as_ref().map(|_| GifRecorder::new(palette, options.screenshot_scale))
+}
+
+/// Writes a GIF recording
+fn save_gif(path: &Path, recorder: &GifRecorder) -> Result< (), String >
+{
+    fs::write(path, recorder.to_gif()).map_err(|e| format!("Could not write \"{}\": {}", path.display(), e))
+}
+
+/// The palettes to choose from: the built-in ones followed by those from
+/// --palette-file. Also returns the index of the one picked by --palette,
+/// which is added to the list if it is a list of colours
+fn palettes(options: &Options) -> Result< (Vec< Palette >, usize), String >
+{
+    let mut palettes = Palette::builtin();
+    if let Some(path) = &options.palette_file
+    {
+        let text = fs::read_to_string(path).map_err(|e| format!("Could not read \"{}\": {}", path.display(), e))?;
+        palettes.extend(palette::parse_file(&text).map_err(|e| format!("{}: {}", path.display(), e))?);
+    }
+
+    let spec = match &options.palette
+    {
+        Some(spec) => spec,
+        None => return Ok((palettes, 0))
+    };
+
+    // Later palettes win, so a palette file can redefine a built-in one
+    if let Some(index) = palettes.iter().rposition(|palette| palette.name.eq_ignore_ascii_case(spec))
+    {
+        return Ok((palettes, index));
     }
+    palettes.push(spec.parse::< Palette >().map_err(|e| format!("--palette: {}", e))?);
+    let index = palettes.len() - 1;
+    Ok((palettes, index))
 }
@@ -0,0 +1,1131 @@
+//! Compiler for Octo, the high level assembly language most modern CHIP-8
+//! programs are written in.
+//!
+//! ```text
+//! :alias x v0
+//! :const SPEED 2
+//! : main
+//!     x := 0
+//!     loop
+//!         x += SPEED
+//!         if x == 64 then x := 0
+//!     again
+//! ```
+//!
+//! Source is compiled in a single pass. References to labels that are not
+//! defined yet are patched once the whole file has been read. Supported
+//! directives are `:`, `:alias`, `:const`, `:calc`, `:macro`, `:org`,
+//! `:next`, `:byte`, `:call` and `:unpack`, along with the structured
+//! `if ... then`, `if ... begin ... else ... end`, `loop ... while ... again`.
+//! `:calc` expressions are evaluated right to left with no precedence, as in
+//! Octo itself.
+
+use crate::asm::AsmError;
+use crate::cpu::{ MEMORY_SIZE, PROGRAM_START };
+use crate::expr;
+use crate::instruction::Instruction;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Most macro expansions in one program, to stop runaway recursive macros
+const MAX_MACRO_EXPANSIONS: usize = 100_000;
+
+/// The flag register, which comparisons other than `==` and `!=` use
+const VF: u8 = 0xF;
+
+/// Compiles Octo source into a program to be loaded at 0x200
+pub fn compile(source: &str) -> Result< Vec< u8 >, AsmError >
+{
+    Compiler::new("<input>", source).run()
+}
+
+/// Compiles an Octo source file into a program to be loaded at 0x200
+pub fn compile_file(path: &Path) -> Result< Vec< u8 >, AsmError >
+{
+    let name = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|e| AsmError { file: name.clone(), line: 0, message: e.to_string() })?;
+    Compiler::new(&name, &source).run()
+}
+
+#[derive(Clone)]
+struct Token
+{
+    text: String,
+    line: usize,
+}
+
+/// Splits source into whitespace separated tokens, dropping `#` comments
+fn tokenize(source: &str) -> Vec< Token >
+{
+    let mut tokens = Vec::new();
+    for (index, line) in source.lines().enumerate()
+    {
+        for word in line.split_whitespace()
+        {
+            if word.starts_with('#')
+            {
+                break;
+            }
+            tokens.push(Token { text: word.to_string(), line: index + 1 });
+        }
+    }
+    tokens
+}
+
+/// How a reference to a label is patched once the label's address is known
+#[derive(Clone, Copy)]
+enum FixupKind
+{
+    /// The low 12 bits of the opcode at the address
+    Addr12,
+
+    /// The whole 16-bit word at the address
+    Word,
+
+    /// The immediates of the `v0 := nn` `v1 := nn` pair `:unpack` writes,
+    /// with the nibble that goes above the address
+    Unpack(u8),
+}
+
+struct Fixup
+{
+    addr: usize,
+    kind: FixupKind,
+    label: String,
+    line: usize,
+}
+
+/// The right hand side of a comparison
+#[derive(Clone, Copy)]
+enum Operand
+{
+    Register(u8),
+    Byte(u8),
+}
+
+/// The condition of an `if` or `while`
+#[derive(Clone, Copy)]
+enum Condition
+{
+    Eq(u8, Operand),
+    Ne(u8, Operand),
+    Lt(u8, Operand),
+    Gt(u8, Operand),
+    Le(u8, Operand),
+    Ge(u8, Operand),
+    Key(u8),
+    NotKey(u8),
+}
+
+impl Condition
+{
+    fn negate(self) -> Condition
+    {
+        match self
+        {
+            Condition::Eq(x, rhs) => Condition::Ne(x, rhs),
+            Condition::Ne(x, rhs) => Condition::Eq(x, rhs),
+            Condition::Lt(x, rhs) => Condition::Ge(x, rhs),
+            Condition::Ge(x, rhs) => Condition::Lt(x, rhs),
+            Condition::Gt(x, rhs) => Condition::Le(x, rhs),
+            Condition::Le(x, rhs) => Condition::Gt(x, rhs),
+            Condition::Key(x) => Condition::NotKey(x),
+            Condition::NotKey(x) => Condition::Key(x),
+        }
+    }
+}
+
+/// An open control structure
+enum Block
+{
+    /// `if ... begin` or `else`, with the address of the jump that skips the
+    /// block and the line it started on
+    If { jump: usize, line: usize },
+
+    /// `loop`, with the address it starts at and the jumps out of it made by
+    /// `while`
+    Loop { start: usize, breaks: Vec< usize >, line: usize },
+}
+
+struct Macro
+{
+    args: Vec< String >,
+    body: Vec< Token >,
+}
+
+struct Compiler
+{
+    file: String,
+
+    /// Tokens still to read, in reverse so the next token is at the end
+    tokens: Vec< Token >,
+
+    /// Line of the last token read, for errors
+    line: usize,
+
+    /// The program, starting at PROGRAM_START
+    rom: Vec< u8 >,
+
+    /// Address the next byte is written to
+    pc: usize,
+
+    labels: HashMap< String, usize >,
+    constants: HashMap< String, f64 >,
+    aliases: HashMap< String, u8 >,
+    macros: HashMap< String, Macro >,
+    fixups: Vec< Fixup >,
+    blocks: Vec< Block >,
+
+    /// Label to define at the immediate of the next instruction, from `:next`
+    next_label: Option< String >,
+
+    macro_expansions: usize,
+}
+
+impl Compiler
+{
+    fn new(file: &str, source: &str) -> Self
+    {
+        let mut tokens = tokenize(source);
+        tokens.reverse();
+
+        Compiler {
+            file: file.to_string(),
+            tokens,
+            line: 0,
+            rom: Vec::new(),
+            pc: PROGRAM_START,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            next_label: None,
+            macro_expansions: 0,
+        }
+    }
+
+    fn error(&self, line: usize, message: String) -> AsmError
+    {
+        AsmError { file: self.file.clone(), line, message }
+    }
+
+    fn run(mut self) -> Result< Vec< u8 >, AsmError >
+    {
+        // Execution starts at `main`. Reserve a jump to it, which is dropped
+        // again if main turns out to be the first thing in the program
+        self.jump_to_label(FixupKind::Addr12, 0x1000, "main".to_string(), 0).map_err(|e| self.error(0, e))?;
+
+        while let Some(token) = self.tokens.pop()
+        {
+            self.line = token.line;
+            self.statement(&token.text).map_err(|e| self.error(self.line, e))?;
+        }
+
+        if let Some(block) = self.blocks.last()
+        {
+            let (kind, closer, line) = match block
+            {
+                Block::If { line, .. } => ("begin", "end", *line),
+                Block::Loop { line, .. } => ("loop", "again", *line),
+            };
+            return Err(self.error(line, format!("This {} has no matching {}", kind, closer)));
+        }
+
+        for fixup in self.fixups.iter()
+        {
+            let target = match self.labels.get(&fixup.label)
+            {
+                Some(&target) => target,
+                None if fixup.label == "main" => return Err(self.error(0, "The program has no \": main\" label".to_string())),
+                None => return Err(self.error(fixup.line, format!("Undefined label \"{}\"", fixup.label)))
+            };
+
+            let offset = fixup.addr - PROGRAM_START;
+            match fixup.kind
+            {
+                FixupKind::Addr12 =>
+                {
+                    if target > 0xFFF
+                    {
+                        return Err(self.error(fixup.line, format!("Label \"{}\" at 0x{:X} is out of reach of a 12-bit address; use i := long", fixup.label, target)));
+                    }
+                    self.rom[offset] = (self.rom[offset] & 0xF0) | (target >> 8) as u8;
+                    self.rom[offset + 1] = target as u8;
+                },
+                FixupKind::Word =>
+                {
+                    self.rom[offset] = (target >> 8) as u8;
+                    self.rom[offset + 1] = target as u8;
+                },
+                FixupKind::Unpack(nibble) =>
+                {
+                    self.rom[offset + 1] = (nibble << 4) | ((target >> 8) & 0xF) as u8;
+                    self.rom[offset + 3] = target as u8;
+                },
+            }
+        }
+
+        Ok(self.rom)
+    }
+
+    fn next(&mut self) -> Result< String, String >
+    {
+        match self.tokens.pop()
+        {
+            Some(token) =>
+            {
+                self.line = token.line;
+                Ok(token.text)
+            },
+            None => Err("Unexpected end of file".to_string())
+        }
+    }
+
+    fn peek(&self) -> Option< &str >
+    {
+        self.tokens.last().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result< (), String >
+    {
+        let token = self.next()?;
+        if token != expected
+        {
+            return Err(format!("Expected \"{}\", found \"{}\"", expected, token));
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: &str) -> Result< (), String >
+    {
+        use crate::instruction::Instruction::*;
+
+        match token
+        {
+            ":" =>
+            {
+                let name = self.next()?;
+                self.define_label(name)?;
+            },
+            ":alias" =>
+            {
+                let name = self.next()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            },
+            ":const" =>
+            {
+                let name = self.next()?;
+                let token = self.next()?;
+                let value = self.number(&token)?;
+                self.constants.insert(name, value as f64);
+            },
+            ":calc" =>
+            {
+                let name = self.next()?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+            },
+            ":macro" => self.define_macro()?,
+            ":org" =>
+            {
+                let token = self.next()?;
+                let addr = self.number(&token)?;
+                if addr < PROGRAM_START as i64 || addr > MEMORY_SIZE as i64
+                {
+                    return Err(format!(":org 0x{:X} is outside program memory", addr));
+                }
+                self.pc = addr as usize;
+            },
+            ":next" => self.next_label = Some(self.next()?),
+            ":byte" =>
+            {
+                let value = if self.peek() == Some("{") { self.calc()? as i64 } else { let token = self.next()?; self.number(&token)? };
+                self.byte(value)?;
+            },
+            ":call" =>
+            {
+                let token = self.next()?;
+                self.address(0x2000, &token)?;
+            },
+            ":unpack" =>
+            {
+                let token = self.next()?;
+                let nibble = self.number(&token)?;
+                if !(0..=0xF).contains(&nibble)
+                {
+                    return Err(format!(":unpack expects a nibble, got {}", nibble));
+                }
+                let label = self.next()?;
+                match self.labels.get(&label)
+                {
+                    Some(&target) =>
+                    {
+                        self.emit(LdVxNn(0, ((nibble as u8) << 4) | ((target >> 8) & 0xF) as u8))?;
+                        self.emit(LdVxNn(1, target as u8))?;
+                    },
+                    None =>
+                    {
+                        self.fixups.push(Fixup { addr: self.pc, kind: FixupKind::Unpack(nibble as u8), label, line: self.line });
+                        self.emit(LdVxNn(0, 0))?;
+                        self.emit(LdVxNn(1, 0))?;
+                    }
+                }
+            },
+            ":breakpoint" =>
+            {
+                self.next()?;
+            },
+            ";" | "return" => self.emit(Ret)?,
+            "clear" => self.emit(Cls)?,
+            "hires" => self.emit(High)?,
+            "lores" => self.emit(Low)?,
+            "exit" => self.emit(Exit)?,
+            "scroll-left" => self.emit(ScrollLeft)?,
+            "scroll-right" => self.emit(ScrollRight)?,
+            "audio" => self.emit(Audio)?,
+            "scroll-down" => { let n = self.nibble()?; self.emit(ScrollDown(n))? },
+            "scroll-up" => { let n = self.nibble()?; self.emit(ScrollUp(n))? },
+            "plane" => { let n = self.nibble()?; self.emit(Plane(n))? },
+            "bcd" => { let x = self.register()?; self.emit(LdBVx(x))? },
+            "saveflags" => { let x = self.register()?; self.emit(LdRVx(x))? },
+            "loadflags" => { let x = self.register()?; self.emit(LdVxR(x))? },
+            "save" | "load" =>
+            {
+                let x = self.register()?;
+                let range = if self.peek() == Some("-")
+                {
+                    self.next()?;
+                    Some(self.register()?)
+                }
+                else
+                {
+                    None
+                };
+                let instruction = match (token, range)
+                {
+                    ("save", None) => LdIVx(x),
+                    ("load", None) => LdVxI(x),
+                    ("save", Some(y)) => SaveRange(x, y),
+                    (_, Some(y)) => LoadRange(x, y),
+                    _ => unreachable!()
+                };
+                self.emit(instruction)?;
+            },
+            "sprite" =>
+            {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.emit(Drw(x, y, n))?;
+            },
+            "jump" => { let token = self.next()?; self.address(0x1000, &token)? },
+            "jump0" => { let token = self.next()?; self.address(0xB000, &token)? },
+            "delay" | "buzzer" | "pitch" =>
+            {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(match token { "delay" => LdDtVx(x), "buzzer" => LdStVx(x), _ => Pitch(x) })?;
+            },
+            "i" => self.assign_i()?,
+            "if" => self.conditional()?,
+            "else" =>
+            {
+                let (jump, line) = match self.blocks.pop()
+                {
+                    Some(Block::If { jump, line }) => (jump, line),
+                    _ => return Err("else without if ... begin".to_string())
+                };
+                let end = self.pc;
+                self.emit(Jp(0))?;
+                self.patch_jump(jump, self.pc);
+                self.blocks.push(Block::If { jump: end, line });
+            },
+            "end" =>
+            {
+                match self.blocks.pop()
+                {
+                    Some(Block::If { jump, .. }) => self.patch_jump(jump, self.pc),
+                    _ => return Err("end without if ... begin".to_string())
+                }
+            },
+            "loop" => self.blocks.push(Block::Loop { start: self.pc, breaks: Vec::new(), line: self.line }),
+            "while" =>
+            {
+                let condition = self.condition()?;
+                self.skip_unless(condition.negate())?;
+                let jump = self.pc;
+                self.emit(Jp(0))?;
+                match self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop { .. }))
+                {
+                    Some(Block::Loop { breaks, .. }) => breaks.push(jump),
+                    _ => return Err("while outside of a loop".to_string())
+                }
+            },
+            "again" =>
+            {
+                match self.blocks.pop()
+                {
+                    Some(Block::Loop { start, breaks, .. }) =>
+                    {
+                        self.emit(Jp(start as u16))?;
+                        for jump in breaks
+                        {
+                            self.patch_jump(jump, self.pc);
+                        }
+                    },
+                    _ => return Err("again without loop".to_string())
+                }
+            },
+            _ if self.register_of(token).is_some() => self.assign_register(token)?,
+            _ if self.macros.contains_key(token) => self.expand_macro(token)?,
+            _ =>
+            {
+                // A number or constant is a byte of data, anything else a call
+                match self.value_of(token)
+                {
+                    Some(value) => self.byte(value)?,
+                    None => self.address(0x2000, token)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: String) -> Result< (), String >
+    {
+        if self.labels.contains_key(&name)
+        {
+            return Err(format!("Label \"{}\" is already defined", name));
+        }
+
+        // Drop the reserved jump when main comes first
+        if name == "main" && self.labels.is_empty() && self.pc == PROGRAM_START + 2 && self.rom.len() == 2
+        {
+            self.rom.clear();
+            self.fixups.clear();
+            self.pc = PROGRAM_START;
+        }
+
+        self.labels.insert(name, self.pc);
+        Ok(())
+    }
+
+    fn define_macro(&mut self) -> Result< (), String >
+    {
+        let name = self.next()?;
+        let mut args = Vec::new();
+        loop
+        {
+            let token = self.next()?;
+            if token == "{"
+            {
+                break;
+            }
+            args.push(token);
+        }
+
+        let body = self.braced_tokens()?;
+        self.macros.insert(name, Macro { args, body });
+        Ok(())
+    }
+
+    /// Reads tokens up to the `}` matching an already read `{`
+    fn braced_tokens(&mut self) -> Result< Vec< Token >, String >
+    {
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop
+        {
+            let token = self.tokens.pop().ok_or_else(|| "Missing \"}\"".to_string())?;
+            match token.text.as_str()
+            {
+                "{" => depth += 1,
+                "}" =>
+                {
+                    depth -= 1;
+                    if depth == 0
+                    {
+                        return Ok(body);
+                    }
+                },
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result< (), String >
+    {
+        self.macro_expansions += 1;
+        if self.macro_expansions > MAX_MACRO_EXPANSIONS
+        {
+            return Err(format!("Too many macro expansions, is \"{}\" recursive?", name));
+        }
+
+        let arg_count = self.macros[name].args.len();
+        let mut values = Vec::new();
+        for _ in 0..arg_count
+        {
+            values.push(self.next()?);
+        }
+
+        let line = self.line;
+        let definition = &self.macros[name];
+        let expansion: Vec< Token > = definition.body.iter().map(|token| {
+            let text = match definition.args.iter().position(|arg| *arg == token.text)
+            {
+                Some(index) => values[index].clone(),
+                None => token.text.clone()
+            };
+            // Report errors in the expansion at the line that used the macro
+            Token { text, line }
+        }).collect();
+
+        self.tokens.extend(expansion.into_iter().rev());
+        Ok(())
+    }
+
+    /// `i := addr`, `i := long addr`, `i := hex vx`, `i := bighex vx` or `i += vx`
+    fn assign_i(&mut self) -> Result< (), String >
+    {
+        let op = self.next()?;
+        match op.as_str()
+        {
+            "+=" => { let x = self.register()?; self.emit(Instruction::AddIVx(x)) },
+            ":=" =>
+            {
+                let token = self.next()?;
+                match token.as_str()
+                {
+                    "hex" => { let x = self.register()?; self.emit(Instruction::LdFVx(x)) },
+                    "bighex" => { let x = self.register()?; self.emit(Instruction::LdHfVx(x)) },
+                    "long" =>
+                    {
+                        let token = self.next()?;
+                        self.emit(Instruction::LdILong)?;
+                        match self.value_of(&token)
+                        {
+                            Some(value) if (0..=0xFFFF).contains(&value) => self.word(value as u16),
+                            Some(value) => Err(format!("Address {} does not fit in 16 bits", value)),
+                            None =>
+                            {
+                                self.fixups.push(Fixup { addr: self.pc, kind: FixupKind::Word, label: token, line: self.line });
+                                self.word(0)
+                            }
+                        }
+                    },
+                    _ => self.address(0xA000, &token)
+                }
+            },
+            _ => Err(format!("Expected := or += after i, found \"{}\"", op))
+        }
+    }
+
+    /// Assignments and arithmetic on a register, e.g. `va := 3` or `va += vb`
+    fn assign_register(&mut self, target: &str) -> Result< (), String >
+    {
+        use crate::instruction::Instruction::*;
+
+        let x = self.register_of(target).unwrap();
+        let op = self.next()?;
+        let rhs = self.next()?;
+
+        if let Some(y) = self.register_of(&rhs)
+        {
+            let instruction = match op.as_str()
+            {
+                ":=" => LdVxVy(x, y),
+                "|=" => Or(x, y),
+                "&=" => And(x, y),
+                "^=" => Xor(x, y),
+                "+=" => AddVxVy(x, y),
+                "-=" => Sub(x, y),
+                "=-" => Subn(x, y),
+                ">>=" => Shr(x, y),
+                "<<=" => Shl(x, y),
+                _ => return Err(format!("Unknown operator \"{}\"", op))
+            };
+            return self.emit(instruction);
+        }
+
+        match (op.as_str(), rhs.as_str())
+        {
+            (":=", "delay") => self.emit(LdVxDt(x)),
+            (":=", "key") => self.emit(LdVxK(x)),
+            (":=", "random") =>
+            {
+                let mask = self.next()?;
+                let mask = self.number(&mask)?;
+                let mask = self.check_byte(mask)?;
+                self.emit(Rnd(x, mask))
+            },
+            (":=", _) => { let nn = self.number(&rhs)?; let nn = self.check_byte(nn)?; self.emit(LdVxNn(x, nn)) },
+            ("+=", _) => { let nn = self.number(&rhs)?; let nn = self.check_byte(nn)?; self.emit(AddVxNn(x, nn)) },
+            ("-=", _) => { let nn = self.number(&rhs)?; let nn = self.check_byte(nn)?; self.emit(AddVxNn(x, nn.wrapping_neg())) },
+            _ => Err(format!("\"{} {} {}\" is not a valid instruction", target, op, rhs))
+        }
+    }
+
+    /// `if condition then statement` or `if condition begin ...`
+    fn conditional(&mut self) -> Result< (), String >
+    {
+        let condition = self.condition()?;
+        let token = self.next()?;
+        match token.as_str()
+        {
+            "then" => self.skip_unless(condition),
+            "begin" =>
+            {
+                self.skip_unless(condition.negate())?;
+                self.blocks.push(Block::If { jump: self.pc, line: self.line });
+                self.emit(Instruction::Jp(0))
+            },
+            _ => Err(format!("Expected then or begin, found \"{}\"", token))
+        }
+    }
+
+    fn condition(&mut self) -> Result< Condition, String >
+    {
+        let x = self.register()?;
+        let op = self.next()?;
+        match op.as_str()
+        {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            _ => {}
+        }
+
+        let rhs = self.next()?;
+        let rhs = match self.register_of(&rhs)
+        {
+            Some(y) => Operand::Register(y),
+            None => { let nn = self.number(&rhs)?; Operand::Byte(self.check_byte(nn)?) }
+        };
+
+        match op.as_str()
+        {
+            "==" => Ok(Condition::Eq(x, rhs)),
+            "!=" => Ok(Condition::Ne(x, rhs)),
+            "<" => Ok(Condition::Lt(x, rhs)),
+            ">" => Ok(Condition::Gt(x, rhs)),
+            "<=" => Ok(Condition::Le(x, rhs)),
+            ">=" => Ok(Condition::Ge(x, rhs)),
+            _ => Err(format!("Unknown comparison \"{}\"", op))
+        }
+    }
+
+    /// Emits instructions that skip the next one unless the condition holds.
+    /// Ordering comparisons subtract into VF and test the borrow flag, which
+    /// is set when no borrow happened
+    fn skip_unless(&mut self, condition: Condition) -> Result< (), String >
+    {
+        use crate::instruction::Instruction::*;
+
+        match condition
+        {
+            Condition::Eq(x, Operand::Byte(nn)) => self.emit(SneVxNn(x, nn)),
+            Condition::Eq(x, Operand::Register(y)) => self.emit(SneVxVy(x, y)),
+            Condition::Ne(x, Operand::Byte(nn)) => self.emit(SeVxNn(x, nn)),
+            Condition::Ne(x, Operand::Register(y)) => self.emit(SeVxVy(x, y)),
+            Condition::Key(x) => self.emit(Sknp(x)),
+            Condition::NotKey(x) => self.emit(Skp(x)),
+            Condition::Lt(x, rhs) | Condition::Ge(x, rhs) | Condition::Gt(x, rhs) | Condition::Le(x, rhs) =>
+            {
+                match rhs
+                {
+                    Operand::Byte(nn) => self.emit(LdVxNn(VF, nn))?,
+                    Operand::Register(y) => self.emit(LdVxVy(VF, y))?,
+                }
+
+                // VF is now rhs. For < and >= compute x - rhs, so the flag is
+                // x >= rhs; for > and <= compute rhs - x, so it is rhs >= x
+                let subtract = match condition { Condition::Lt(..) | Condition::Ge(..) => Subn(VF, x), _ => Sub(VF, x) };
+                self.emit(subtract)?;
+
+                // The flag is clear exactly when < or > hold
+                match condition
+                {
+                    Condition::Lt(..) | Condition::Gt(..) => self.emit(SneVxNn(VF, 0)),
+                    _ => self.emit(SeVxNn(VF, 0)),
+                }
+            }
+        }
+    }
+
+    /// Parses a `{ ... }` calc expression
+    fn calc(&mut self) -> Result< f64, String >
+    {
+        self.expect("{")?;
+        let tokens: Vec< String > = self.braced_tokens()?.into_iter().map(|token| token.text).collect();
+        let mut pos = 0;
+        let value = self.calc_expr(&tokens, &mut pos)?;
+        match tokens.get(pos)
+        {
+            None => Ok(value),
+            Some(token) => Err(format!("Unexpected \"{}\" in :calc", token))
+        }
+    }
+
+    /// Octo evaluates calc expressions right to left without precedence, so
+    /// `2 * 3 + 1` is 8
+    fn calc_expr(&self, tokens: &[String], pos: &mut usize) -> Result< f64, String >
+    {
+        let lhs = self.calc_term(tokens, pos)?;
+        let op = match tokens.get(*pos)
+        {
+            Some(op) if op != ")" => op.clone(),
+            _ => return Ok(lhs)
+        };
+        *pos += 1;
+        let rhs = self.calc_expr(tokens, pos)?;
+
+        let (a, b) = (lhs as i64, rhs as i64);
+        let value = match op.as_str()
+        {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => a.wrapping_shl(b as u32) as f64,
+            ">>" => a.wrapping_shr(b as u32) as f64,
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "<" => (lhs < rhs) as i64 as f64,
+            ">" => (lhs > rhs) as i64 as f64,
+            "<=" => (lhs <= rhs) as i64 as f64,
+            ">=" => (lhs >= rhs) as i64 as f64,
+            "==" => (lhs == rhs) as i64 as f64,
+            "!=" => (lhs != rhs) as i64 as f64,
+            _ => return Err(format!("Unknown operator \"{}\" in :calc", op))
+        };
+        Ok(value)
+    }
+
+    fn calc_term(&self, tokens: &[String], pos: &mut usize) -> Result< f64, String >
+    {
+        let token = tokens.get(*pos).ok_or_else(|| "Unexpected end of :calc".to_string())?;
+        *pos += 1;
+
+        let unary = |f: fn(f64) -> f64, pos: &mut usize| self.calc_term(tokens, pos).map(f);
+        match token.as_str()
+        {
+            "(" =>
+            {
+                let value = self.calc_expr(tokens, pos)?;
+                match tokens.get(*pos)
+                {
+                    Some(token) if token == ")" =>
+                    {
+                        *pos += 1;
+                        Ok(value)
+                    },
+                    _ => Err("Expected \")\" in :calc".to_string())
+                }
+            },
+            "-" => unary(|v| -v, pos),
+            "~" => unary(|v| !(v as i64) as f64, pos),
+            "!" => unary(|v| (v == 0.0) as i64 as f64, pos),
+            "sin" => unary(f64::sin, pos),
+            "cos" => unary(f64::cos, pos),
+            "tan" => unary(f64::tan, pos),
+            "exp" => unary(f64::exp, pos),
+            "log" => unary(f64::ln, pos),
+            "abs" => unary(f64::abs, pos),
+            "sqrt" => unary(f64::sqrt, pos),
+            "sign" => unary(f64::signum, pos),
+            "ceil" => unary(f64::ceil, pos),
+            "floor" => unary(f64::floor, pos),
+            "@" =>
+            {
+                let addr = self.calc_term(tokens, pos)? as i64;
+                let offset = (addr - PROGRAM_START as i64) as usize;
+                Ok(self.rom.get(offset).map_or(0.0, |&b| b as f64))
+            },
+            "HERE" => Ok(self.pc as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ =>
+            {
+                if let Some(&addr) = self.labels.get(token)
+                {
+                    return Ok(addr as f64);
+                }
+                self.number(token).map(|n| n as f64)
+            }
+        }
+    }
+
+    /// Resolves a register name or alias
+    fn register_of(&self, token: &str) -> Option< u8 >
+    {
+        if let Some(&register) = self.aliases.get(token)
+        {
+            return Some(register);
+        }
+
+        let lower = token.to_ascii_lowercase();
+        if lower.len() == 2 && lower.starts_with('v')
+        {
+            u8::from_str_radix(&lower[1..], 16).ok()
+        }
+        else
+        {
+            None
+        }
+    }
+
+    fn register(&mut self) -> Result< u8, String >
+    {
+        let token = self.next()?;
+        self.register_of(&token).ok_or_else(|| format!("Expected a register, found \"{}\"", token))
+    }
+
+    /// The value of a number literal or constant
+    fn value_of(&self, token: &str) -> Option< i64 >
+    {
+        if let Some(&value) = self.constants.get(token)
+        {
+            return Some(value.floor() as i64);
+        }
+        if token.starts_with('#') || token.starts_with('$')
+        {
+            return None;
+        }
+
+        match token.strip_prefix('-')
+        {
+            Some(rest) => expr::parse_number(rest).map(|n| -n),
+            None => expr::parse_number(token)
+        }
+    }
+
+    fn number(&self, token: &str) -> Result< i64, String >
+    {
+        self.value_of(token).ok_or_else(|| format!("Expected a number, found \"{}\"", token))
+    }
+
+    fn nibble(&mut self) -> Result< u8, String >
+    {
+        let token = self.next()?;
+        let n = self.number(&token)?;
+        if !(0..=0xF).contains(&n)
+        {
+            return Err(format!("Value {} does not fit in 4 bits", n));
+        }
+        Ok(n as u8)
+    }
+
+    /// Bytes may be given as signed or unsigned values
+    fn check_byte(&self, value: i64) -> Result< u8, String >
+    {
+        if !(-128..=255).contains(&value)
+        {
+            return Err(format!("Value {} does not fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+
+    /// Emits an opcode with a 12-bit address operand, which may be a number,
+    /// a constant or a label
+    fn address(&mut self, base: u16, token: &str) -> Result< (), String >
+    {
+        if let Some(value) = self.value_of(token)
+        {
+            if !(0..=0xFFF).contains(&value)
+            {
+                return Err(format!("Address {} does not fit in 12 bits", value));
+            }
+            return self.emit_opcode(base | value as u16);
+        }
+
+        match self.labels.get(token)
+        {
+            Some(&addr) if addr > 0xFFF => Err(format!("Label \"{}\" at 0x{:X} is out of reach of a 12-bit address; use i := long", token, addr)),
+            Some(&addr) => self.emit_opcode(base | addr as u16),
+            None => self.jump_to_label(FixupKind::Addr12, base, token.to_string(), self.line)
+        }
+    }
+
+    /// Emits an opcode whose address is patched once the label is defined
+    fn jump_to_label(&mut self, kind: FixupKind, base: u16, label: String, line: usize) -> Result< (), String >
+    {
+        self.fixups.push(Fixup { addr: self.pc, kind, label, line });
+        self.emit_opcode(base)
+    }
+
+    /// Points the jump emitted at addr at target
+    fn patch_jump(&mut self, addr: usize, target: usize)
+    {
+        let offset = addr - PROGRAM_START;
+        let opcode = Instruction::Jp(target as u16).encode();
+        self.rom[offset..offset + 2].copy_from_slice(&opcode.to_be_bytes());
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> Result< (), String >
+    {
+        self.emit_opcode(instruction.encode())
+    }
+
+    fn emit_opcode(&mut self, opcode: u16) -> Result< (), String >
+    {
+        // `:next` labels the immediate operand in the low byte
+        if let Some(name) = self.next_label.take()
+        {
+            if self.labels.contains_key(&name)
+            {
+                return Err(format!("Label \"{}\" is already defined", name));
+            }
+            self.labels.insert(name, self.pc + 1);
+        }
+        self.word(opcode)
+    }
+
+    fn word(&mut self, value: u16) -> Result< (), String >
+    {
+        let [hi, lo] = value.to_be_bytes();
+        self.byte(hi as i64)?;
+        self.byte(lo as i64)
+    }
+
+    fn byte(&mut self, value: i64) -> Result< (), String >
+    {
+        let value = self.check_byte(value)?;
+        if self.pc >= MEMORY_SIZE
+        {
+            return Err(format!("Program does not fit in memory, which ends at 0x{:X}", MEMORY_SIZE));
+        }
+
+        let offset = self.pc - PROGRAM_START;
+        if self.rom.len() <= offset
+        {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = value;
+        self.pc += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::quirks::Quirks;
+
+    fn words(source: &str) -> Vec< u16 >
+    {
+        let rom = compile(source).unwrap_or_else(|e| panic!("{}", e));
+        rom.chunks(2).map(|word| u16::from_be_bytes([word[0], word[1]])).collect()
+    }
+
+    fn error(source: &str) -> String
+    {
+        compile(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn main_jump_is_dropped_when_main_comes_first()
+    {
+        assert_eq!(words(": main clear"), vec![0x00E0]);
+        assert_eq!(words(": sub return : main sub"), vec![0x1204, 0x00EE, 0x2202]);
+        assert!(error(": start clear").contains("no \": main\" label"));
+    }
+
+    #[test]
+    fn if_then()
+    {
+        assert_eq!(words(": main if v0 == 1 then v1 := 2"), vec![0x4001, 0x6102]);
+        assert_eq!(words(": main if v0 != v2 then v1 := 2"), vec![0x5020, 0x6102]);
+        assert_eq!(words(": main if v3 key then v1 := 2"), vec![0xE3A1, 0x6102]);
+        assert_eq!(words(": main if v3 -key then v1 := 2"), vec![0xE39E, 0x6102]);
+        assert!(error(": main if v0 == 1 v1 := 2").contains("Expected then or begin"));
+    }
+
+    #[test]
+    fn if_begin_else_end()
+    {
+        assert_eq!(words(": main if v0 == 1 begin v1 := 2 else v1 := 3 end"), vec![0x3001, 0x1208, 0x6102, 0x120A, 0x6103]);
+        assert_eq!(words(": main if v0 == 1 begin v1 := 2 end"), vec![0x3001, 0x1206, 0x6102]);
+
+        let message = error(": main\nif v0 == 1 begin\nv1 := 2");
+        assert!(message.contains(":2:") && message.contains("This begin has no matching end"), "{}", message);
+        assert!(error(": main end").contains("end without if ... begin"));
+    }
+
+    #[test]
+    fn loop_while_again()
+    {
+        assert_eq!(words(": main loop v0 += 1 while v0 != 5 again"), vec![0x7001, 0x4005, 0x1208, 0x1200]);
+
+        let message = error(": main\nloop\nv0 += 1");
+        assert!(message.contains(":2:") && message.contains("This loop has no matching again"), "{}", message);
+        assert!(error(": main again").contains("again without loop"));
+        assert!(error(": main while v0 == 1").contains("while outside of a loop"));
+    }
+
+    #[test]
+    fn ordering_comparisons()
+    {
+        assert_eq!(words(": main if v1 < v2 then v0 := 1"), vec![0x8F20, 0x8F17, 0x4F00, 0x6001]);
+        assert_eq!(words(": main if v1 > 3 then v0 := 1"), vec![0x6F03, 0x8F15, 0x4F00, 0x6001]);
+        assert_eq!(words(": main if v1 <= 3 then v0 := 1"), vec![0x6F03, 0x8F15, 0x3F00, 0x6001]);
+        assert_eq!(words(": main if v1 >= 3 then v0 := 1"), vec![0x6F03, 0x8F17, 0x3F00, 0x6001]);
+
+        // Run each comparison to check the flag logic, on both sides of equal
+        for &op in ["<", ">", "<=", ">="].iter()
+        {
+            for &(a, b) in [(1, 2), (2, 2), (3, 2), (0, 255), (255, 0)].iter()
+            {
+                let source = format!(": main v0 := {} v1 := {} if v0 {} v1 then v2 := 1 loop again", a, b, op);
+                let mut cpu = CPU::new(Quirks::default());
+                cpu.load_program(&compile(&source).unwrap()).unwrap();
+                for _ in 0..8
+                {
+                    cpu.cpu_cycle().unwrap();
+                }
+                let expected = match op { "<" => a < b, ">" => a > b, "<=" => a <= b, _ => a >= b };
+                assert_eq!(cpu.v()[2] == 1, expected, "{} {} {}", a, op, b);
+            }
+        }
+    }
+
+    #[test]
+    fn unpack_and_next()
+    {
+        assert_eq!(compile(": main :unpack 0xA data : data 0x12").unwrap(), vec![0x60, 0xA2, 0x61, 0x04, 0x12]);
+        assert_eq!(compile(": main jump go : data 1 2 : go :unpack 0 data").unwrap(), vec![0x12, 0x04, 0x01, 0x02, 0x60, 0x02, 0x61, 0x02]);
+        assert!(error(": main :unpack 16 data : data").contains(":unpack expects a nibble"));
+
+        // :next names the immediate byte of the next instruction
+        assert_eq!(words(": main :next target v0 := 5 i := target"), vec![0x6005, 0xA201]);
+    }
+
+    #[test]
+    fn calc_evaluates_right_to_left()
+    {
+        assert_eq!(words(":calc x { 2 * 3 + 1 } : main v0 := x"), vec![0x6008]);
+        assert_eq!(words(":calc x { 10 - 2 - 3 } : main v0 := x"), vec![0x600B]);
+        assert_eq!(words(":calc x { ( 2 * 3 ) + 1 } : main v0 := x"), vec![0x6007]);
+        assert_eq!(words(":const A 4 :calc x { A << 2 } : main v0 := x"), vec![0x6010]);
+        assert!(error(":calc x { 1 ? 2 }").contains("Unknown operator \"?\""));
+    }
+
+    #[test]
+    fn macros()
+    {
+        assert_eq!(words(":macro set reg val { reg := val } : main set v3 7 set v4 8"), vec![0x6307, 0x6408]);
+        assert!(error(":macro forever { forever } : main forever").contains("Too many macro expansions"));
+    }
+}
@@ -0,0 +1,189 @@
+//! Colour palettes for drawing the display. A palette has a colour for each
+//! pixel value: off, plane 1 only, plane 2 only and both planes
+
+use std::str::FromStr;
+
+/// An RGB colour
+pub type Rgb = (u8, u8, u8);
+
+/// A named set of four pixel colours
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette
+{
+    pub name: String,
+    pub colors: [Rgb; 4],
+}
+
+/// The built-in palettes, the first being the default
+const BUILTIN: [(&str, [Rgb; 4]); 5] = [
+    ("classic", [(0x00, 0x00, 0x00), (0xFF, 0xFF, 0xFF), (0x55, 0x55, 0x55), (0xAA, 0xAA, 0xAA)]),
+    ("green", [(0x0A, 0x14, 0x0A), (0x33, 0xFF, 0x66), (0x14, 0x66, 0x2E), (0x8C, 0xFF, 0xA8)]),
+    ("amber", [(0x1A, 0x0F, 0x00), (0xFF, 0xB0, 0x00), (0x80, 0x55, 0x00), (0xFF, 0xD8, 0x80)]),
+    ("lcd", [(0x9B, 0xBC, 0x0F), (0x0F, 0x38, 0x0F), (0x8B, 0xAC, 0x0F), (0x30, 0x62, 0x30)]),
+    ("octo", [(0x99, 0x66, 0x00), (0xFF, 0xCC, 0x00), (0xFF, 0x66, 0x00), (0x66, 0x22, 0x00)]),
+];
+
+impl Palette
+{
+    pub fn new(name: &str, colors: [Rgb; 4]) -> Self
+    {
+        Palette { name: name.to_string(), colors }
+    }
+
+    /// All the built-in palettes
+    pub fn builtin() -> Vec< Palette >
+    {
+        BUILTIN.iter().map(|&(name, colors)| Palette::new(name, colors)).collect()
+    }
+
+    /// The built-in palette with the given name, if there is one
+    pub fn named(name: &str) -> Option< Palette >
+    {
+        BUILTIN.iter()
+            .find(|(builtin, _)| builtin.eq_ignore_ascii_case(name))
+            .map(|&(name, colors)| Palette::new(name, colors))
+    }
+
+    /// The colour of a pixel value from `Display::memory`
+    pub fn color(&self, pixel: u8) -> Rgb
+    {
+        self.colors[pixel as usize & 0x3]
+    }
+}
+
+impl Default for Palette
+{
+    fn default() -> Self
+    {
+        let (name, colors) = BUILTIN[0];
+        Palette::new(name, colors)
+    }
+}
+
+/// Parses a built-in palette name, or a list of 2 or 4 hex colours separated
+/// by commas such as `#000000,#33FF66`
+impl FromStr for Palette
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result< Self, Self::Err >
+    {
+        if let Some(palette) = Palette::named(s)
+        {
+            return Ok(palette);
+        }
+
+        let names: Vec< &str > = BUILTIN.iter().map(|&(name, _)| name).collect();
+        let colors = parse_colors(s.split(',')).map_err(|e| format!("{}. Built-in palettes are {}", e, names.join(", ")))?;
+        Ok(Palette::new("custom", colors))
+    }
+}
+
+/// Parses palettes from a file with one palette per line, written as a name,
+/// a colon and 2 or 4 hex colours: `night: #000020 #8080FF`. Blank lines and
+/// lines starting with `;` are ignored
+pub fn parse_file(text: &str) -> Result< Vec< Palette >, String >
+{
+    let mut palettes = Vec::new();
+    for (number, line) in text.lines().enumerate()
+    {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';')
+        {
+            continue;
+        }
+
+        let error = |message: String| format!("line {}: {}", number + 1, message);
+        let (name, colors) = line.split_once(':').ok_or_else(|| error("expected name: colours".to_string()))?;
+        let name = name.trim();
+        if name.is_empty()
+        {
+            return Err(error("palette has no name".to_string()));
+        }
+        let colors = parse_colors(colors.split(|c: char| c == ',' || c.is_whitespace()).filter(|c| !c.is_empty())).map_err(error)?;
+        palettes.push(Palette::new(name, colors));
+    }
+
+    Ok(palettes)
+}
+
+/// Parses 2 or 4 colours. With 2, the colours of the second plane and of
+/// both planes are blended from the off and on colours
+fn parse_colors< 'a, I: Iterator< Item = &'a str > >(texts: I) -> Result< [Rgb; 4], String >
+{
+    let colors = texts.map(parse_color).collect::< Result< Vec< Rgb >, String > >()?;
+    match colors[..]
+    {
+        [off, on] => Ok([off, on, blend(off, on, 1), blend(off, on, 2)]),
+        [off, on, plane2, both] => Ok([off, on, plane2, both]),
+        _ => Err(format!("expected 2 or 4 colours, got {}", colors.len()))
+    }
+}
+
+/// Parses a colour written as RRGGBB, optionally prefixed with # or 0x
+fn parse_color(text: &str) -> Result< Rgb, String >
+{
+    let text = text.trim();
+    let hex = text.strip_prefix('#').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    match u32::from_str_radix(hex, 16)
+    {
+        Ok(rgb) if hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)),
+        _ => Err(format!("\"{}\" is not a colour, expected a hex colour like #33FF66", text))
+    }
+}
+
+/// The colour thirds of the way from a to b
+fn blend(a: Rgb, b: Rgb, thirds: i32) -> Rgb
+{
+    let mix = |a: u8, b: u8| (a as i32 + (b as i32 - a as i32) * thirds / 3) as u8;
+    (mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn named()
+    {
+        assert_eq!("Amber".parse::< Palette >().unwrap().name, "amber");
+        assert_eq!(Palette::default(), Palette::named("classic").unwrap());
+        assert_eq!(Palette::builtin().len(), BUILTIN.len());
+    }
+
+    #[test]
+    fn hex_colours()
+    {
+        let palette: Palette = "#000000, 0xFFFFFF".parse().unwrap();
+        assert_eq!(palette, Palette::new("custom", [(0, 0, 0), (0xFF, 0xFF, 0xFF), (0x55, 0x55, 0x55), (0xAA, 0xAA, 0xAA)]));
+
+        let palette: Palette = "102030,#405060,#708090,#A0b0C0".parse().unwrap();
+        assert_eq!(palette.colors, [(0x10, 0x20, 0x30), (0x40, 0x50, 0x60), (0x70, 0x80, 0x90), (0xA0, 0xB0, 0xC0)]);
+        assert_eq!(palette.color(0x7), (0xA0, 0xB0, 0xC0));
+    }
+
+    #[test]
+    fn invalid()
+    {
+        for text in ["sepia", "#000000", "#000000,#111111,#222222", "#000000,#FFFFF", "#000000,+FFFFF", "#000000,#GGGGGG"].iter()
+        {
+            let error = text.parse::< Palette >().unwrap_err();
+            assert!(error.contains("Built-in palettes are classic"), "{}: {}", text, error);
+        }
+    }
+
+    #[test]
+    fn file()
+    {
+        let palettes = parse_file("; comment\n\nnight: #000020 #8080FF\nfour: 000000, 111111 222222 333333\n").unwrap();
+        assert_eq!(palettes.len(), 2);
+        assert_eq!(palettes[0].name, "night");
+        assert_eq!(palettes[0].colors[..2], [(0, 0, 0x20), (0x80, 0x80, 0xFF)]);
+        assert_eq!(palettes[1].colors[3], (0x33, 0x33, 0x33));
+
+        assert_eq!(parse_file("night #000020 #8080FF").unwrap_err(), "line 1: expected name: colours");
+        assert_eq!(parse_file("\n : #000020 #8080FF").unwrap_err(), "line 2: palette has no name");
+        assert!(parse_file("night: #000020").unwrap_err().starts_with("line 1: expected 2 or 4 colours, got 1"));
+    }
+}
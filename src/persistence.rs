@@ -0,0 +1,221 @@
+//! Anti-flicker rendering. CHIP-8 games move sprites by erasing and redrawing
+//! them with XOR, so a frame shown between the two has the sprite missing.
+//! Fading pixels out like a phosphor screen, or showing every pixel lit in
+//! the last few frames, hides the gaps
+
+use crate::display::Display;
+use crate::palette::{ Palette, Rgb };
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+/// Brightness a fading pixel keeps each frame when none is given
+pub const DEFAULT_DECAY: f32 = 0.6;
+
+/// Frames ORed together when blending and no count is given
+pub const DEFAULT_BLEND_FRAMES: usize = 2;
+
+/// Most frames that can be blended
+pub const MAX_BLEND_FRAMES: usize = 16;
+
+/// How pixels that turn off are shown
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Persistence
+{
+    /// Pixels turn off at once
+    #[default]
+    Off,
+    /// Pixels that turn off fade out, keeping this fraction of their
+    /// brightness each frame
+    Fade(f32),
+    /// Each pixel is drawn as the OR of its value over this many frames
+    Blend(usize),
+}
+
+/// Parses off, fade, fade:DECAY, blend or blend:FRAMES
+impl FromStr for Persistence
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result< Self, Self::Err >
+    {
+        let lower = s.to_ascii_lowercase();
+        let (mode, value) = match lower.split_once(':')
+        {
+            Some((mode, value)) => (mode, Some(value)),
+            None => (lower.as_str(), None)
+        };
+
+        match (mode, value)
+        {
+            ("off", None) => Ok(Persistence::Off),
+            ("fade", None) => Ok(Persistence::Fade(DEFAULT_DECAY)),
+            ("fade", Some(value)) => match value.parse::< f32 >()
+            {
+                Ok(decay) if (0.0..1.0).contains(&decay) => Ok(Persistence::Fade(decay)),
+                _ => Err(format!("Fade decay must be a number from 0 up to 1, got \"{}\"", value))
+            },
+            ("blend", None) => Ok(Persistence::Blend(DEFAULT_BLEND_FRAMES)),
+            ("blend", Some(value)) => match value.parse::< usize >()
+            {
+                Ok(frames) if (1..=MAX_BLEND_FRAMES).contains(&frames) => Ok(Persistence::Blend(frames)),
+                _ => Err(format!("Blend frames must be a whole number from 1 to {}, got \"{}\"", MAX_BLEND_FRAMES, value))
+            },
+            _ => Err(format!("Unknown persistence \"{}\", expected off, fade[:DECAY] or blend[:FRAMES]", s))
+        }
+    }
+}
+
+impl fmt::Display for Persistence
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Persistence::Off => write!(f, "off"),
+            Persistence::Fade(decay) => write!(f, "fade:{}", decay),
+            Persistence::Blend(frames) => write!(f, "blend:{}", frames),
+        }
+    }
+}
+
+/// The colours to show for the active screen, kept up to date one frame at
+/// a time
+#[derive(Clone, Debug)]
+pub struct PersistenceBuffer
+{
+    mode: Persistence,
+    width: usize,
+    height: usize,
+    /// The colour shown for each pixel while fading, row by row
+    glow: Vec< [f32; 3] >,
+    /// Pixel values of the last frames while blending, newest first
+    history: VecDeque< Vec< u8 > >,
+}
+
+impl PersistenceBuffer
+{
+    pub fn new(mode: Persistence) -> Self
+    {
+        PersistenceBuffer { mode, width: 0, height: 0, glow: Vec::new(), history: VecDeque::new() }
+    }
+
+    pub fn mode(&self) -> Persistence
+    {
+        self.mode
+    }
+
+    /// Adds a frame of the display. Call once per emulated frame. The
+    /// buffer starts over when the resolution changes
+    pub fn update(&mut self, display: &Display, palette: &Palette)
+    {
+        let (width, height) = (display.width(), display.height());
+        if (width, height) != (self.width, self.height)
+        {
+            self.width = width;
+            self.height = height;
+            self.glow.clear();
+            self.history.clear();
+        }
+
+        let pixels = display.memory.iter().take(height).flat_map(|row| row[..width].iter().copied());
+        match self.mode
+        {
+            Persistence::Off => {},
+            Persistence::Fade(decay) =>
+            {
+                let off = to_float(palette.color(0));
+                self.glow.resize(width * height, off);
+                for (glow, pixel) in self.glow.iter_mut().zip(pixels)
+                {
+                    // Lit pixels show at full brightness; the rest fade towards off
+                    *glow = if pixel != 0
+                    {
+                        to_float(palette.color(pixel))
+                    }
+                    else
+                    {
+                        [0, 1, 2].map(|c| off[c] + (glow[c] - off[c]) * decay)
+                    };
+                }
+            },
+            Persistence::Blend(frames) =>
+            {
+                self.history.push_front(pixels.collect());
+                self.history.truncate(frames);
+            }
+        }
+    }
+
+    /// The colour of each pixel of the active screen, row by row
+    pub fn colors(&self, display: &Display, palette: &Palette) -> Vec< Rgb >
+    {
+        let (width, height) = (display.width(), display.height());
+        let current = || display.memory.iter().take(height).flat_map(move |row| row[..width].iter().copied());
+        let size_matches = (width, height) == (self.width, self.height);
+
+        match self.mode
+        {
+            Persistence::Fade(_) if size_matches && !self.glow.is_empty() =>
+            {
+                self.glow.iter().map(|&[r, g, b]| (r.round() as u8, g.round() as u8, b.round() as u8)).collect()
+            },
+            Persistence::Blend(_) if size_matches && !self.history.is_empty() =>
+            {
+                current().enumerate()
+                    .map(|(i, pixel)| palette.color(self.history.iter().fold(pixel, |value, frame| value | frame[i])))
+                    .collect()
+            },
+            _ => current().map(|pixel| palette.color(pixel)).collect()
+        }
+    }
+}
+
+fn to_float((r, g, b): Rgb) -> [f32; 3]
+{
+    [r as f32, g as f32, b as f32]
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn modes()
+    {
+        assert_eq!("off".parse(), Ok(Persistence::Off));
+        assert_eq!("FADE".parse(), Ok(Persistence::Fade(DEFAULT_DECAY)));
+        assert_eq!("fade:0.8".parse(), Ok(Persistence::Fade(0.8)));
+        assert_eq!("fade:0".parse(), Ok(Persistence::Fade(0.0)));
+        assert_eq!("blend".parse(), Ok(Persistence::Blend(DEFAULT_BLEND_FRAMES)));
+        assert_eq!("blend:16".parse(), Ok(Persistence::Blend(16)));
+    }
+
+    #[test]
+    fn display_round_trips()
+    {
+        for &mode in [Persistence::Off, Persistence::Fade(0.25), Persistence::Blend(3)].iter()
+        {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+    }
+
+    #[test]
+    fn invalid()
+    {
+        for text in ["fade:1", "fade:-0.1", "fade:nan", "fade:"].iter()
+        {
+            assert!(text.parse::< Persistence >().unwrap_err().starts_with("Fade decay"), "{}", text);
+        }
+        for text in ["blend:0", "blend:17", "blend:1.5"].iter()
+        {
+            assert!(text.parse::< Persistence >().unwrap_err().starts_with("Blend frames"), "{}", text);
+        }
+        for text in ["", "on", "off:1", "glow:2"].iter()
+        {
+            assert!(text.parse::< Persistence >().unwrap_err().starts_with("Unknown persistence"), "{}", text);
+        }
+    }
+}
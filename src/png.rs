@@ -0,0 +1,230 @@
+//! Minimal PNG encoder for screenshots. Images are 8-bit RGB and stored
+//! without compression, which keeps the encoder small; CHIP-8 screens are
+//! tiny so the files are still only a few hundred kilobytes at most
+
+use crate::display::Display;
+use crate::palette::{ Palette, Rgb };
+
+/// The eight byte signature every PNG file starts with
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// Largest block of data a stored deflate block can hold
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// Encodes an image given as rows of RGB triples, top row first
+pub fn encode_rgb(width: u32, height: u32, rgb: &[u8]) -> Vec< u8 >
+{
+    assert_eq!(rgb.len(), width as usize * height as usize * 3, "image data does not match its size");
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per channel, truecolour, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // Each row is preceded by its filter type, 0 for none
+    let row_len = width as usize * 3;
+    let mut raw = Vec::with_capacity((row_len + 1) * height as usize);
+    for row in rgb.chunks(row_len.max(1)).take(height as usize)
+    {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Encodes the active screen of a display in a palette's colours, with each
+/// pixel drawn as a square scale pixels wide
+pub fn encode_display(display: &Display, palette: &Palette, scale: u32) -> Vec< u8 >
+{
+    let (width, height) = (display.width(), display.height());
+    let colors: Vec< Rgb > = display.memory.iter()
+        .take(height)
+        .flat_map(|row| row[..width].iter().map(|&pixel| palette.color(pixel)))
+        .collect();
+    encode_pixels(width, &colors, scale)
+}
+
+/// Encodes an image width pixels wide given as the colour of each pixel, top
+/// row first, with each pixel drawn as a square scale pixels wide
+pub fn encode_pixels(width: usize, colors: &[Rgb], scale: u32) -> Vec< u8 >
+{
+    let height = colors.len().checked_div(width).unwrap_or(0);
+    let scale = scale as usize;
+
+    let mut rgb = Vec::with_capacity(colors.len() * scale * scale * 3);
+    for row in colors.chunks(width.max(1)).take(height)
+    {
+        for _ in 0..scale
+        {
+            for &(r, g, b) in row.iter()
+            {
+                for _ in 0..scale
+                {
+                    rgb.extend_from_slice(&[r, g, b]);
+                }
+            }
+        }
+    }
+
+    encode_rgb((width * scale) as u32, (height * scale) as u32, &rgb)
+}
+
+fn write_chunk(png: &mut Vec< u8 >, kind: &[u8; 4], data: &[u8])
+{
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps data in a zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec< u8 >
+{
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none()
+    {
+        // An empty stream still needs one final block
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next()
+    {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32
+{
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data.iter()
+    {
+        crc ^= byte as u32;
+        for _ in 0..8
+        {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32
+{
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data.iter()
+    {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// Splits a PNG after its signature into (kind, data) chunks, checking
+    /// each chunk's CRC
+    fn chunks(png: &[u8]) -> Vec< ([u8; 4], Vec< u8 >) >
+    {
+        assert_eq!(png[..8], SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty()
+        {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let (body, crc) = (&rest[4..8 + len], &rest[8 + len..12 + len]);
+            assert_eq!(crc32(body).to_be_bytes(), crc, "CRC of {:?}", String::from_utf8_lossy(&body[..4]));
+            chunks.push(([body[0], body[1], body[2], body[3]], body[4..].to_vec()));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    /// Decodes a zlib stream of stored blocks, checking the Adler-32
+    fn unstore(zlib: &[u8]) -> Vec< u8 >
+    {
+        assert_eq!(zlib[..2], [0x78, 0x01]);
+        let mut data = Vec::new();
+        let mut pos = 2;
+        loop
+        {
+            let last = zlib[pos] & 1 != 0;
+            let len = u16::from_le_bytes([zlib[pos + 1], zlib[pos + 2]]);
+            assert_eq!(!len, u16::from_le_bytes([zlib[pos + 3], zlib[pos + 4]]));
+            data.extend_from_slice(&zlib[pos + 5..pos + 5 + len as usize]);
+            pos += 5 + len as usize;
+            if last
+            {
+                break;
+            }
+        }
+        assert_eq!(zlib[pos..], adler32(&data).to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn checksums()
+    {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(b""), 1);
+    }
+
+    #[test]
+    fn header_and_chunks()
+    {
+        let png = encode_rgb(2, 1, &[1, 2, 3, 4, 5, 6]);
+        let chunks = chunks(&png);
+        let kinds: Vec< &[u8; 4] > = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, vec![b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, vec![0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+        assert_eq!(unstore(&chunks[1].1), vec![0, 1, 2, 3, 4, 5, 6]);
+        assert!(chunks[2].1.is_empty());
+
+        // The IEND chunk never changes
+        assert_eq!(png[png.len() - 12..], [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn large_images_span_several_blocks()
+    {
+        let (width, height) = (200, 200);
+        let rgb: Vec< u8 > = (0..width * height * 3).map(|n| n as u8).collect();
+        let chunks = chunks(&encode_rgb(width as u32, height as u32, &rgb));
+        let raw = unstore(&chunks[1].1);
+        assert!(raw.len() > MAX_STORED_BLOCK);
+        for (y, row) in raw.chunks(width * 3 + 1).enumerate()
+        {
+            assert_eq!(row[0], 0);
+            assert!(row[1..] == rgb[y * width * 3..(y + 1) * width * 3], "row {}", y);
+        }
+    }
+
+    #[test]
+    fn pixels_are_scaled()
+    {
+        let (red, blue) = ((0xFF, 0, 0), (0, 0, 0xFF));
+        let chunks = chunks(&encode_pixels(2, &[red, blue], 2));
+        assert_eq!(chunks[0].1[..8], [0, 0, 0, 4, 0, 0, 0, 2]);
+        let row = [0, 0xFF, 0, 0, 0xFF, 0, 0, 0, 0, 0xFF, 0, 0, 0xFF];
+        assert_eq!(unstore(&chunks[1].1), [row, row].concat());
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Behaviour that differs between CHIP-8 interpreters. ROMs written for one
+/// interpreter often rely on its particular answer to each of these.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks
+{
+    /// 8xy6/8xyE store Vy shifted into Vx. When false Vx is shifted in place
+    pub shift_vy: bool,
+
+    /// Fx55/Fx65 leave I pointing past the last register stored or loaded.
+    /// When false I is left unchanged
+    pub load_store_increment_i: bool,
+
+    /// Bnnn jumps to nnn + Vx, where x is the highest nibble of nnn.
+    /// When false it jumps to nnn + V0
+    pub jump_vx: bool,
+
+    /// Sprites drawn over the edge of the screen wrap around to the other
+    /// side. When false they are clipped
+    pub wrap_sprites: bool,
+
+    /// 8xy1/8xy2/8xy3 reset VF to 0
+    pub vf_reset: bool,
+}
+
+impl Quirks
+{
+    /// The original COSMAC VIP interpreter
+    pub const VIP: Quirks = Quirks {
+        shift_vy: true,
+        load_store_increment_i: true,
+        jump_vx: false,
+        wrap_sprites: false,
+        vf_reset: true,
+    };
+
+    /// CHIP-48 on the HP-48 calculators
+    pub const CHIP48: Quirks = Quirks {
+        shift_vy: false,
+        load_store_increment_i: true,
+        jump_vx: true,
+        wrap_sprites: false,
+        vf_reset: false,
+    };
+
+    /// SUPER-CHIP 1.1
+    pub const SCHIP: Quirks = Quirks {
+        shift_vy: false,
+        load_store_increment_i: false,
+        jump_vx: true,
+        wrap_sprites: false,
+        vf_reset: false,
+    };
+
+    /// Octo and most modern interpreters
+    pub const OCTO: Quirks = Quirks {
+        shift_vy: true,
+        load_store_increment_i: true,
+        jump_vx: false,
+        wrap_sprites: true,
+        vf_reset: false,
+    };
+
+    /// Names of the presets accepted by `Quirks::preset`
+    pub const PRESET_NAMES: [&'static str; 4] = ["vip", "chip48", "schip", "octo"];
+
+    /// Names of the flags that can be overridden in a quirks specification
+    pub const FLAG_NAMES: [&'static str; 5] = ["shift_vy", "load_store_increment_i", "jump_vx", "wrap_sprites", "vf_reset"];
+
+    /// Returns the preset with the given name, if there is one
+    pub fn preset(name: &str) -> Option< Quirks >
+    {
+        match name.to_ascii_lowercase().as_str()
+        {
+            "vip" | "chip8" | "cosmac" => Some(Quirks::VIP),
+            "chip48" => Some(Quirks::CHIP48),
+            "schip" | "superchip" => Some(Quirks::SCHIP),
+            "octo" | "modern" => Some(Quirks::OCTO),
+            _ => None
+        }
+    }
+
+    /// Sets the flag with the given name. Returns an error if there is no such flag
+    pub fn set_flag(&mut self, name: &str, value: bool) -> Result< (), String >
+    {
+        let flag = match name
+        {
+            "shift_vy" => &mut self.shift_vy,
+            "load_store_increment_i" => &mut self.load_store_increment_i,
+            "jump_vx" => &mut self.jump_vx,
+            "wrap_sprites" => &mut self.wrap_sprites,
+            "vf_reset" => &mut self.vf_reset,
+            _ => return Err(format!("Unknown quirk \"{}\". Expected one of: {}", name, Quirks::FLAG_NAMES.join(", ")))
+        };
+        *flag = value;
+
+        Ok(())
+    }
+}
+
+impl Default for Quirks
+{
+    fn default() -> Self
+    {
+        Quirks::OCTO
+    }
+}
+
+/// Parses a quirks specification: a preset name optionally followed by comma
+/// separated flag overrides, e.g. `schip,wrap_sprites=1,vf_reset=false`.
+/// The preset may be omitted, in which case overrides apply to the default
+impl FromStr for Quirks
+{
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result< Self, Self::Err >
+    {
+        let mut quirks = Quirks::default();
+
+        for (n, part) in spec.split(',').map(str::trim).enumerate()
+        {
+            if part.is_empty()
+            {
+                continue;
+            }
+
+            if let Some(eq) = part.find('=')
+            {
+                let (name, value) = (part[..eq].trim(), part[eq + 1..].trim());
+                let value = match value.to_ascii_lowercase().as_str()
+                {
+                    "1" | "on" | "true" | "yes" => true,
+                    "0" | "off" | "false" | "no" => false,
+                    _ => return Err(format!("Invalid value \"{}\" for quirk \"{}\"", value, name))
+                };
+                quirks.set_flag(name, value)?;
+            }
+            else if n == 0
+            {
+                quirks = Quirks::preset(part).ok_or_else(|| {
+                    format!("Unknown quirks preset \"{}\". Expected one of: {}", part, Quirks::PRESET_NAMES.join(", "))
+                })?;
+            }
+            else
+            {
+                return Err(format!("Expected flag=value, got \"{}\"", part));
+            }
+        }
+
+        Ok(quirks)
+    }
+}
+
+impl fmt::Display for Quirks
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "shift_vy={},load_store_increment_i={},jump_vx={},wrap_sprites={},vf_reset={}",
+            self.shift_vy as u8, self.load_store_increment_i as u8, self.jump_vx as u8, self.wrap_sprites as u8, self.vf_reset as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn presets()
+    {
+        assert_eq!("vip".parse(), Ok(Quirks::VIP));
+        assert_eq!("CHIP48".parse(), Ok(Quirks::CHIP48));
+        assert_eq!("superchip".parse(), Ok(Quirks::SCHIP));
+        assert_eq!("modern".parse(), Ok(Quirks::OCTO));
+        assert_eq!("".parse(), Ok(Quirks::default()));
+    }
+
+    #[test]
+    fn overrides()
+    {
+        let quirks: Quirks = "schip, wrap_sprites=1, jump_vx=off".parse().unwrap();
+        assert_eq!(quirks, Quirks { wrap_sprites: true, jump_vx: false, ..Quirks::SCHIP });
+
+        // Without a preset the overrides apply to the default
+        let quirks: Quirks = "vf_reset=yes".parse().unwrap();
+        assert_eq!(quirks, Quirks { vf_reset: true, ..Quirks::default() });
+    }
+
+    #[test]
+    fn display_round_trips()
+    {
+        for &quirks in [Quirks::VIP, Quirks::CHIP48, Quirks::SCHIP, Quirks::OCTO].iter()
+        {
+            assert_eq!(quirks.to_string().parse(), Ok(quirks));
+        }
+    }
+
+    #[test]
+    fn invalid()
+    {
+        assert!("cosmic".parse::< Quirks >().unwrap_err().contains("Unknown quirks preset"));
+        assert!("vip,wobble=1".parse::< Quirks >().unwrap_err().contains("Unknown quirk \"wobble\""));
+        assert!("vip,shift_vy=maybe".parse::< Quirks >().unwrap_err().contains("Invalid value"));
+        assert!("vip,schip".parse::< Quirks >().unwrap_err().contains("Expected flag=value"));
+    }
+}
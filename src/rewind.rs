@@ -0,0 +1,300 @@
+use crate::cpu::CPU;
+use crate::error::Chip8Error;
+
+use std::collections::VecDeque;
+
+/// Runs of unchanged bytes shorter than this are folded into the surrounding
+/// changed runs, since each run costs 6 bytes of header
+const MIN_GAP: usize = 8;
+
+/// A ring buffer of machine snapshots for stepping backwards through time.
+///
+/// Only the newest snapshot is kept in full. Every older frame is stored as
+/// a delta that turns the frame after it back into that frame, which is small
+/// because memory and the display barely change between frames. When the
+/// buffer is full the oldest frame is dropped.
+pub struct RewindBuffer
+{
+    /// Maximum number of past frames kept
+    capacity: usize,
+
+    /// Full snapshot of the newest frame
+    head: Option< Vec< u8 > >,
+
+    /// Deltas from each frame to the one before it, oldest first
+    deltas: VecDeque< Vec< u8 > >,
+}
+
+impl RewindBuffer
+{
+    /// Creates a buffer that can step back up to capacity frames
+    pub fn new(capacity: usize) -> Self
+    {
+        RewindBuffer {
+            capacity,
+            head: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Number of frames that can currently be stepped back
+    pub fn len(&self) -> usize
+    {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.deltas.is_empty()
+    }
+
+    /// Forgets every recorded frame
+    pub fn clear(&mut self)
+    {
+        self.head = None;
+        self.deltas.clear();
+    }
+
+    /// Records the current state of the CPU as the newest frame
+    pub fn push(&mut self, cpu: &CPU)
+    {
+        if self.capacity == 0
+        {
+            return;
+        }
+
+        let state = cpu.save_state();
+        if let Some(prev) = self.head.take()
+        {
+            self.deltas.push_back(encode_delta(&state, &prev));
+            if self.deltas.len() > self.capacity
+            {
+                self.deltas.pop_front();
+            }
+        }
+        self.head = Some(state);
+    }
+
+    /// Restores the CPU to the frame before the newest one, which becomes the
+    /// newest. Returns false if there is nothing left to rewind
+    pub fn step_back(&mut self, cpu: &mut CPU) -> Result< bool, Chip8Error >
+    {
+        let delta = match self.deltas.pop_back()
+        {
+            Some(delta) => delta,
+            None => return Ok(false)
+        };
+
+        let mut state = self.head.take().unwrap_or_default();
+        apply_delta(&mut state, &delta);
+        cpu.load_state(&state)?;
+        self.head = Some(state);
+
+        Ok(true)
+    }
+}
+
+/// Encodes the changes that turn `from` into `to` as a list of runs. Each run
+/// is a little-endian u32 offset, a u16 length and the bytes of `to` there.
+/// States that differ in size are stored whole as a single run at offset 0
+/// with the full length in a u32 after a zero u16 length
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec< u8 >
+{
+    let mut delta = Vec::new();
+    if from.len() != to.len()
+    {
+        delta.extend_from_slice(&0u32.to_le_bytes());
+        delta.extend_from_slice(&0u16.to_le_bytes());
+        delta.extend_from_slice(&(to.len() as u32).to_le_bytes());
+        delta.extend_from_slice(to);
+        return delta;
+    }
+
+    let mut pos = 0;
+    while pos < to.len()
+    {
+        if from[pos] == to[pos]
+        {
+            pos += 1;
+            continue;
+        }
+
+        // Extend the run until MIN_GAP unchanged bytes in a row, or the maximum run length
+        let start = pos;
+        let mut end = pos + 1;
+        let mut scan = end;
+        while scan < to.len() && scan - start < u16::MAX as usize && scan - end < MIN_GAP
+        {
+            if from[scan] != to[scan]
+            {
+                end = scan + 1;
+            }
+            scan += 1;
+        }
+
+        delta.extend_from_slice(&(start as u32).to_le_bytes());
+        delta.extend_from_slice(&((end - start) as u16).to_le_bytes());
+        delta.extend_from_slice(&to[start..end]);
+        pos = end;
+    }
+
+    delta
+}
+
+/// Applies a delta made by `encode_delta` in place
+fn apply_delta(state: &mut Vec< u8 >, delta: &[u8])
+{
+    let mut pos = 0;
+    while pos + 6 <= delta.len()
+    {
+        let offset = u32::from_le_bytes([delta[pos], delta[pos + 1], delta[pos + 2], delta[pos + 3]]) as usize;
+        let mut len = u16::from_le_bytes([delta[pos + 4], delta[pos + 5]]) as usize;
+        pos += 6;
+
+        if len == 0
+        {
+            // A whole replacement state
+            len = u32::from_le_bytes([delta[pos], delta[pos + 1], delta[pos + 2], delta[pos + 3]]) as usize;
+            pos += 4;
+            *state = delta[pos..pos + len].to_vec();
+        }
+        else
+        {
+            state[offset..offset + len].copy_from_slice(&delta[pos..pos + len]);
+        }
+        pos += len;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::quirks::Quirks;
+
+    /// A CPU running a loop that changes a register, memory and the display
+    /// every instruction or two
+    fn looping_cpu() -> CPU
+    {
+        // v0 += 1, i := 0x300, save v0, sprite v0 v0 1, jump 0x200
+        let program = [0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0xD0, 0x01, 0x12, 0x00];
+        let mut cpu = CPU::new(Quirks::OCTO);
+        cpu.set_rng_seed(1);
+        cpu.load_program(&program).unwrap();
+        cpu
+    }
+
+    /// Runs a few instructions, as a frame would
+    fn run_frame(cpu: &mut CPU)
+    {
+        for _ in 0..3
+        {
+            cpu.cpu_cycle().unwrap();
+        }
+    }
+
+    #[test]
+    fn restores_every_frame_exactly()
+    {
+        const CAPACITY: usize = 5;
+        let mut cpu = looping_cpu();
+        let mut rewind = RewindBuffer::new(CAPACITY);
+        let mut snapshots = Vec::new();
+
+        // Push past the capacity so the oldest frames have been dropped
+        for _ in 0..CAPACITY * 3
+        {
+            run_frame(&mut cpu);
+            rewind.push(&cpu);
+            snapshots.push(cpu.save_state());
+        }
+        assert_eq!(rewind.len(), CAPACITY);
+
+        // Each step applies one more delta to the full snapshot of the newest frame
+        for back in 1..=CAPACITY
+        {
+            assert!(rewind.step_back(&mut cpu).unwrap());
+            assert!(cpu.save_state() == snapshots[snapshots.len() - 1 - back], "{} frames back", back);
+        }
+
+        // The oldest frame kept is as far as it goes
+        assert!(!rewind.step_back(&mut cpu).unwrap());
+        assert!(cpu.save_state() == snapshots[snapshots.len() - 1 - CAPACITY]);
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn pushing_after_rewinding_continues_from_there()
+    {
+        let mut cpu = looping_cpu();
+        let mut rewind = RewindBuffer::new(8);
+        let mut snapshots = Vec::new();
+        for _ in 0..4
+        {
+            run_frame(&mut cpu);
+            rewind.push(&cpu);
+            snapshots.push(cpu.save_state());
+        }
+
+        rewind.step_back(&mut cpu).unwrap();
+        rewind.step_back(&mut cpu).unwrap();
+        snapshots.truncate(2);
+
+        cpu.delay_timer = 0x55;
+        for _ in 0..3
+        {
+            run_frame(&mut cpu);
+            rewind.push(&cpu);
+            snapshots.push(cpu.save_state());
+        }
+
+        for back in 1..snapshots.len()
+        {
+            assert!(rewind.step_back(&mut cpu).unwrap());
+            assert!(cpu.save_state() == snapshots[snapshots.len() - 1 - back], "{} frames back", back);
+        }
+        assert!(!rewind.step_back(&mut cpu).unwrap());
+    }
+
+    #[test]
+    fn zero_capacity_records_nothing()
+    {
+        let mut cpu = looping_cpu();
+        let mut rewind = RewindBuffer::new(0);
+        rewind.push(&cpu);
+        run_frame(&mut cpu);
+        rewind.push(&cpu);
+        assert!(rewind.is_empty());
+        assert!(!rewind.step_back(&mut cpu).unwrap());
+    }
+
+    #[test]
+    fn deltas_restore_exactly()
+    {
+        let from: Vec< u8 > = (0..70_000).map(|i| (i % 251) as u8).collect();
+
+        // Scattered changes, some closer together than MIN_GAP, and one at the very end
+        let mut to = from.clone();
+        for &i in [0, 3, 9, 100, 101, 5000, 69_999].iter()
+        {
+            to[i] ^= 0xFF;
+        }
+        let mut state = from.clone();
+        apply_delta(&mut state, &encode_delta(&from, &to));
+        assert!(state == to);
+
+        // A run longer than a u16 length can hold is split
+        let to: Vec< u8 > = from.iter().map(|b| !b).collect();
+        let mut state = from.clone();
+        apply_delta(&mut state, &encode_delta(&from, &to));
+        assert!(state == to);
+
+        // States of different sizes are stored whole
+        let to = vec![7u8; 10];
+        let mut state = from.clone();
+        apply_delta(&mut state, &encode_delta(&from, &to));
+        assert_eq!(state, to);
+
+        assert!(encode_delta(&from, &from).is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+//! Helpers for the binary save state format. Values are stored little-endian
+//! in the order they are written, so readers must read fields back in the
+//! same order they were saved
+
+use crate::error::Chip8Error;
+
+/// Appends fields to a save state buffer
+pub(crate) struct StateWriter
+{
+    buf: Vec< u8 >,
+}
+
+impl StateWriter
+{
+    pub fn new() -> Self
+    {
+        StateWriter { buf: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8)
+    {
+        self.buf.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool)
+    {
+        self.buf.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16)
+    {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32)
+    {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64)
+    {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, value: &[u8])
+    {
+        self.buf.extend_from_slice(value);
+    }
+
+    pub fn into_inner(self) -> Vec< u8 >
+    {
+        self.buf
+    }
+}
+
+/// Reads fields back out of a save state buffer
+pub(crate) struct StateReader< 'a >
+{
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl< 'a > StateReader< 'a >
+{
+    pub fn new(data: &'a [u8]) -> Self
+    {
+        StateReader { data, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result< &'a [u8], Chip8Error >
+    {
+        if self.pos + len > self.data.len()
+        {
+            return Err(Chip8Error::InvalidSaveState("unexpected end of data".to_string()));
+        }
+
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result< u8, Chip8Error >
+    {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result< bool, Chip8Error >
+    {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result< u16, Chip8Error >
+    {
+        let mut buf = [0u8; 2];
+        buf.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn u32(&mut self) -> Result< u32, Chip8Error >
+    {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn u64(&mut self) -> Result< u64, Chip8Error >
+    {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Fails if any data is left over, which means the state was not written
+    /// by this version of the format
+    pub fn finish(self) -> Result< (), Chip8Error >
+    {
+        if self.pos != self.data.len()
+        {
+            return Err(Chip8Error::InvalidSaveState(format!("{} unexpected bytes at end of data", self.data.len() - self.pos)));
+        }
+
+        Ok(())
+    }
+}
+
+/// 64-bit FNV-1a hash, used to check a save state belongs to the loaded ROM
+pub fn rom_hash(data: &[u8]) -> u64
+{
+    data.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3))
+}
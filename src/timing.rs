@@ -0,0 +1,183 @@
+//! Instruction timing. By default every instruction takes the same time, so
+//! a frontend runs a fixed number of them per second. The VIP mode instead
+//! charges each instruction the time it took on the COSMAC VIP and runs as
+//! many as fit in each 60 Hz frame
+
+use crate::cpu::{ CPU, TIMER_CLOCK };
+use crate::instruction::Instruction;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Length of a frame, between two timer ticks, in microseconds
+pub const FRAME_MICROS: i64 = 1_000_000 / TIMER_CLOCK as i64;
+
+/// Time per frame the VIP spends in the display interrupt and the CDP1861's
+/// DMA of the screen, during which no instructions run
+pub const VIP_DISPLAY_MICROS: i64 = 5_000;
+
+/// How instruction timing is modelled
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Timing
+{
+    /// A fixed number of instructions per second
+    #[default]
+    Fixed,
+    /// Instructions cost their COSMAC VIP execution time
+    Vip,
+}
+
+impl FromStr for Timing
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result< Self, Self::Err >
+    {
+        match s.to_ascii_lowercase().as_str()
+        {
+            "fixed" => Ok(Timing::Fixed),
+            "vip" => Ok(Timing::Vip),
+            _ => Err(format!("Unknown timing \"{}\", expected fixed or vip", s))
+        }
+    }
+}
+
+impl fmt::Display for Timing
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Timing::Fixed => write!(f, "fixed"),
+            Timing::Vip => write!(f, "vip"),
+        }
+    }
+}
+
+/// Approximate time in microseconds an instruction took on the COSMAC VIP,
+/// including the interpreter's fetch and decode. Skips that are taken cost a
+/// little more. Instructions the VIP did not have are costed like the
+/// closest instruction it did have. Dxyn is only the drawing; the wait for
+/// the display interrupt before it is handled by `run_vip_frame`
+pub fn vip_instruction_micros(instruction: Instruction, skipped: bool) -> i64
+{
+    use crate::instruction::Instruction::*;
+
+    let skip = if skipped { 9 } else { 0 };
+    match instruction
+    {
+        Cls | ScrollDown(_) | ScrollUp(_) | ScrollRight | ScrollLeft | Low | High => 109,
+        Ret | Exit | Jp(_) | Call(_) | JpV0(_) => 105,
+        SeVxNn(..) | SneVxNn(..) => 55 + skip,
+        SeVxVy(..) | SneVxVy(..) | Skp(_) | Sknp(_) => 73 + skip,
+        LdVxNn(..) => 27,
+        AddVxNn(..) => 45,
+        LdVxVy(..) | Or(..) | And(..) | Xor(..) | AddVxVy(..) | Sub(..) | Shr(..) | Subn(..) | Shl(..) => 200,
+        LdI(_) | LdILong => 55,
+        Rnd(..) => 164,
+        Drw(_, _, n) => 340 + 380 * if n == 0 { 16 } else { n as i64 },
+        LdVxDt(_) | LdVxK(_) | LdDtVx(_) | LdStVx(_) | Plane(_) | Pitch(_) => 45,
+        AddIVx(_) => 86,
+        LdFVx(_) | LdHfVx(_) => 91,
+        LdBVx(_) => 927,
+        SaveRange(..) | LoadRange(..) | LdIVx(_) | LdVxI(_) | LdRVx(_) | LdVxR(_) | Audio => 605,
+    }
+}
+
+/// Runs one frame at the speed of the COSMAC VIP: instructions until the
+/// frame's time is used up, then a timer tick. Time an instruction runs over
+/// is taken from the next frame, and is kept in the CPU so save states and
+/// rewinding restore it. Dxyn waits for the display interrupt before drawing,
+/// so one that is not the first instruction of the frame ends the frame and
+/// runs at the start of the next. The frame also ends while the program waits
+/// for a key. step runs a single instruction and returns false to stop the
+/// frame, e.g. at a breakpoint
+pub fn run_vip_frame< E, F >(cpu: &mut CPU, mut step: F) -> Result< (), E >
+    where F: FnMut(&mut CPU) -> Result< bool, E >
+{
+    // Time left unused at the end of a frame is spent waiting for the interrupt
+    cpu.vip_micros = cpu.vip_micros.min(0) + FRAME_MICROS - VIP_DISPLAY_MICROS;
+
+    let mut first = true;
+    while cpu.vip_micros > 0 && !cpu.exited
+    {
+        let pc = cpu.pc();
+        let instruction = match cpu.peek_opcode().and_then(Instruction::decode)
+        {
+            Some(Instruction::Drw(..)) if !first => break,
+            instruction => instruction
+        };
+        if !step(cpu)?
+        {
+            break;
+        }
+        first = false;
+
+        let instruction = match instruction
+        {
+            Some(instruction) => instruction,
+            None => break
+        };
+        let skipped = instruction.is_skip() && cpu.pc() != pc + 2;
+        cpu.vip_micros -= vip_instruction_micros(instruction, skipped);
+        if cpu.is_waiting_for_key()
+        {
+            break;
+        }
+    }
+
+    cpu.update_cpu_timers();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::quirks::Quirks;
+
+    fn run_frame(cpu: &mut CPU)
+    {
+        run_vip_frame(cpu, |cpu| cpu.cpu_cycle().map(|_| true)).unwrap();
+    }
+
+    /// v0 := 0, draw a row of the 0 glyph, then loop forever
+    fn drawing_cpu() -> CPU
+    {
+        let mut cpu = CPU::new(Quirks::VIP);
+        cpu.load_program(&[0x60, 0x00, 0xD0, 0x01, 0x12, 0x04]).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn sprites_are_drawn_after_the_display_interrupt()
+    {
+        let mut cpu = drawing_cpu();
+        run_frame(&mut cpu);
+        assert_eq!(cpu.pc(), 0x202);
+        assert!(cpu.display.memory.iter().all(|row| row.iter().all(|&pixel| pixel == 0)));
+
+        run_frame(&mut cpu);
+        assert_eq!(cpu.display.memory[0][..4], [1, 1, 1, 1]);
+        assert_eq!(cpu.pc(), 0x204);
+    }
+
+    #[test]
+    fn save_states_keep_the_time_carried_between_frames()
+    {
+        let mut cpu = drawing_cpu();
+        run_frame(&mut cpu);
+        run_frame(&mut cpu);
+        let (state, micros) = (cpu.save_state(), cpu.vip_micros);
+        assert!(micros != 0);
+
+        run_frame(&mut cpu);
+        let after = cpu.save_state();
+
+        let mut restored = drawing_cpu();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.vip_micros, micros);
+        run_frame(&mut restored);
+        assert!(restored.save_state() == after);
+    }
+}
@@ -0,0 +1,76 @@
+//! Minimal WAV encoder for audio recordings. Samples are written as mono
+//! 16-bit PCM
+
+/// Encodes samples from -1 to 1 played at sample_rate samples per second
+pub fn encode_mono(sample_rate: u32, samples: &[f32]) -> Vec< u8 >
+{
+    let data_len = samples.len() as u32 * 2;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    // PCM format, 1 channel, byte rate, block align and bits per sample
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for &sample in samples.iter()
+    {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        wav.extend_from_slice(&value.to_le_bytes());
+    }
+
+    wav
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn u16_at(wav: &[u8], offset: usize) -> u16
+    {
+        u16::from_le_bytes([wav[offset], wav[offset + 1]])
+    }
+
+    fn u32_at(wav: &[u8], offset: usize) -> u32
+    {
+        u32::from_le_bytes([wav[offset], wav[offset + 1], wav[offset + 2], wav[offset + 3]])
+    }
+
+    #[test]
+    fn header()
+    {
+        let wav = encode_mono(44_100, &[0.0; 3]);
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(&wav, 4), wav.len() as u32 - 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&wav, 16), 16);
+        assert_eq!(u16_at(&wav, 20), 1, "PCM");
+        assert_eq!(u16_at(&wav, 22), 1, "channels");
+        assert_eq!(u32_at(&wav, 24), 44_100, "sample rate");
+        assert_eq!(u32_at(&wav, 28), 88_200, "byte rate");
+        assert_eq!(u16_at(&wav, 32), 2, "block align");
+        assert_eq!(u16_at(&wav, 34), 16, "bits per sample");
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(&wav, 40), 6);
+    }
+
+    #[test]
+    fn samples_are_clamped()
+    {
+        let wav = encode_mono(8_000, &[0.0, 0.5, 1.0, -1.0, 2.0, -2.0]);
+        let samples: Vec< i16 > = wav[44..].chunks(2).map(|s| i16::from_le_bytes([s[0], s[1]])).collect();
+        assert_eq!(samples, vec![0, 16383, 32767, -32767, 32767, -32767]);
+    }
+}
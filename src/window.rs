@@ -0,0 +1,568 @@
+//! The SDL frontend: a window showing the display, with keyboard input,
+//! pausing, save states, rewind and the debugger on stdin
+
+use crate::cli::Options;
+use crate::{ audio_recorder, beeper, capture_path, gif_recorder, load_program, palettes, save_audio_recording, save_gif, save_screenshot };
+use chip8::audio::{ Beeper, DEFAULT_SAMPLE_RATE };
+use chip8::cpu::{ self, CPU, RPL_FLAG_COUNT };
+use chip8::debugger::Debugger;
+use chip8::display::{ Display, DISPLAY_WIDTH, DISPLAY_HEIGHT };
+use chip8::error::Chip8Error;
+use chip8::gif::GifRecorder;
+use chip8::rewind::RewindBuffer;
+use chip8::machine::{ instructions_per_frame, Machine };
+use chip8::palette::{ Palette, Rgb };
+use chip8::persistence::PersistenceBuffer;
+use chip8::timing::FRAME_MICROS;
+
+use sdl2::Sdl;
+use sdl2::audio::{ AudioCallback, AudioDevice, AudioSpecDesired };
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::event::Event;
+use sdl2::keyboard::{ Keycode, Mod };
+use sdl2::render::WindowCanvas;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ self, BufRead, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::mpsc::{ self, Receiver };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+/// Most frames stepped back per frame while rewinding
+const MAX_REWIND_SPEED: u32 = 8;
+
+/// How much faster than the current speed holding Tab runs
+const FAST_FORWARD_SPEED: f64 = 4.0;
+
+/// Slowest and fastest speeds the bracket keys can select
+const MIN_SPEED: f64 = 0.125;
+const MAX_SPEED: f64 = 8.0;
+
+/// Samples per audio callback. Small enough that beeps start within a frame
+const AUDIO_BUFFER_SAMPLES: u16 = 512;
+
+/// Most frames run at once to catch up after a stall, at 1x speed
+const MAX_CATCH_UP_FRAMES: u32 = 4;
+
+/// Runs the ROM given in options until the window is closed or the program exits
+pub fn run(options: &Options) -> Result< (), String >
+{
+    // Initialize SDL
+    let sdl_context = sdl2::init()?;
+    let video_subsys = sdl_context.video()?;
+
+    // Create the SDL window
+    let mut window_builder = video_subsys.window("chip8-rs", DISPLAY_WIDTH as u32 * options.scale, DISPLAY_HEIGHT as u32 * options.scale);
+    window_builder.opengl().position_centered();
+    if options.fullscreen
+    {
+        window_builder.fullscreen_desktop();
+    }
+    let window = window_builder.build().map_err(|e| e.to_string())?;
+
+    // Create the SDL drawing canvas and texture
+    let mut canvas = window
+        .into_canvas()
+        .accelerated()
+        .build()
+        .map_err(|e| e.to_string())?;
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.clear();
+    canvas.present();
+
+    // The beep plays on SDL's audio thread. Without an audio device the 
+    // emulator still runs, silently
+    let mut audio = None;
+    if !options.mute
+    {
+        match open_audio(&sdl_context, options)
+        {
+            Ok(device) => audio = Some(device),
+            Err(e) => eprintln!("chip8-rs: Could not open audio, continuing without sound: {}", e)
+        }
+    }
+
+    // Recording follows emulated time, so it only advances while frames run
+    let mut recorder = audio_recorder(options);
+
+    // F11 cycles through the palettes
+    let (palettes, mut palette) = palettes(options)?;
+
+    // Fades or blends pixels across frames to hide flicker
+    let mut persistence = PersistenceBuffer::new(options.persistence);
+
+    // G starts and stops a GIF recording, which --record-gif starts at once
+    let mut gif = gif_recorder(options, &palettes[palette]).zip(options.record_gif.clone());
+
+    // Create input stuff
+    let mut event_pump = sdl_context.event_pump().map_err(|e| e.to_string())?;
+    let key_binds = get_sdl_keybinds();
+
+    // Create the Chip-8 CPU & load a rom
+    let mut cpu = CPU::new(options.quirks);
+    if let Some(seed) = options.seed
+    {
+        cpu.set_rng_seed(seed);
+    }
+    let rom_path = options.rom_path.as_path();
+    load_program(&mut cpu, rom_path)?;
+    load_rpl_flags(&mut cpu, rom_path);
+    let mut machine = Machine::new(cpu, options.timing);
+
+    // Real time is added to the accumulator, scaled by the speed, and whole 
+    // frames of emulated time are run from it
+    let frame_time = Duration::from_micros(FRAME_MICROS as u64);
+    let mut accumulator = Duration::ZERO;
+    let mut last_time = Instant::now();
+    let mut speed = options.speed;
+    let mut fast_forward = false;
+    set_title(&mut canvas, speed);
+
+    // While paused the CPU and timers are stopped but the window stays responsive
+    let mut paused = options.paused;
+
+    // A frame is recorded every frame run. Holding Backspace steps back 
+    // rewind_speed frames per frame instead of running the CPU
+    let mut rewind = RewindBuffer::new((options.rewind_seconds * cpu::TIMER_CLOCK as u32) as usize);
+    let mut rewinding = false;
+    let mut rewind_speed = 1;
+
+    // The debugger reads commands from stdin on a separate thread so the 
+    // window keeps drawing while it waits
+    let mut debugger = Debugger::new(options.debug);
+    let debugger_commands = spawn_stdin_reader();
+    if options.debug
+    {
+        print_debugger_output(&debugger, &chip8::debugger::registers(&machine.cpu));
+    }
+
+    // Set if the program hits an error, which stops emulation
+    let mut cpu_error = None;
+
+    // Main application loop
+    'running: loop
+    {
+        // Handle SDL events
+        for event in event_pump.poll_iter()
+        {
+            match event
+            {
+                // Quit events
+                Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => break 'running,
+
+                // Break into the debugger
+                Event::KeyDown { keycode: Some(Keycode::F12), repeat: false, .. } =>
+                {
+                    debugger.pause();
+                    print_debugger_output(&debugger, &chip8::debugger::registers(&machine.cpu));
+                },
+
+                Event::KeyDown { keycode: Some(Keycode::F11), repeat: false, .. } =>
+                {
+                    palette = (palette + 1) % palettes.len();
+                    println!("Palette: {}", palettes[palette].name);
+                },
+
+                Event::KeyDown { keycode: Some(Keycode::PrintScreen), repeat: false, .. } =>
+                {
+                    match save_screenshot(options, &machine.cpu.display, &palettes[palette], machine.frames())
+                    {
+                        Ok(path) => println!("Screenshot: {}", path.display()),
+                        Err(e) => eprintln!("chip8-rs: {}", e)
+                    }
+                },
+
+                Event::KeyDown { keycode: Some(Keycode::G), repeat: false, .. } =>
+                {
+                    match gif.take()
+                    {
+                        Some((recorder, path)) => match save_gif(&path, &recorder)
+                        {
+                            Ok(()) => println!("Saved GIF: {}", path.display()),
+                            Err(e) => eprintln!("chip8-rs: {}", e)
+                        },
+                        None =>
+                        {
+                            let path = capture_path(options, machine.frames(), "gif");
+                            println!("Recording GIF: {}", path.display());
+                            gif = Some((GifRecorder::new(&palettes[palette], options.screenshot_scale), path));
+                        }
+                    }
+                },
+
+                // Pause toggle
+                Event::KeyDown { keycode: Some(Keycode::P), repeat: false, .. } => paused = !paused,
+
+                // Rewind while Backspace is held, with Minus and Equals changing the speed
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => rewinding = true,
+                Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => rewinding = false,
+                Event::KeyDown { keycode: Some(Keycode::Minus), repeat: false, .. } => rewind_speed = (rewind_speed / 2).max(1),
+                Event::KeyDown { keycode: Some(Keycode::Equals), repeat: false, .. } => rewind_speed = (rewind_speed * 2).min(MAX_REWIND_SPEED),
+
+                // Fast-forward while Tab is held, with the brackets changing the speed
+                Event::KeyDown { keycode: Some(Keycode::Tab), .. } => fast_forward = true,
+                Event::KeyUp { keycode: Some(Keycode::Tab), .. } => fast_forward = false,
+                Event::KeyDown { keycode: Some(keycode @ (Keycode::LeftBracket | Keycode::RightBracket)), repeat: false, .. } =>
+                {
+                    let factor = if keycode == Keycode::LeftBracket { 0.5 } else { 2.0 };
+                    speed = (speed * factor).clamp(MIN_SPEED, MAX_SPEED);
+                    set_title(&mut canvas, speed);
+                },
+
+                // Save states: F1-F10 load a slot, Shift+F1-F10 save to it
+                Event::KeyDown { keycode: Some(keycode), keymod, repeat: false, .. } if save_slot_for_key(keycode).is_some() =>
+                {
+                    let slot = save_slot_for_key(keycode).unwrap();
+                    let result = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD)
+                    {
+                        save_state_slot(&machine.cpu, rom_path, slot)
+                    }
+                    else
+                    {
+                        load_state_slot(&mut machine.cpu, rom_path, slot)
+                    };
+
+                    match result
+                    {
+                        Ok(message) => println!("{}", message),
+                        Err(e) => eprintln!("chip8-rs: {}", e)
+                    }
+                },
+
+                // Keydown events
+                Event::KeyDown { keycode: Some(keycode), .. } => 
+                {
+                    // Send the key down event to the CPU
+                    if let Some(value) = key_binds.get(&keycode)
+                    {
+                        let key = *value;
+                        machine.cpu.keypad.set_key_state(key, true);
+                        if machine.cpu.is_waiting_for_key()
+                        {
+                            machine.cpu.stop_waiting_for_key(key);
+                        }
+                    }
+                },
+
+                // Keyup events
+                Event::KeyUp { keycode: Some(keycode), .. } =>
+                {
+                    if let Some(value) = key_binds.get(&keycode)
+                    {
+                        let key = *value;
+                        machine.cpu.keypad.set_key_state(key, false);
+                    }
+                },
+
+                _ => { continue; }
+            }
+        }
+
+        // Handle debugger commands
+        while let Ok(line) = debugger_commands.try_recv()
+        {
+            let output = debugger.execute(&mut machine.cpu, &line);
+            print_debugger_output(&debugger, &output);
+        }
+
+        // The program exited with 00FD
+        if machine.cpu.exited
+        {
+            break 'running;
+        }
+
+        let now = Instant::now();
+        let elapsed = now - last_time;
+        last_time = now;
+
+        let stopped = paused || debugger.is_paused();
+        let multiplier = if fast_forward { speed * FAST_FORWARD_SPEED } else { speed };
+        if stopped
+        {
+            accumulator = Duration::ZERO;
+        }
+        else
+        {
+            // After a stall, drop the frames that are too far behind rather 
+            // than running them all at once
+            let limit = frame_time * MAX_CATCH_UP_FRAMES * multiplier.ceil() as u32;
+            accumulator = (accumulator + elapsed.mul_f64(multiplier)).min(limit);
+        }
+
+        while accumulator >= frame_time
+        {
+            accumulator -= frame_time;
+            if rewinding
+            {
+                for _ in 0..rewind_speed
+                {
+                    if !rewind.step_back(&mut machine.cpu).map_err(|e| e.to_string())?
+                    {
+                        break;
+                    }
+                }
+                persistence.update(&machine.cpu.display, &palettes[palette]);
+                if let Some((recorder, _)) = &mut gif
+                {
+                    recorder.record_frame(&machine.cpu.display);
+                }
+                continue;
+            }
+
+            let instructions = instructions_per_frame(options.cpu_hz, machine.frames());
+            if let Err(e) = machine.run_frame_with(instructions, |cpu| debugger_step(&mut debugger, cpu, options.debug))
+            {
+                cpu_error = Some(e);
+                break 'running;
+            }
+            rewind.push(&machine.cpu);
+            persistence.update(&machine.cpu.display, &palettes[palette]);
+            if let Some(recorder) = &mut recorder
+            {
+                recorder.record_frame(&machine.cpu);
+            }
+            if let Some((recorder, _)) = &mut gif
+            {
+                recorder.record_frame(&machine.cpu.display);
+            }
+
+            if debugger.is_paused() || machine.cpu.exited
+            {
+                accumulator = Duration::ZERO;
+            }
+        }
+
+        if let Some(device) = &mut audio
+        {
+            let mut callback = device.lock();
+            callback.0.update(&machine.cpu);
+            if stopped || rewinding
+            {
+                callback.0.set_on(false);
+            }
+        }
+
+        // Render
+        draw_display(&mut canvas, &machine.cpu.display, &palettes[palette], &persistence);
+        canvas.present();
+
+        // Sleep until the next frame is due
+        let wait = if stopped { frame_time } else { (frame_time - accumulator).div_f64(multiplier) };
+        thread::sleep(wait);
+    }
+
+    save_rpl_flags(&machine.cpu, rom_path)?;
+    if let Some(recorder) = &recorder
+    {
+        save_audio_recording(options, recorder)?;
+    }
+    if let Some((recorder, path)) = &gif
+    {
+        save_gif(path, recorder)?;
+    }
+
+    match cpu_error
+    {
+        Some(e) => Err(e.to_string()),
+        None => Ok(())
+    }
+}
+
+/// Plays a `Beeper` from SDL's audio callback
+struct BeeperCallback(Beeper);
+
+impl AudioCallback for BeeperCallback
+{
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32])
+    {
+        self.0.fill(out);
+    }
+}
+
+/// Opens the default audio device playing the beep set up in options
+fn open_audio(sdl_context: &Sdl, options: &Options) -> Result< AudioDevice< BeeperCallback >, String >
+{
+    let desired = AudioSpecDesired { freq: Some(DEFAULT_SAMPLE_RATE as i32), channels: Some(1), samples: Some(AUDIO_BUFFER_SAMPLES) };
+    let device = sdl_context.audio()?.open_playback(None, &desired, |spec| BeeperCallback(beeper(options, spec.freq as u32)))?;
+    device.resume();
+
+    Ok(device)
+}
+
+/// Shows the emulation speed in the window title when it is not 1x
+fn set_title(canvas: &mut WindowCanvas, speed: f64)
+{
+    let title = if speed == 1.0 { "chip8-rs".to_string() } else { format!("chip8-rs ({}x)", speed) };
+    canvas.window_mut().set_title(&title).ok();
+}
+
+/// Runs one instruction through the debugger. Returns false if the debugger 
+/// is paused, e.g. at a breakpoint. When debugging, errors pause execution 
+/// so the state can be inspected instead of ending the run
+fn debugger_step(debugger: &mut Debugger, cpu: &mut CPU, debug: bool) -> Result< bool, Chip8Error >
+{
+    match debugger.cycle(cpu)
+    {
+        Ok(Some(message)) => print_debugger_output(debugger, &message),
+        Ok(None) => {},
+        Err(e) if debug => print_debugger_output(debugger, &format!("{}\n{}", e, chip8::debugger::registers(cpu))),
+        Err(e) => return Err(e)
+    }
+
+    Ok(!debugger.is_paused())
+}
+
+/// Reads lines from stdin on a background thread and sends them to the 
+/// returned channel
+fn spawn_stdin_reader() -> Receiver< String >
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines().map_while(Result::ok)
+        {
+            if sender.send(line).is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+/// Prints debugger output, followed by a prompt if the debugger is waiting for a command
+fn print_debugger_output(debugger: &Debugger, output: &str)
+{
+    if !output.is_empty()
+    {
+        println!("{}", output);
+    }
+    if debugger.is_paused()
+    {
+        print!("(chip8) ");
+        io::stdout().flush().ok();
+    }
+}
+
+/// Draws the display at the active resolution, scaled to fill the window, in 
+/// the palette's colours as shown by the persistence buffer
+fn draw_display(canvas: &mut WindowCanvas, display: &Display, palette: &Palette, persistence: &PersistenceBuffer)
+{
+    let (window_width, _) = canvas.output_size().unwrap();
+    let width = display.width();
+    let scale = (window_width / width as u32) as i32;
+
+    let background = palette.color(0);
+    let color = |(r, g, b): Rgb| Color::RGB(r, g, b);
+
+    canvas.set_draw_color(color(background));
+    canvas.clear();
+    for (i, &rgb) in persistence.colors(display, palette).iter().enumerate()
+    {
+        if rgb == background
+        {
+            continue;
+        }
+
+        let (x, y) = ((i % width) as i32, (i / width) as i32);
+        canvas.set_draw_color(color(rgb));
+        canvas.fill_rect(Rect::new(x * scale, y * scale, scale as u32, scale as u32)).unwrap();
+    }
+}
+
+/// Path of a file kept next to the ROM, named after it with the given suffix
+fn rom_sidecar_path(rom_path: &Path, suffix: &str) -> PathBuf
+{
+    let mut path = rom_path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// The SUPER-CHIP RPL user flags for a ROM are kept in a file next to it
+fn rpl_flags_path(rom_path: &Path) -> PathBuf
+{
+    rom_sidecar_path(rom_path, ".rpl")
+}
+
+/// Maps F1-F10 to save state slots 1-10
+fn save_slot_for_key(keycode: Keycode) -> Option< u32 >
+{
+    let keys = [
+        Keycode::F1, Keycode::F2, Keycode::F3, Keycode::F4, Keycode::F5,
+        Keycode::F6, Keycode::F7, Keycode::F8, Keycode::F9, Keycode::F10
+    ];
+    keys.iter().position(|&key| key == keycode).map(|n| n as u32 + 1)
+}
+
+/// Save states are kept next to the ROM, one file per slot
+fn save_state_path(rom_path: &Path, slot: u32) -> PathBuf
+{
+    rom_sidecar_path(rom_path, &format!(".state{}", slot))
+}
+
+/// Writes a snapshot of the CPU to a save state slot
+fn save_state_slot(cpu: &CPU, rom_path: &Path, slot: u32) -> Result< String, String >
+{
+    let path = save_state_path(rom_path, slot);
+    fs::write(&path, cpu.save_state()).map_err(|e| format!("Could not write save state \"{}\": {}", path.display(), e))?;
+    Ok(format!("Saved state to slot {}", slot))
+}
+
+/// Restores the CPU from a save state slot
+fn load_state_slot(cpu: &mut CPU, rom_path: &Path, slot: u32) -> Result< String, String >
+{
+    let path = save_state_path(rom_path, slot);
+    let data = fs::read(&path).map_err(|e| format!("Could not read save state \"{}\": {}", path.display(), e))?;
+    cpu.load_state(&data).map_err(|e| format!("Could not load slot {}: {}", slot, e))?;
+    Ok(format!("Loaded state from slot {}", slot))
+}
+
+/// Restores the RPL user flags saved by a previous run of the ROM, if any
+fn load_rpl_flags(cpu: &mut CPU, rom_path: &Path)
+{
+    if let Ok(flags) = fs::read(rpl_flags_path(rom_path))
+    {
+        let n = flags.len().min(RPL_FLAG_COUNT);
+        cpu.rpl[..n].copy_from_slice(&flags[..n]);
+    }
+}
+
+/// Saves the RPL user flags if the ROM has set any of them
+fn save_rpl_flags(cpu: &CPU, rom_path: &Path) -> Result< (), String >
+{
+    let path = rpl_flags_path(rom_path);
+    if cpu.rpl.iter().all(|&flag| flag == 0) && !path.exists()
+    {
+        return Ok(());
+    }
+
+    fs::write(&path, &cpu.rpl[..]).map_err(|e| format!("Could not save RPL flags to \"{}\": {}", path.display(), e))
+}
+
+fn get_sdl_keybinds() -> HashMap< Keycode, usize >
+{
+    let mut hm = HashMap::new();
+    hm.insert(Keycode::Num1, 0x1);
+    hm.insert(Keycode::Num2, 0x2);
+    hm.insert(Keycode::Num3, 0x3);
+    hm.insert(Keycode::Num4, 0xC);
+    hm.insert(Keycode::Q, 0x4);
+    hm.insert(Keycode::W, 0x5);
+    hm.insert(Keycode::E, 0x6);
+    hm.insert(Keycode::R, 0xD);
+    hm.insert(Keycode::A, 0x7);
+    hm.insert(Keycode::S, 0x8);
+    hm.insert(Keycode::D, 0x9);
+    hm.insert(Keycode::F, 0xE);
+    hm.insert(Keycode::Z, 0xA);
+    hm.insert(Keycode::X, 0x0);
+    hm.insert(Keycode::C, 0xB);
+    hm.insert(Keycode::V, 0xF);
+
+    hm
+}
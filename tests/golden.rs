@@ -0,0 +1,84 @@
+//! Golden-image regression tests. Each case loads a ROM, runs it without a
+//! window for a number of frames and compares the screen against a checked-in
+//! text bitmap in tests/golden, as rendered by `Display::to_text`.
+//!
+//! Set CHIP8_BLESS=1 to write the current screens as the new goldens. Only
+//! the ROMs bundled in ROMs/ are covered; third-party test ROMs such as the
+//! IBM logo, corax+ and Timendus' suite are not distributed with the crate
+
+extern crate chip8;
+
+use chip8::cpu::{ CPU, CPU_CLOCK, TIMER_CLOCK };
+use chip8::timing::Timing;
+use chip8::{ Machine, Quirks };
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// A ROM run and the golden its final screen must match
+struct Case
+{
+    /// Name of the golden file, without the .txt extension
+    name: &'static str,
+    /// Path of the ROM relative to the crate root
+    rom: &'static str,
+    frames: u64,
+}
+
+fn root() -> PathBuf
+{
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Runs frames of CPU_CLOCK / TIMER_CLOCK instructions followed by a timer tick
+fn run(case: &Case, rom: &[u8]) -> Machine
+{
+    let mut cpu = CPU::new(Quirks::VIP);
+    cpu.set_rng_seed(1);
+    cpu.load_program(rom).unwrap_or_else(|e| panic!("{}: {}", case.name, e));
+
+    let mut machine = Machine::new(cpu, Timing::Fixed);
+    for frame in 0..case.frames
+    {
+        machine.run_frame((CPU_CLOCK / TIMER_CLOCK) as u32).unwrap_or_else(|e| panic!("{}: frame {}: {}", case.name, frame, e));
+    }
+
+    machine
+}
+/// Runs a case and compares its screen to the golden
+fn check(case: &Case)
+{
+    let rom_path = root().join(case.rom);
+    let rom = fs::read(&rom_path).unwrap_or_else(|e| {
+        panic!("{}: could not read {}: {}", case.name, rom_path.display(), e)
+    });
+
+    let screen = run(case, &rom).cpu.display.to_text();
+    let golden_path = root().join("tests").join("golden").join(format!("{}.txt", case.name));
+    if env::var_os("CHIP8_BLESS").is_some()
+    {
+        fs::write(&golden_path, &screen).unwrap();
+        return;
+    }
+
+    let golden = fs::read_to_string(&golden_path).unwrap_or_else(|_| {
+        panic!("{}: no golden at {}, run with CHIP8_BLESS=1 to create it", case.name, golden_path.display())
+    });
+    assert!(golden == screen, "{}: screen after {} frames does not match {}\n\nexpected:\n{}\nactual:\n{}",
+            case.name, case.frames, golden_path.display(), golden, screen);
+}
+
+fn check_all(cases: &[Case])
+{
+    cases.iter().for_each(check);
+}
+
+#[test]
+fn bundled_roms()
+{
+    check_all(&[
+        Case { name: "pong_attract", rom: "ROMs/PONG.ch8", frames: 120 },
+        Case { name: "airplane_attract", rom: "ROMs/AIRPLANE.ch8", frames: 120 },
+    ]);
+}